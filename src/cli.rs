@@ -0,0 +1,425 @@
+use std::path::PathBuf;
+
+use clap::error::ErrorKind;
+use clap::{Arg, ArgAction, ArgMatches, Args, FromArgMatches, Parser, Subcommand};
+use clap_complete::Shell;
+
+use url::Url;
+
+use crate::node::parse_root;
+use crate::options::{parse_flags, CRAWL_FLAGS};
+
+#[derive(Parser, Debug)]
+#[command(
+    name = "crawl",
+    version,
+    about = "Crawls websites in a background daemon, building a tree of links for each root url",
+    long_about = "The application consists of a command line client and a local service (daemon) which performs the actual web crawling. The communication between client and server uses a form of IPC mechanism. For each URL, the Web Crawler creates a tree of links with the root of the tree being the root URL.",
+    after_help = "Exit status is 0 on success, 1 when the daemon couldn't carry out the request, 2 for usage errors and 3 when the daemon isn't running or can't be reached"
+)]
+pub struct Cli {
+    #[arg(
+        long,
+        global = true,
+        value_name = "PATH",
+        help = "Config file read after /etc/crawl/config.toml and ~/.config/crawl/config.toml, its crawl defaults are sent with start and schedule in place of the daemon's"
+    )]
+    pub config: Option<PathBuf>,
+    #[command(subcommand)]
+    pub command: Command,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    #[command(
+        about = "Start the daemon, or have it crawl a url",
+        long_about = "Without a url this starts the daemon. With one it tasks the running daemon with crawling the url. Without a proxy option jobs use the HTTP_PROXY, HTTPS_PROXY, ALL_PROXY and NO_PROXY variables the daemon was started with"
+    )]
+    Start {
+        #[arg(value_parser = parse_root, help = "Root url of the site to crawl")]
+        url: Option<Url>,
+        #[arg(
+            long,
+            requires = "url",
+            help = "Crawl the url even though it was crawled before, keeping the earlier results"
+        )]
+        force: bool,
+        #[command(flatten)]
+        flags: CrawlFlags,
+    },
+    #[command(about = "Stop crawling a url or take it out of the queue")]
+    Stop {
+        #[arg(value_parser = parse_root)]
+        url: Url,
+    },
+    #[command(
+        about = "Have the daemon crawl a url by itself every so often",
+        long_about = "This has the daemon crawl the url by itself, keeping each run as a new result. A run is skipped while the previous one is still going. Any start option can be given and is used for every crawl"
+    )]
+    Schedule {
+        #[arg(value_parser = parse_root)]
+        url: Url,
+        #[arg(
+            long,
+            value_name = "LENGTH",
+            required_unless_present = "cron",
+            conflicts_with = "cron",
+            help = "Time between crawls such as 90s, 30m, 6h or 1d"
+        )]
+        every: Option<String>,
+        #[arg(
+            long,
+            value_name = "EXPRESSION",
+            help = "Five field cron expression of minute, hour, day of month, month and day of week in UTC"
+        )]
+        cron: Option<String>,
+        #[arg(
+            long,
+            value_name = "N",
+            help = "Runs and results kept for the site (default 10)"
+        )]
+        keep_runs: Option<usize>,
+        #[arg(
+            long,
+            value_name = "LENGTH",
+            help = "Runs and results older than this are dropped"
+        )]
+        keep_for: Option<String>,
+        #[command(flatten)]
+        flags: CrawlFlags,
+    },
+    #[command(about = "Stop crawling a url on a schedule, keeping its history and last crawl")]
+    Unschedule {
+        #[arg(value_parser = parse_root)]
+        url: Url,
+    },
+    #[command(
+        about = "Delete the finished crawls of a url, or only one of them",
+        long_about = "This deletes the finished crawls of a url, or only one of them. Finished crawls are only kept while the daemon runs, a restarted daemon keeps the schedules and run history but starts without any results and numbers them from 1 again, so use save for a crawl that should last"
+    )]
+    Forget {
+        #[arg(value_parser = parse_root)]
+        url: Url,
+        #[arg(
+            long,
+            value_name = "N",
+            help = "Only forget this stored result, as numbered by status"
+        )]
+        result: Option<usize>,
+    },
+    #[command(
+        about = "Show a url's schedule, whether it's being crawled or queued and its runs",
+        long_about = "This shows the url's schedule, whether it's being crawled or queued and the runs in its history. Without a url it shows every running and queued job"
+    )]
+    Status {
+        #[arg(value_parser = parse_root)]
+        url: Option<Url>,
+    },
+    #[command(
+        about = "Set how many jobs the daemon runs at once, the rest wait in a queue (default 4)"
+    )]
+    MaxJobs {
+        #[arg(value_parser = clap::value_parser!(u64).range(1..))]
+        jobs: u64,
+    },
+    #[command(
+        about = "Have the daemon read its config files again",
+        long_about = "This has the daemon read its config files and environment again, the same as sending it SIGHUP. A new job limit and number of runs kept apply straight away, and so do a new log level and rate limit, which running jobs pick up before their next page. New crawl defaults are used by jobs started afterwards, including scheduled runs unless they were scheduled with --config, while jobs already running or queued keep the options they started with. The pid file, socket, output files and state file can't change without restarting the daemon"
+    )]
+    Reload,
+    #[command(
+        about = "Crawl a finished job again, only reading the pages which changed since according to their ETag or Last-Modified"
+    )]
+    Recrawl {
+        #[arg(value_parser = parse_root)]
+        url: Url,
+    },
+    #[command(
+        about = "Write a sitemap.xml of the 200 status html pages of a finished crawl",
+        long_about = "This writes a sitemap.xml of the 200 status html pages of a finished crawl, split into numbered files under a sitemap index above 50,000 urls"
+    )]
+    Sitemap {
+        #[arg(value_parser = parse_root)]
+        url: Url,
+        #[arg(
+            long,
+            help = "Include lastmod taken from each page's Last-Modified header"
+        )]
+        lastmod: bool,
+        #[arg(
+            long,
+            value_name = "URL",
+            value_parser = parse_root,
+            help = "Where the files will be served from, used for the urls in a sitemap index, the site's root by default"
+        )]
+        base_url: Option<Url>,
+        #[arg(
+            long,
+            value_name = "DIR",
+            default_value = ".",
+            help = "Directory the files are written to"
+        )]
+        output: PathBuf,
+    },
+    #[command(about = "Save the tree of a finished crawl so later crawls can be compared with it")]
+    Save {
+        #[arg(value_parser = parse_root)]
+        url: Url,
+        file: PathBuf,
+    },
+    #[command(
+        about = "Compare two saved crawls of a site",
+        long_about = "This compares two saved crawls of a site, listing added and removed pages, status changes, moved pages and new broken links"
+    )]
+    Diff {
+        old: PathBuf,
+        new: PathBuf,
+        #[arg(long, help = "Print the changes as json")]
+        json: bool,
+    },
+    #[command(
+        about = "List every url the daemon knows of",
+        long_about = "This lists every url the daemon knows of with whether it's running, queued, finished or only scheduled, and the page count and finish time of its newest result. Results are only kept while the daemon runs, after a restart only the scheduled urls are listed until they're crawled again"
+    )]
+    List,
+    #[command(
+        about = "Show the tree and report of a url's newest result",
+        long_about = "This shows the tree of the url's newest result, or of result n, followed by a report of redirect chains, external and non-http links, suspected crawl traps, duplicate pages and links to missing anchors. Without a url every site's newest result is shown"
+    )]
+    Show {
+        #[arg(value_parser = parse_root)]
+        url: Option<Url>,
+        #[arg(
+            long,
+            value_name = "N",
+            requires = "url",
+            help = "Which stored result to show, as numbered by status (defaults to the newest)"
+        )]
+        result: Option<usize>,
+    },
+    #[command(about = "Write what show would print to a file")]
+    Print {
+        #[arg(value_parser = parse_root)]
+        url: Option<Url>,
+        #[arg(
+            long,
+            value_name = "N",
+            requires = "url",
+            help = "Which stored result to write, as numbered by status (defaults to the newest)"
+        )]
+        result: Option<usize>,
+        #[arg(
+            long,
+            value_name = "PATH",
+            default_value = "output.txt",
+            help = "File the trees are written to"
+        )]
+        output: PathBuf,
+    },
+    #[command(about = "Clear all files related to the daemon")]
+    Clear,
+    #[command(about = "Kill the daemon and then clear all files related to the daemon")]
+    Kill,
+    #[command(about = "Print a completion script for a shell")]
+    Completions { shell: Shell },
+    #[command(about = "Print the man page, or write a page for every command into a directory")]
+    Man {
+        #[arg(long, value_name = "DIR")]
+        output: Option<PathBuf>,
+    },
+}
+
+//The per job crawl options shared by start and schedule, as the `key=value` lines sent to the daemon
+//The flags are built from CRAWL_FLAGS so the options, their parsing and their help only live in one place
+//Each needs the url both commands take, so `start --max-depth 3` isn't mistaken for starting the daemon
+#[derive(Debug, Default, PartialEq)]
+pub struct CrawlFlags {
+    pub lines: Vec<String>,
+}
+
+impl Args for CrawlFlags {
+    fn augment_args(cmd: clap::Command) -> clap::Command {
+        CRAWL_FLAGS
+            .iter()
+            .fold(cmd, |cmd, (name, value_name, help)| {
+                let arg = Arg::new(*name).long(*name).help(*help).requires("url");
+                match value_name {
+                    Some(value_name) => cmd.arg(
+                        arg.value_name(*value_name)
+                            .action(ArgAction::Append)
+                            .allow_hyphen_values(true),
+                    ),
+                    //Each switch has a --no- form so a job can turn off one the config's defaults turn on
+                    None => {
+                        let negated = format!("no-{}", name);
+                        cmd.arg(arg.action(ArgAction::SetTrue)).arg(
+                            Arg::new(negated.clone())
+                                .long(negated)
+                                .help(format!(
+                                    "Turn off --{} when a config default turns it on",
+                                    name
+                                ))
+                                .requires("url")
+                                .action(ArgAction::SetTrue),
+                        )
+                    }
+                }
+            })
+    }
+
+    fn augment_args_for_update(cmd: clap::Command) -> clap::Command {
+        Self::augment_args(cmd)
+    }
+}
+
+impl FromArgMatches for CrawlFlags {
+    fn from_arg_matches(matches: &ArgMatches) -> Result<Self, clap::Error> {
+        //Options are applied in the order they were given since later ones can build on earlier ones
+        let mut given: Vec<(usize, String, Option<&String>)> = Vec::new();
+        for (name, value_name, _) in CRAWL_FLAGS.iter() {
+            if value_name.is_none() {
+                for switch in [name.to_string(), format!("no-{}", name)] {
+                    if matches.get_flag(&switch) {
+                        given.push((matches.index_of(&switch).unwrap_or_default(), switch, None));
+                    }
+                }
+            } else if let (Some(values), Some(indices)) =
+                (matches.get_many::<String>(name), matches.indices_of(name))
+            {
+                given.extend(
+                    indices
+                        .zip(values)
+                        .map(|(index, value)| (index, name.to_string(), Some(value))),
+                );
+            }
+        }
+        given.sort_by_key(|(index, _, _)| *index);
+
+        let mut args = Vec::new();
+        for (_, name, value) in given {
+            args.push(format!("--{}", name));
+            args.extend(value.cloned());
+        }
+        let lines =
+            parse_flags(&args).map_err(|e| clap::Error::raw(ErrorKind::ValueValidation, e))?;
+        Ok(CrawlFlags { lines })
+    }
+
+    fn update_from_arg_matches(&mut self, matches: &ArgMatches) -> Result<(), clap::Error> {
+        *self = Self::from_arg_matches(matches)?;
+        Ok(())
+    }
+}
+
+//The commands used to be single dash options such as -start, those are still accepted
+pub fn legacy_args(args: impl Iterator<Item = String>) -> Vec<String> {
+    args.enumerate()
+        .map(|(position, arg)| match arg.as_str() {
+            "-h" if position == 1 => "--help".to_string(),
+            _ if position == 1
+                && arg.starts_with('-')
+                && !arg.starts_with("--")
+                && arg.len() > 2 =>
+            {
+                arg[1..].to_string()
+            }
+            _ => arg,
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{legacy_args, Cli, Command};
+    use clap::{CommandFactory, Parser};
+
+    fn parse(args: &[&str]) -> Result<Cli, clap::Error> {
+        Cli::try_parse_from(legacy_args(args.iter().map(|arg| arg.to_string())))
+    }
+
+    #[test]
+    fn test_cli() {
+        Cli::command().debug_assert();
+
+        match parse(&[
+            "crawl",
+            "-start",
+            "example.com",
+            "--max-depth",
+            "2",
+            "--force",
+            "--sitemap",
+            "--priority",
+            "-3",
+            "--header",
+            "X-Team: docs",
+        ])
+        .unwrap()
+        .command
+        {
+            Command::Start { url, force, flags } => {
+                assert_eq!(url.unwrap().as_str(), "http://example.com/");
+                assert!(force);
+                assert_eq!(
+                    flags.lines,
+                    vec![
+                        "max-depth=2",
+                        "sitemap=true",
+                        "priority=-3",
+                        "header=X-Team: docs"
+                    ]
+                );
+            }
+            command => panic!("Parsed the wrong command {:?}", command),
+        }
+
+        //The --no- forms send false, in order with the rest so the last one given wins
+        match parse(&[
+            "crawl",
+            "start",
+            "example.com",
+            "--sitemap",
+            "--no-check-external",
+            "--no-sitemap",
+        ])
+        .unwrap()
+        .command
+        {
+            Command::Start { flags, .. } => assert_eq!(
+                flags.lines,
+                vec!["sitemap=true", "check-external=false", "sitemap=false"]
+            ),
+            command => panic!("Parsed the wrong command {:?}", command),
+        }
+
+        match parse(&[
+            "crawl",
+            "schedule",
+            "https://example.com",
+            "--cron",
+            "0 3 * * *",
+        ])
+        .unwrap()
+        .command
+        {
+            Command::Schedule { cron, every, .. } => {
+                assert_eq!(cron.as_deref(), Some("0 3 * * *"));
+                assert_eq!(every, None);
+            }
+            command => panic!("Parsed the wrong command {:?}", command),
+        }
+
+        //Crawl options are checked by the client before anything is sent
+        let error = parse(&["crawl", "start", "example.com", "--max-depth", "deep"]).unwrap_err();
+        assert!(error
+            .to_string()
+            .contains("--max-depth expects a whole number but was given deep"));
+        assert!(parse(&["crawl", "schedule", "example.com"]).is_err());
+        assert!(parse(&["crawl", "max-jobs", "0"]).is_err());
+        assert!(parse(&["crawl", "show", "--result", "2"]).is_err());
+        assert!(parse(&["crawl", "start", "--force"]).is_err());
+        assert!(parse(&["crawl", "start", "--max-depth", "3"]).is_err());
+        assert!(parse(&["crawl", "start", "--no-sitemap"]).is_err());
+        assert!(parse(&["crawl", "start", "mailto:a@b"]).is_err());
+    }
+}
@@ -0,0 +1,190 @@
+use std::error::Error;
+use std::fs::read_to_string;
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use reqwest::cookie::Jar;
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue};
+use reqwest::redirect::Policy;
+use reqwest::{Client, NoProxy, Proxy};
+
+use url::Url;
+
+use crate::cookies::parse_cookie_file;
+use crate::options::CrawlOptions;
+
+type ProxyConstructor = fn(Url) -> reqwest::Result<Proxy>;
+
+//Builds the client shared by every request in a job
+//Requests go through the job's proxies if it has any, otherwise the proxies from the daemon's environment
+//Its cookie jar starts with the job's cookies and keeps any the site sets for the rest of the job
+pub fn build_client(root: &Url, options: &CrawlOptions) -> Result<Client, Box<dyn Error>> {
+    let mut headers = HeaderMap::new();
+    for (name, value) in options.headers.iter() {
+        headers.append(
+            HeaderName::from_bytes(name.as_bytes())?,
+            HeaderValue::from_str(value)?,
+        );
+    }
+
+    let jar = Jar::default();
+    for path in options.cookie_files.iter() {
+        let contents = read_to_string(path)
+            .map_err(|e| format!("Couldn't read cookie file {}: {}", path.display(), e))?;
+        for (cookie, url) in parse_cookie_file(&contents)? {
+            jar.add_cookie_str(&cookie, &url);
+        }
+    }
+    //Cookies given on their own are set as if the root page had sent them
+    for cookie in options.cookies.iter() {
+        jar.add_cookie_str(cookie, root);
+    }
+
+    let mut builder = Client::builder();
+    //Proxies are matched in order so the scheme specific ones come first
+    let proxies: [(&Option<Url>, ProxyConstructor); 3] = [
+        (&options.http_proxy, Proxy::http),
+        (&options.https_proxy, Proxy::https),
+        (&options.proxy, Proxy::all),
+    ];
+    for (proxy_url, proxy) in proxies {
+        if let Some(proxy_url) = proxy_url {
+            let mut proxy = proxy(proxy_url.clone())?
+                .no_proxy(options.no_proxy.as_deref().and_then(NoProxy::from_string));
+            if let Some((user, password)) = &options.proxy_auth {
+                proxy = proxy.basic_auth(user, password.expose());
+            }
+            builder = builder.proxy(proxy);
+        }
+    }
+
+    //DNS has no notion of ports so connections use the port from the url, which keeps the
+    //Host header and the urls in the tree the same as they would be without the override
+    //The override applies whatever port is requested, so one for the crawled host has to name the port it uses
+    for (host, port, address) in options.resolve.iter() {
+        let root_port = root.port_or_known_default().unwrap_or_default();
+        if root.host_str() == Some(host.as_str()) && *port != root_port {
+            return Err(format!(
+                "--resolve {}:{}:{} doesn't match the port {} the crawl of {} connects to",
+                host, port, address, root_port, root
+            )
+            .into());
+        }
+        builder = builder.resolve(host, SocketAddr::new(*address, *port));
+    }
+
+    //Redirects are followed by the crawler so each hop can be recorded
+    Ok(builder
+        .redirect(Policy::none())
+        .user_agent(options.user_agent.as_str())
+        .default_headers(headers)
+        .cookie_provider(Arc::new(jar))
+        .connect_timeout(options.connect_timeout)
+        .timeout(options.timeout)
+        .build()?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::build_client;
+    use crate::options::CrawlOptions;
+    use crate::test_server::{header, http_response, serve};
+    use std::env::temp_dir;
+    use std::fs::write;
+
+    #[test]
+    fn test_identity_and_cookies() {
+        let (url, requests) = serve(vec![
+            (
+                "/",
+                http_response("200 OK", "Set-Cookie: session=s1; Path=/\r\n", ""),
+            ),
+            ("/next", http_response("200 OK", "", "")),
+        ]);
+        let cookie_file =
+            temp_dir().join(format!("crawl-test-cookies-{}.txt", url.port().unwrap()));
+        write(
+            &cookie_file,
+            "127.0.0.1\tFALSE\t/\tFALSE\t0\timported\tyes\n",
+        )
+        .expect("Couldn't write the cookie file");
+
+        let mut options = CrawlOptions::default();
+        options.set("user-agent", "docs-bot/1.0").unwrap();
+        options.set("header", "X-Team: docs").unwrap();
+        options.set("cookie", "theme=dark").unwrap();
+        options
+            .set("cookies", cookie_file.to_str().unwrap())
+            .unwrap();
+        let client = build_client(&url, &options).expect("Couldn't build the client");
+
+        tokio::runtime::Builder::new_multi_thread()
+            .enable_all()
+            .build()
+            .unwrap()
+            .block_on(async {
+                client.get(url.clone()).send().await.unwrap();
+                client.get(url.join("/next").unwrap()).send().await.unwrap();
+            });
+        std::fs::remove_file(&cookie_file).unwrap();
+
+        let requests = requests.lock().unwrap();
+        assert_eq!(header(&requests[0], "user-agent"), Some("docs-bot/1.0"));
+        assert_eq!(header(&requests[0], "x-team"), Some("docs"));
+        let mut first_cookies: Vec<&str> = header(&requests[0], "cookie")
+            .unwrap()
+            .split("; ")
+            .collect();
+        first_cookies.sort();
+        assert_eq!(first_cookies, vec!["imported=yes", "theme=dark"]);
+        //The session cookie set by the first response is sent with the next request
+        assert!(header(&requests[1], "cookie")
+            .unwrap()
+            .contains("session=s1"));
+    }
+
+    #[test]
+    fn test_proxy() {
+        let (proxy_url, proxy_requests) = serve(vec![(
+            "http://crawl.invalid/page",
+            http_response("200 OK", "", ""),
+        )]);
+        let (direct_url, direct_requests) = serve(vec![("/", http_response("200 OK", "", ""))]);
+
+        let mut options = CrawlOptions::default();
+        let mut authenticated_proxy = proxy_url.clone();
+        authenticated_proxy.set_username("egress").unwrap();
+        authenticated_proxy.set_password(Some("hunter2")).unwrap();
+        options.set("proxy", authenticated_proxy.as_str()).unwrap();
+        options.set("no-proxy", "127.0.0.1").unwrap();
+        assert_eq!(options.proxy, Some(proxy_url));
+        let client = build_client(&direct_url, &options).expect("Couldn't build the client");
+
+        tokio::runtime::Builder::new_multi_thread()
+            .enable_all()
+            .build()
+            .unwrap()
+            .block_on(async {
+                let response = client
+                    .get("http://crawl.invalid/page")
+                    .send()
+                    .await
+                    .expect("The proxy should answer for the unresolvable host");
+                assert_eq!(response.status(), 200);
+                client.get(direct_url.clone()).send().await.unwrap();
+            });
+
+        let proxy_requests = proxy_requests.lock().unwrap();
+        assert_eq!(proxy_requests.len(), 1);
+        assert_eq!(
+            proxy_requests[0][0],
+            "GET http://crawl.invalid/page HTTP/1.1"
+        );
+        assert_eq!(
+            header(&proxy_requests[0], "proxy-authorization"),
+            Some("Basic ZWdyZXNzOmh1bnRlcjI=")
+        );
+        //Hosts in the no proxy list are connected to directly
+        assert_eq!(direct_requests.lock().unwrap().len(), 1);
+    }
+}
@@ -0,0 +1,519 @@
+use std::collections::BTreeMap;
+use std::env;
+use std::fs::read_to_string;
+use std::path::{Path, PathBuf};
+
+use toml::{Table, Value};
+
+use crate::live::{LiveSettings, LogLevel};
+use crate::options::{CrawlOptions, CRAWL_FLAGS, PATH_OPTIONS};
+use crate::queue::DEFAULT_MAX_JOBS;
+use crate::schedule::DEFAULT_KEEP_RUNS;
+
+//Read first, then the user's file and then the one given with --config, each overriding the settings before it
+pub const SYSTEM_CONFIG: &str = "/etc/crawl/config.toml";
+//Environment variables override every file, daemon settings are CRAWL_ followed by the setting and crawl
+//options are CRAWL_OPTION_ followed by the option, both in capitals with underscores for dashes
+const ENV_PREFIX: &str = "CRAWL_";
+const ENV_OPTION_PREFIX: &str = "CRAWL_OPTION_";
+//Given to notify commands, which may run the client themselves, so it's the one other name allowed
+const ENV_NOTIFY_SITE: &str = "CRAWL_SITE";
+const DAEMON_SETTINGS: [&str; 9] = [
+    "pid-file",
+    "socket",
+    "stdout",
+    "stderr",
+    "state-file",
+    "max-jobs",
+    "keep-runs",
+    "log-level",
+    "rate-limit",
+];
+//A job started with --config uses that file's crawl defaults rather than the daemon's. They're sent as a
+//defaults=client line, so a config without a [crawl] table still replaces them, then a default= line each
+const CLIENT_DEFAULTS: &str = "defaults=client";
+const CLIENT_DEFAULT_PREFIX: &str = "default=";
+
+//Settings for the daemon along with the crawl options every job starts from
+#[derive(Debug, PartialEq, Clone)]
+pub struct Config {
+    pub pid_path: PathBuf,
+    pub stream_path: PathBuf,
+    pub out_path: PathBuf,
+    pub err_path: PathBuf,
+    //Schedules and run history are saved here so they outlast the daemon
+    pub state_path: PathBuf,
+    //Jobs run at once when the daemon starts, max-jobs changes it until the daemon exits
+    pub max_jobs: usize,
+    //Runs and results kept for a site unless its schedule says otherwise
+    pub keep_runs: usize,
+    //Both reach jobs which are already running when the config is reloaded
+    pub log_level: LogLevel,
+    //Most pages a second each job without its own --rate-limit fetches
+    pub rate_limit: Option<f64>,
+    //Crawl options by key, several values for options which can be repeated
+    pub crawl: BTreeMap<String, Vec<String>>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            pid_path: PathBuf::from("/tmp/crawl.pid"),
+            stream_path: PathBuf::from("/tmp/crawl.stream"),
+            out_path: PathBuf::from("/tmp/crawl.out"),
+            err_path: PathBuf::from("/tmp/crawl.err"),
+            state_path: PathBuf::from("/tmp/crawl.state"),
+            max_jobs: DEFAULT_MAX_JOBS,
+            keep_runs: DEFAULT_KEEP_RUNS,
+            log_level: LogLevel::default(),
+            rate_limit: None,
+            crawl: BTreeMap::new(),
+        }
+    }
+}
+
+impl Config {
+    //The default crawl options as the `key=value` lines a job's own options are applied after
+    pub fn crawl_lines(&self) -> Vec<String> {
+        self.crawl
+            .iter()
+            .flat_map(|(key, values)| values.iter().map(move |value| format!("{}={}", key, value)))
+            .collect()
+    }
+
+    //The crawl defaults as sent along with a job
+    pub fn client_lines(&self) -> Vec<String> {
+        client_default_lines(&self.crawl_lines())
+    }
+
+    pub fn live(&self) -> LiveSettings {
+        LiveSettings {
+            log_level: self.log_level,
+            rate_limit: self.rate_limit,
+        }
+    }
+
+    fn set_daemon(&mut self, key: &str, value: &str, dir: &Path) -> Result<(), String> {
+        match key {
+            "pid-file" => self.pid_path = dir.join(value),
+            "socket" => self.stream_path = dir.join(value),
+            "stdout" => self.out_path = dir.join(value),
+            "stderr" => self.err_path = dir.join(value),
+            "state-file" => self.state_path = dir.join(value),
+            "log-level" => self.log_level = LogLevel::parse(value)?,
+            "rate-limit" => {
+                self.rate_limit = Some(
+                    value
+                        .parse::<f64>()
+                        .ok()
+                        .filter(|rate| rate.is_finite() && *rate > 0.0)
+                        .ok_or_else(|| {
+                            format!(
+                                "rate-limit expects a positive number of pages a second but was given {}",
+                                value
+                            )
+                        })?,
+                )
+            }
+            "max-jobs" | "keep-runs" => {
+                let number = value
+                    .parse::<usize>()
+                    .ok()
+                    .filter(|number| *number > 0)
+                    .ok_or_else(|| format!("{} expects at least 1 but was given {}", key, value))?;
+                if key == "max-jobs" {
+                    self.max_jobs = number;
+                } else {
+                    self.keep_runs = number;
+                }
+            }
+            _ => return Err(format!("Unknown daemon setting {}", key)),
+        }
+        Ok(())
+    }
+
+    //Relative paths are taken from dir, the directory of the file they were given in
+    fn set_crawl(&mut self, key: &str, values: Vec<String>, dir: &Path) {
+        let values = if PATH_OPTIONS.contains(&key) {
+            values
+                .into_iter()
+                .map(|value| dir.join(value).display().to_string())
+                .collect()
+        } else {
+            values
+        };
+        self.crawl.insert(key.to_string(), values);
+    }
+
+    //Applies the [daemon] and [crawl] tables of a config file
+    pub fn apply_toml(&mut self, text: &str, dir: &Path) -> Result<(), String> {
+        let table = text.parse::<Table>().map_err(|e| e.to_string())?;
+        for (name, section) in table {
+            let Value::Table(section) = section else {
+                return Err(format!("{} should be a [{}] table", name, name));
+            };
+            if name != "daemon" && name != "crawl" {
+                return Err(format!(
+                    "Unknown table [{}], only [daemon] and [crawl] are read",
+                    name
+                ));
+            }
+            for (key, value) in section {
+                let values = toml_values(&name, &key, value)?;
+                if name == "crawl" {
+                    self.set_crawl(&key, values, dir);
+                    continue;
+                }
+                match &values[..] {
+                    [value] => self
+                        .set_daemon(&key, value, dir)
+                        .map_err(|e| format!("[daemon] {}", e))?,
+                    _ => return Err(format!("[daemon] {} takes a single value", key)),
+                }
+            }
+        }
+        Ok(())
+    }
+
+    //Applies CRAWL_ and CRAWL_OPTION_ variables, relative paths are taken from the current directory
+    pub fn apply_env(
+        &mut self,
+        vars: impl Iterator<Item = (String, String)>,
+        dir: &Path,
+    ) -> Result<(), String> {
+        for (name, value) in vars {
+            if name == ENV_NOTIFY_SITE {
+                continue;
+            }
+            //A misspelt name is refused rather than ignored, the same as an unknown key in a file
+            if let Some(option) = name.strip_prefix(ENV_OPTION_PREFIX) {
+                let key = option.to_lowercase().replace('_', "-");
+                if !CRAWL_FLAGS.iter().any(|(flag, _, _)| *flag == key) {
+                    return Err(format!("{}: Unknown option --{}", name, key));
+                }
+                self.set_crawl(&key, vec![value], dir);
+            } else if let Some(setting) = name.strip_prefix(ENV_PREFIX) {
+                let key = setting.to_lowercase().replace('_', "-");
+                if !DAEMON_SETTINGS.contains(&key.as_str()) {
+                    return Err(format!("{}: Unknown daemon setting {}", name, key));
+                }
+                self.set_daemon(&key, &value, dir)
+                    .map_err(|e| format!("{}: {}", name, e))?;
+            }
+        }
+        Ok(())
+    }
+
+    //The crawl options are checked together the way a job would read them
+    pub fn validate(&self) -> Result<(), String> {
+        let lines = self.crawl_lines();
+        CrawlOptions::from_lines(lines.iter().map(|line| line.as_str()))
+            .map_err(|e| format!("[crawl] {}", e))?;
+        Ok(())
+    }
+
+    //What switching a running daemon from this config to a newly loaded one changes. Its files can't move
+    //while it's running so a config which moves them is refused
+    pub fn changes(&self, new: &Config) -> Result<Vec<String>, String> {
+        if (
+            &self.pid_path,
+            &self.stream_path,
+            &self.out_path,
+            &self.err_path,
+            &self.state_path,
+        ) != (
+            &new.pid_path,
+            &new.stream_path,
+            &new.out_path,
+            &new.err_path,
+            &new.state_path,
+        ) {
+            return Err(
+                "The pid file, socket, output files and state file can't change while the daemon is running, restart it to use the new ones"
+                    .to_string(),
+            );
+        }
+        let mut changes = Vec::new();
+        if self.max_jobs != new.max_jobs {
+            changes.push(format!("at most {} jobs now run at once", new.max_jobs));
+        }
+        if self.keep_runs != new.keep_runs {
+            changes.push(format!(
+                "{} runs are now kept for sites without their own limit",
+                new.keep_runs
+            ));
+        }
+        if self.log_level != new.log_level {
+            changes.push(format!("jobs now log at the {} level", new.log_level));
+        }
+        if self.rate_limit != new.rate_limit {
+            changes.push(match new.rate_limit {
+                Some(rate) => format!(
+                    "jobs without their own rate limit now fetch at most {} pages a second",
+                    rate
+                ),
+                None => "jobs without their own rate limit are no longer limited".to_string(),
+            });
+        }
+        if self.crawl != new.crawl {
+            changes.push("new jobs and scheduled runs use the new crawl defaults".to_string());
+        }
+        Ok(changes)
+    }
+}
+
+//A setting's value as text, arrays give one value for each item
+fn toml_values(table: &str, key: &str, value: Value) -> Result<Vec<String>, String> {
+    match value {
+        Value::String(value) => Ok(vec![value]),
+        Value::Integer(value) => Ok(vec![value.to_string()]),
+        Value::Float(value) => Ok(vec![value.to_string()]),
+        Value::Boolean(value) => Ok(vec![value.to_string()]),
+        Value::Array(values) => values
+            .into_iter()
+            .map(|value| match value {
+                Value::Array(_) | Value::Table(_) => Err(format!(
+                    "[{}] {} can't hold nested lists or tables",
+                    table, key
+                )),
+                value => toml_values(table, key, value).map(|mut values| values.remove(0)),
+            })
+            .collect(),
+        _ => Err(format!(
+            "[{}] {} expects a string, number, true or false, or a list of them",
+            table, key
+        )),
+    }
+}
+
+pub fn client_default_lines(defaults: &[String]) -> Vec<String> {
+    let lines = defaults
+        .iter()
+        .map(|line| format!("{}{}", CLIENT_DEFAULT_PREFIX, line));
+    std::iter::once(CLIENT_DEFAULTS.to_string())
+        .chain(lines)
+        .collect()
+}
+
+//Separates the crawl defaults a client sent from the job's other lines, None when it sent none
+pub fn take_client_defaults<'a>(
+    lines: impl Iterator<Item = &'a str>,
+) -> (Option<Vec<String>>, Vec<&'a str>) {
+    let mut defaults = None;
+    let mut rest = Vec::new();
+    for line in lines {
+        if line.trim() == CLIENT_DEFAULTS {
+            defaults.get_or_insert_with(Vec::new);
+        } else if let Some(default) = line.strip_prefix(CLIENT_DEFAULT_PREFIX) {
+            defaults
+                .get_or_insert_with(Vec::new)
+                .push(default.to_string());
+        } else {
+            rest.push(line);
+        }
+    }
+    (defaults, rest)
+}
+
+//$XDG_CONFIG_HOME/crawl/config.toml, or ~/.config/crawl/config.toml
+pub fn user_config_path() -> Option<PathBuf> {
+    env::var_os("XDG_CONFIG_HOME")
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")))
+        .map(|dir| dir.join("crawl").join("config.toml"))
+}
+
+//Reads the system and user config files when they exist, then the given one which has to, then the environment
+pub fn load_config(path: Option<&Path>) -> Result<Config, String> {
+    let mut config = Config::default();
+    let mut files: Vec<PathBuf> = [Some(PathBuf::from(SYSTEM_CONFIG)), user_config_path()]
+        .into_iter()
+        .flatten()
+        .filter(|file| file.exists())
+        .collect();
+    files.extend(path.map(Path::to_path_buf));
+    for file in files {
+        let text = read_to_string(&file)
+            .map_err(|e| format!("Couldn't read the config file {}: {}", file.display(), e))?;
+        let file = file.canonicalize().unwrap_or(file);
+        let dir = file.parent().unwrap_or(Path::new("/"));
+        config
+            .apply_toml(&text, dir)
+            .map_err(|e| format!("Invalid config file {}: {}", file.display(), e))?;
+    }
+    let current = env::current_dir().map_err(|e| e.to_string())?;
+    config.apply_env(env::vars(), &current)?;
+    config
+        .validate()
+        .map_err(|e| format!("Invalid config: {}", e))?;
+    Ok(config)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{take_client_defaults, Config};
+    use crate::live::{LiveSettings, LogLevel};
+    use std::path::{Path, PathBuf};
+
+    #[test]
+    fn test_config() {
+        let mut config = Config::default();
+        config
+            .apply_toml(
+                r#"
+                [daemon]
+                socket = "run/crawl.stream"
+                state-file = "run/crawl.state"
+                max-jobs = 8
+                log-level = "warn"
+                rate-limit = 2.5
+
+                [crawl]
+                max-depth = 3
+                header = ["Accept-Language: en", "X-Team: docs"]
+                sitemap = true
+                cookies = "cookies.txt"
+                "#,
+                Path::new("/etc/crawl"),
+            )
+            .unwrap();
+        config
+            .apply_env(
+                [
+                    ("CRAWL_KEEP_RUNS", "20"),
+                    ("CRAWL_OPTION_MAX_DEPTH", "5"),
+                    ("CRAWL_SITE", "https://example.com/"),
+                ]
+                .into_iter()
+                .map(|(name, value)| (name.to_string(), value.to_string())),
+                Path::new("/home/user"),
+            )
+            .unwrap();
+        config.validate().unwrap();
+        assert_eq!(
+            config.stream_path,
+            PathBuf::from("/etc/crawl/run/crawl.stream")
+        );
+        assert_eq!(
+            config.state_path,
+            PathBuf::from("/etc/crawl/run/crawl.state")
+        );
+        assert_eq!(config.pid_path, PathBuf::from("/tmp/crawl.pid"));
+        assert_eq!((config.max_jobs, config.keep_runs), (8, 20));
+        assert_eq!(
+            config.live(),
+            LiveSettings {
+                log_level: LogLevel::Warn,
+                rate_limit: Some(2.5)
+            }
+        );
+        assert_eq!(
+            config.crawl_lines(),
+            vec![
+                "cookies=/etc/crawl/cookies.txt",
+                "header=Accept-Language: en",
+                "header=X-Team: docs",
+                "max-depth=5",
+                "sitemap=true"
+            ]
+        );
+
+        //The defaults a client sends come back apart from the job's own lines, even when there are none
+        let lines = config.client_lines();
+        let (defaults, rest) = take_client_defaults(
+            lines
+                .iter()
+                .map(|line| line.as_str())
+                .chain(["max-depth=1"]),
+        );
+        assert_eq!(defaults, Some(config.crawl_lines()));
+        assert_eq!(rest, vec!["max-depth=1"]);
+        let lines = Config::default().client_lines();
+        let (defaults, _) = take_client_defaults(lines.iter().map(|line| line.as_str()));
+        assert_eq!(defaults, Some(Vec::new()));
+        assert_eq!(take_client_defaults(["max-depth=1"].into_iter()).0, None);
+
+        let error = |text: &str| {
+            let mut config = Config::default();
+            config
+                .apply_toml(text, Path::new("/etc/crawl"))
+                .and_then(|_| config.validate())
+                .unwrap_err()
+        };
+        assert_eq!(
+            error("[daemon]\nmax-jobs = 0"),
+            "[daemon] max-jobs expects at least 1 but was given 0"
+        );
+        assert_eq!(
+            error("[daemon]\nlog-file = \"crawl.log\""),
+            "[daemon] Unknown daemon setting log-file"
+        );
+        assert_eq!(
+            error("[daemon]\nlog-level = \"verbose\""),
+            "[daemon] log-level expects error, warn, info or debug but was given verbose"
+        );
+        assert_eq!(
+            error("[daemon]\nrate-limit = 0"),
+            "[daemon] rate-limit expects a positive number of pages a second but was given 0"
+        );
+        assert_eq!(
+            error("[crawl]\nmax-depth = \"deep\""),
+            "[crawl] --max-depth expects a whole number but was given deep"
+        );
+        assert_eq!(
+            error("[crawl]\nmax-dept = 3"),
+            "[crawl] Unknown option --max-dept"
+        );
+        assert_eq!(
+            error("[jobs]\nmax = 3"),
+            "Unknown table [jobs], only [daemon] and [crawl] are read"
+        );
+        assert!(error("[crawl\nmax-depth = 3").contains("invalid table header"));
+
+        let env_error = |name: &str, value: &str| {
+            Config::default()
+                .apply_env(
+                    [(name.to_string(), value.to_string())].into_iter(),
+                    Path::new("/home/user"),
+                )
+                .unwrap_err()
+        };
+        assert_eq!(
+            env_error("CRAWL_MAX_JOB", "4"),
+            "CRAWL_MAX_JOB: Unknown daemon setting max-job"
+        );
+        assert_eq!(
+            env_error("CRAWL_OPTION_MAX_DEPT", "3"),
+            "CRAWL_OPTION_MAX_DEPT: Unknown option --max-dept"
+        );
+        assert_eq!(
+            env_error("CRAWL_MAX_JOBS", "none"),
+            "CRAWL_MAX_JOBS: max-jobs expects at least 1 but was given none"
+        );
+    }
+
+    #[test]
+    fn test_config_changes() {
+        let old = Config::default();
+        let mut new = Config::default();
+        assert_eq!(old.changes(&new), Ok(Vec::new()));
+
+        new.apply_toml(
+            "[daemon]\nmax-jobs = 2\nrate-limit = 4\n[crawl]\nuser-agent = \"examplebot\"",
+            Path::new("/etc/crawl"),
+        )
+        .unwrap();
+        assert_eq!(
+            old.changes(&new),
+            Ok(vec![
+                "at most 2 jobs now run at once".to_string(),
+                "jobs without their own rate limit now fetch at most 4 pages a second".to_string(),
+                "new jobs and scheduled runs use the new crawl defaults".to_string()
+            ])
+        );
+
+        new.stream_path = PathBuf::from("/run/crawl.stream");
+        assert!(old.changes(&new).is_err());
+    }
+}
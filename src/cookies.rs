@@ -0,0 +1,105 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use url::Url;
+
+//Converts a Netscape cookies.txt file into Set-Cookie strings paired with a url the cookie can be set from
+//Each line holds domain, subdomain flag, path, secure flag, expiry, name and value separated by tabs
+//Cookies which have already expired are dropped
+pub fn parse_cookie_file(contents: &str) -> Result<Vec<(String, Url)>, String> {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or_default();
+
+    let mut cookies = Vec::new();
+    for (index, line) in contents.lines().enumerate() {
+        //curl marks HttpOnly cookies with a prefix that otherwise looks like a comment
+        let line = line.strip_prefix("#HttpOnly_").unwrap_or(line);
+        if line.trim().is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let fields: Vec<&str> = line.trim_end_matches(['\r', '\n']).split('\t').collect();
+        if fields.len() != 7 {
+            return Err(format!(
+                "Line {} of the cookie file should have 7 tab separated fields but has {}",
+                index + 1,
+                fields.len()
+            ));
+        }
+        let (domain, subdomains, path, secure, expiry, name, value) = (
+            fields[0], fields[1], fields[2], fields[3], fields[4], fields[5], fields[6],
+        );
+        let expiry = expiry.parse::<u64>().map_err(|_| {
+            format!(
+                "Line {} of the cookie file has an invalid expiry",
+                index + 1
+            )
+        })?;
+        if expiry != 0 && expiry <= now {
+            continue;
+        }
+
+        let host = domain.trim_start_matches('.');
+        let secure = secure.eq_ignore_ascii_case("TRUE");
+        let url = Url::parse(&format!(
+            "{}://{}{}",
+            if secure { "https" } else { "http" },
+            host,
+            path
+        ))
+        .map_err(|e| {
+            format!(
+                "Line {} of the cookie file has an invalid domain: {}",
+                index + 1,
+                e
+            )
+        })?;
+
+        let mut cookie = format!("{}={}; Path={}", name, value, path);
+        if subdomains.eq_ignore_ascii_case("TRUE") {
+            cookie = cookie + "; Domain=" + host;
+        }
+        if secure {
+            cookie += "; Secure";
+        }
+        if expiry != 0 {
+            cookie = cookie + "; Max-Age=" + &(expiry - now).to_string();
+        }
+        cookies.push((cookie, url));
+    }
+    Ok(cookies)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::parse_cookie_file;
+    use url::Url;
+
+    #[test]
+    fn test_parse_cookie_file() {
+        let contents = "# Netscape HTTP Cookie File\n\
+            .example.com\tTRUE\t/\tFALSE\t0\tsession\tabc\n\
+            #HttpOnly_docs.example.com\tFALSE\t/guide\tTRUE\t99999999999\ttoken\txyz\n\
+            old.example.com\tFALSE\t/\tFALSE\t1\texpired\tgone\n";
+        let cookies = parse_cookie_file(contents).expect("Couldn't parse the cookie file");
+
+        assert_eq!(cookies.len(), 2);
+        assert_eq!(
+            cookies[0],
+            (
+                "session=abc; Path=/; Domain=example.com".to_string(),
+                Url::parse("http://example.com/").unwrap()
+            )
+        );
+        assert!(cookies[1]
+            .0
+            .starts_with("token=xyz; Path=/guide; Secure; Max-Age="));
+        assert_eq!(
+            cookies[1].1,
+            Url::parse("https://docs.example.com/guide").unwrap()
+        );
+
+        assert!(parse_cookie_file("example.com\tTRUE\t/\n").is_err());
+    }
+}
@@ -1,111 +1,842 @@
 #[allow(clippy::single_component_path_imports)]
 use libc;
 
-use std::collections::{HashMap, HashSet, VecDeque};
+use std::collections::{BTreeSet, HashMap};
 use std::fs::File;
 use std::io::{self, BufRead};
+use std::path::PathBuf;
 use std::process::exit;
+use std::sync::OnceLock;
+use std::time::{Duration, SystemTime};
 
-use crate::node::{parse_url, tree_url_get};
-use crate::tree::{SiteTree, SubSites};
-use crate::{clear_option, kill_option, PID_PATH, STREAM_PATH};
+use crate::clear_option;
+use crate::client::build_client;
+use crate::config::{load_config, take_client_defaults, Config};
+use crate::live::{Live, LiveSettings};
+use crate::node::{parse_root, parse_url};
+use crate::notify::{check_alerts, send_notification};
+use crate::options::CrawlOptions;
+use crate::queue::{JobQueue, QueuedJob, Task};
+use crate::report::SiteCrawl;
+use crate::schedule::{fetched_pages, format_time, prune_runs, Run, Schedule, StoredCrawl};
+use crate::sitemap::{encode_files, generate_sitemaps};
+use crate::snapshot::save_snapshot;
+use crate::state::{load_state, save_state, SavedState};
+use crate::tree::SiteTree;
 
 use nix::sys::signal::{kill, sigaction, SaFlags, SigAction, SigHandler, SigSet, Signal, SIGINT};
 use nix::unistd::Pid;
 
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt};
 use tokio::net::{UnixListener, UnixStream};
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::watch;
 
-extern "C" fn handle_sigint(_: libc::c_int, _: *mut libc::siginfo_t, _: *mut libc::c_void) {
-    clear_option();
-    exit(0);
-}
-
-pub fn check_daemon() -> bool {
-    let pid_file = File::open(PID_PATH).is_ok();
+const MAX_COMMAND_SIZE: u64 = 64 * 1024;
+//Commands are handled one at a time, so a client which never finishes sending is given up on after this long
+const COMMAND_TIMEOUT: Duration = Duration::from_secs(5);
+//How often the daemon looks for scheduled crawls which are due
+const SCHEDULE_TICK: Duration = Duration::from_secs(1);
+//The files the daemon was started with, which are removed when it's interrupted
+static DAEMON_CONFIG: OnceLock<Config> = OnceLock::new();
 
-    let daemon_active = if pid_file {
-        let file = File::open(PID_PATH).expect("Cant open the pid file, it's likely the program hasn't been started or was stopped already");
-        let reader = io::BufReader::new(file);
+//A crawl running in the background, remembered so it can be added to the site's history once it finishes
+struct Job {
+    handle: tokio::task::JoinHandle<SiteCrawl>,
+    started: SystemTime,
+    scheduled: bool,
+    //The site's last crawl, which the job's alert rules are checked against
+    previous: Option<SiteTree>,
+}
 
-        let first_line = reader
-            .lines()
-            .next()
-            .expect("Their is nothing left to iterate over")
-            .expect("No String was able to be returned in the Result");
+impl Job {
+    fn start(queued: QueuedJob, live: Live) -> Job {
+        Job {
+            handle: queued.task.spawn(live),
+            started: SystemTime::now(),
+            scheduled: queued.scheduled,
+            previous: queued.previous,
+        }
+    }
+}
 
-        let main_task = Pid::from_raw(
-            first_line
-                .parse::<i32>()
-                .expect("Parse Error on PID resource"),
-        );
-        kill(main_task, None).is_ok()
-    } else {
-        false
-    };
+extern "C" fn handle_sigint(_: libc::c_int, _: *mut libc::siginfo_t, _: *mut libc::c_void) {
+    if let Some(config) = DAEMON_CONFIG.get() {
+        clear_option(config);
+    }
+    exit(0);
+}
 
-    if pid_file && daemon_active {
-        true
-    } else if pid_file || daemon_active {
-        kill_option();
-        panic!("An unknown error occured due to a mismatch of the PID file being available but the daemon not being active");
-    } else {
-        false
+pub fn check_daemon(config: &Config) -> bool {
+    if File::open(&config.pid_path).is_err() {
+        return false;
     }
+    match daemon_pid(config) {
+        Some(pid) if kill(pid, None).is_ok() => true,
+        //The pid file was left behind by a daemon which didn't exit cleanly, so its files are stale
+        _ => {
+            clear_option(config);
+            false
+        }
+    }
+}
+
+//The daemon's process id from its pid file
+pub fn daemon_pid(config: &Config) -> Option<Pid> {
+    let file = File::open(&config.pid_path).ok()?;
+    let first_line = io::BufReader::new(file).lines().next()?.ok()?;
+    first_line.trim().parse::<i32>().ok().map(Pid::from_raw)
 }
 
-pub fn message_daemon(command: String, website: Option<String>) -> Vec<u8> {
+pub fn message_daemon(
+    config: &Config,
+    command: String,
+    website: Option<String>,
+) -> Result<Vec<u8>, String> {
     let message_to_server = if let Some(website) = website {
         format!("{} {}", command.as_str(), website.as_str())
     } else {
         command
     };
 
-    let mut byte_response = Vec::new();
     tokio::runtime::Builder::new_multi_thread()
         .enable_all()
         .build()
-        .unwrap()
+        .map_err(|e| format!("Couldn't start the tokio runtime: {}", e))?
         .block_on(async {
             // Connect to the UnixListener
-            let stream = UnixStream::connect(STREAM_PATH)
+            let stream = UnixStream::connect(&config.stream_path)
                 .await
-                .expect("Couldn't connect to the unix stream");
-            byte_response = handle_server(stream, message_to_server).await;
-
-            Result::<(), ()>::Ok(())
+                .map_err(|e| format!("Couldn't connect to the daemon: {}", e))?;
+            handle_server(stream, message_to_server)
+                .await
+                .map_err(|e| format!("Couldn't talk to the daemon: {}", e))
         })
-        .expect("tokio runtime failed");
-    byte_response
 }
 
-async fn handle_server(mut stream: UnixStream, command: String) -> Vec<u8> {
-    stream
-        .write_all(command.as_bytes())
-        .await
-        .expect("Couldn't stream the given command as bytes");
+async fn handle_server(mut stream: UnixStream, command: String) -> io::Result<Vec<u8>> {
+    stream.write_all(command.as_bytes()).await?;
+    //Closing the write half tells the daemon the whole command has been sent
+    stream.shutdown().await?;
 
-    // Write data to the server
-    let mut buffer = Vec::new();
     // Read the server's response
-    loop {
-        let mut chunk = vec![0; 1024];
-        let bytes_read = stream
-            .read(&mut chunk)
-            .await
-            .expect("Cannot read information from the socket");
-        if bytes_read == 0 {
-            // No more data to read, exit the loop
-            break;
+    let mut buffer = Vec::new();
+    stream.read_to_end(&mut buffer).await?;
+    Ok(buffer)
+}
+
+//Commands with options can be long so everything up to the client closing its write half is read
+async fn read_command<S: AsyncRead + Unpin>(
+    stream: &mut S,
+    limit: Duration,
+) -> Result<String, String> {
+    let mut buffer = Vec::new();
+    let mut reader = stream.take(MAX_COMMAND_SIZE);
+    match tokio::time::timeout(limit, reader.read_to_end(&mut buffer)).await {
+        Ok(Ok(_)) => Ok(String::from_utf8_lossy(&buffer).into_owned()),
+        Ok(Err(e)) => Err(format!("Couldn't read the command: {}", e)),
+        Err(_) => Err(format!(
+            "The command wasn't finished within {}s",
+            limit.as_secs()
+        )),
+    }
+}
+
+//Every reply starts with a line saying whether the command worked, followed by the result or why it failed
+const REPLY_OK: &str = "ok";
+const REPLY_ERR: &str = "err";
+
+fn reply(response: Result<String, String>) -> String {
+    match response {
+        Ok(result) => format!("{}\n{}", REPLY_OK, result),
+        Err(reason) => format!("{}\n{}", REPLY_ERR, reason),
+    }
+}
+
+//Splits the status line off a reply, a reply without one is from a daemon older than the client
+pub fn read_reply(reply: &str) -> Result<String, String> {
+    match reply.split_once('\n') {
+        Some((REPLY_OK, result)) => Ok(result.to_string()),
+        Some((REPLY_ERR, reason)) => Err(reason.to_string()),
+        _ => Err(format!(
+            "The daemon's reply didn't say whether it worked, restart the daemon if it's older than the client: {}",
+            reply
+        )),
+    }
+}
+
+//Everything the daemon keeps between commands
+struct DaemonState {
+    config: Config,
+    //The file given with --config, read again on a reload
+    config_path: Option<PathBuf>,
+    processes: HashMap<String, Job>,
+    completed: HashMap<String, Vec<StoredCrawl>>,
+    //The id the next result of each site gets, it only goes up so a forgotten or pruned id is never given out again
+    next_ids: HashMap<String, usize>,
+    history: HashMap<String, Vec<Run>>,
+    schedules: HashMap<String, Schedule>,
+    queue: JobQueue,
+    //Starts at the configured limit and is changed by max-jobs
+    max_jobs: usize,
+    //The log level and rate limit every running job reads, replaced on a reload
+    live: watch::Sender<LiveSettings>,
+}
+
+impl DaemonState {
+    fn new(config: Config, config_path: Option<PathBuf>) -> DaemonState {
+        DaemonState {
+            max_jobs: config.max_jobs,
+            live: watch::Sender::new(config.live()),
+            config,
+            config_path,
+            processes: HashMap::new(),
+            completed: HashMap::new(),
+            next_ids: HashMap::new(),
+            history: HashMap::new(),
+            schedules: HashMap::new(),
+            queue: JobQueue::default(),
+        }
+    }
+
+    //Picks up the schedules and histories saved by an earlier daemon
+    fn restore(&mut self) {
+        let saved = match load_state(&self.config.state_path) {
+            Ok(saved) => saved,
+            Err(e) => {
+                eprintln!("{}", e);
+                return;
+            }
+        };
+        let (schedules, errors) = saved.schedules(&self.config.crawl_lines());
+        for error in errors {
+            eprintln!("{}", error);
+        }
+        self.schedules = schedules;
+        self.history = saved.history.into_iter().collect();
+    }
+
+    //Saved after every change to the schedules or histories, a failure is logged and the daemon carries on
+    fn save(&self) {
+        let saved = SavedState::new(&self.schedules, &self.history);
+        if let Err(e) = save_state(&self.config.state_path, &saved) {
+            eprintln!("Couldn't save the daemon's state: {}", e);
+        }
+    }
+}
+
+//The newest finished crawl of a site, which commands like sitemap and recrawl use
+fn latest<'a>(
+    completed: &'a HashMap<String, Vec<StoredCrawl>>,
+    site: &str,
+) -> Option<&'a SiteCrawl> {
+    completed
+        .get(site)
+        .and_then(|results| results.last())
+        .map(|result| &result.crawl)
+}
+
+//Moves the results of finished jobs into completed, adds them to each site's history and gives back the sites
+//still being crawled
+async fn collect_finished(state: &mut DaemonState) -> Vec<String> {
+    let mut running = Vec::new();
+    let mut collected = false;
+    let sites: Vec<String> = state.processes.keys().cloned().collect();
+    for site in sites {
+        if !state.processes[&site].handle.is_finished() {
+            running.push(site);
+            continue;
+        }
+        collected = true;
+        if let Some(job) = state.processes.remove(&site) {
+            match job.handle.await {
+                Ok(crawl) => {
+                    let run = Run::new(&crawl, job.started, job.scheduled);
+                    if let Some(notification) = job
+                        .previous
+                        .and_then(|previous| check_alerts(&previous, &crawl, run.finished))
+                    {
+                        //Slow webhooks shouldn't hold up the daemon
+                        let options = crawl.options.clone();
+                        tokio::spawn(async move {
+                            println!("Sending alerts for {}", notification.site);
+                            for failure in send_notification(&notification, &options).await {
+                                eprintln!("{}", failure);
+                            }
+                        });
+                    }
+                    //Every crawl of a site is kept as its own result until the retention limits drop it
+                    let next_id = state.next_ids.entry(site.clone()).or_insert(1);
+                    let id = *next_id;
+                    *next_id += 1;
+                    state
+                        .completed
+                        .entry(site.clone())
+                        .or_default()
+                        .push(StoredCrawl {
+                            id,
+                            finished: run.finished,
+                            crawl,
+                        });
+                    state.history.entry(site).or_default().push(run);
+                }
+                Err(_) => {
+                    println!("The tree for {} didn't complete properly", site)
+                }
+            }
+        }
+    }
+    //Old runs and results are dropped even for sites which haven't been crawled in a while
+    let now = SystemTime::now();
+    let limits = |site: &String| match state.schedules.get(site) {
+        Some(schedule) => (
+            schedule.keep_runs.unwrap_or(state.config.keep_runs),
+            schedule.keep_for,
+        ),
+        None => (state.config.keep_runs, None),
+    };
+    for (site, runs) in state.history.iter_mut() {
+        let (keep_runs, keep_for) = limits(site);
+        prune_runs(runs, keep_runs, keep_for, now);
+    }
+    for (site, results) in state.completed.iter_mut() {
+        let (keep_runs, keep_for) = limits(site);
+        prune_runs(results, keep_runs, keep_for, now);
+    }
+    state.completed.retain(|_, results| !results.is_empty());
+    if collected {
+        state.save();
+    }
+    running
+}
+
+//Queues a crawl for every schedule which is due, unless the site is already being crawled or waiting to be
+fn queue_due_schedules(state: &mut DaemonState) {
+    let now = SystemTime::now();
+    let mut due = false;
+    for (site, schedule) in state.schedules.iter_mut() {
+        if schedule.next > now {
+            continue;
         }
+        due = true;
+        schedule.next = schedule.timing.next_after(now);
+        if state.processes.contains_key(site) || state.queue.contains(site) {
+            println!(
+                "Skipped the scheduled crawl of {} as it's still running",
+                site
+            );
+            continue;
+        }
+        let task = parse_root(site).and_then(|url| {
+            let client = build_client(&url, &schedule.options).map_err(|e| e.to_string())?;
+            Ok(Task::Crawl(url, client, Box::new(schedule.options.clone())))
+        });
+        match task {
+            Ok(task) => state.queue.push(QueuedJob {
+                site: site.clone(),
+                task,
+                priority: schedule.options.priority,
+                queued: now,
+                scheduled: true,
+                previous: latest(&state.completed, site).map(|crawl| crawl.tree.clone()),
+            }),
+            Err(e) => eprintln!("Couldn't start the scheduled crawl of {}: {}", site, e),
+        }
+    }
+    //The new next run times are saved so a restart doesn't run the sites again
+    if due {
+        state.save();
+    }
+}
 
-        // Accumulate the received data
-        buffer.extend_from_slice(&chunk[..bytes_read]);
+//Starts queued jobs until the limit of running jobs is reached
+fn start_queued(state: &mut DaemonState) {
+    while state.processes.len() < state.max_jobs {
+        match state.queue.pop() {
+            Some(queued) => {
+                println!("Started the job for {}", queued.site);
+                state.processes.insert(
+                    queued.site.clone(),
+                    Job::start(queued, state.live.subscribe()),
+                );
+            }
+            None => break,
+        }
     }
-    buffer
 }
 
-pub fn daemon_server() {
+//Whether the site has a job running or waiting for a slot
+fn is_busy(state: &DaemonState, site: &str) -> bool {
+    state.processes.contains_key(site) || state.queue.contains(site)
+}
+
+//What the reply to a start or recrawl is once the job was queued
+fn queued_response(state: &DaemonState, site: &str, started: &str) -> Result<String, String> {
+    match state
+        .queue
+        .ordered()
+        .iter()
+        .position(|job| job.site == site)
+    {
+        Some(ahead) => Ok(format!("Queued {}, {} jobs are ahead of it", site, ahead)),
+        None if state.processes.contains_key(site) => Ok(String::from(started) + site),
+        None => Err(String::from("Couldn't start ") + site),
+    }
+}
+
+//The running and queued jobs of every site
+fn jobs_status(state: &DaemonState) -> String {
+    let mut status = format!(
+        "Running {} of at most {} jobs:",
+        state.processes.len(),
+        state.max_jobs
+    );
+    for (site, job) in state.processes.iter() {
+        status += &format!("\n  {} since {}", site, format_time(job.started));
+    }
+    status += &format!("\nQueued ({}):", state.queue.len());
+    for job in state.queue.ordered() {
+        status += &format!(
+            "\n  {} with priority {} since {}",
+            job.site,
+            job.priority,
+            format_time(job.queued)
+        );
+    }
+    status
+}
+
+//One line for every site the daemon knows of with what it's doing and the page count and finish time of its
+//newest result
+fn sites_summary(state: &DaemonState) -> String {
+    let queued = state.queue.ordered();
+    let sites: BTreeSet<&String> = state
+        .processes
+        .keys()
+        .chain(queued.iter().map(|job| &job.site))
+        .chain(state.completed.keys())
+        .chain(state.schedules.keys())
+        .collect();
+    if sites.is_empty() {
+        return String::from("The daemon hasn't been given any sites");
+    }
+    let mut summary = String::from("url status pages finished");
+    for site in sites {
+        let status = if state.processes.contains_key(site) {
+            "running"
+        } else if state.queue.contains(site) {
+            "queued"
+        } else if state.completed.contains_key(site) {
+            "finished"
+        } else {
+            "scheduled"
+        };
+        let newest = state.completed.get(site).and_then(|results| results.last());
+        let (pages, finished) = match newest {
+            Some(result) => (
+                fetched_pages(&result.crawl.tree).0.to_string(),
+                format_time(result.finished),
+            ),
+            None => (String::from("-"), String::from("-")),
+        };
+        summary += &format!("\n{} {} {} {}", site, status, pages, finished);
+    }
+    summary
+}
+
+//The schedule, current job and past runs of a site
+fn site_status(state: &DaemonState, site: &str) -> String {
+    let mut status = String::new();
+    match state.schedules.get(site) {
+        Some(schedule) => {
+            status += &format!(
+                "{} is crawled {}, next at {}, keeping {} runs",
+                site,
+                schedule.timing,
+                format_time(schedule.next),
+                schedule.keep_runs.unwrap_or(state.config.keep_runs)
+            );
+            if let Some(keep_for) = schedule.keep_for {
+                status += &format!(" for up to {}s", keep_for.as_secs());
+            }
+            status += "\n";
+        }
+        None => status += &format!("{} isn't scheduled\n", site),
+    }
+    match state.processes.get(site) {
+        Some(job) => {
+            status += &format!("Being crawled since {}\n", format_time(job.started));
+        }
+        None if state.queue.contains(site) => {
+            let ordered = state.queue.ordered();
+            let ahead = ordered.iter().position(|job| job.site == site);
+            let job = ordered[ahead.unwrap_or_default()];
+            status += &format!(
+                "Queued since {} with priority {}, {} jobs are ahead of it\n",
+                format_time(job.queued),
+                job.priority,
+                ahead.unwrap_or_default()
+            );
+        }
+        None => status += "Not being crawled\n",
+    }
+    let results = state
+        .completed
+        .get(site)
+        .map(Vec::as_slice)
+        .unwrap_or_default();
+    status += &format!("Results ({}):\n", results.len());
+    for result in results.iter().rev() {
+        status += &format!(
+            "  {} finished {}, {} pages\n",
+            result.id,
+            format_time(result.finished),
+            fetched_pages(&result.crawl.tree).0
+        );
+    }
+    let runs = state
+        .history
+        .get(site)
+        .map(Vec::as_slice)
+        .unwrap_or_default();
+    status += &format!("Runs ({}):", runs.len());
+    for run in runs.iter().rev() {
+        status += &format!("\n  {}", run);
+    }
+    status
+}
+
+//Loads the config files again for a SIGHUP or the reload command. The job limit and retention change straight
+//away and the crawl defaults are used from the next job on, jobs already running or queued keep their options
+//and so do schedules made with the client's own defaults
+fn reload_config(state: &mut DaemonState) -> Result<String, String> {
+    let new = load_config(state.config_path.as_deref())?;
+    apply_config(state, new)
+}
+
+//Switches the daemon to a newly loaded config
+fn apply_config(state: &mut DaemonState, new: Config) -> Result<String, String> {
+    let changes = state.config.changes(&new)?;
+    //A limit set with max-jobs is kept unless the config changes it
+    if new.max_jobs != state.config.max_jobs {
+        state.max_jobs = new.max_jobs;
+    }
+    if new.crawl != state.config.crawl {
+        let defaults = new.crawl_lines();
+        for (site, schedule) in state.schedules.iter_mut() {
+            if let Err(e) = schedule.apply_defaults(&defaults) {
+                eprintln!(
+                    "Kept the old crawl defaults for the schedule of {}: {}",
+                    site, e
+                );
+            }
+        }
+    }
+    //Running jobs pick these up before their next page
+    state.live.send_replace(new.live());
+    state.config = new;
+    Ok(if changes.is_empty() {
+        String::from("Reloaded the config, nothing changed")
+    } else {
+        format!("Reloaded the config, {}", changes.join(", "))
+    })
+}
+
+fn handle_stop(state: &mut DaemonState, argument: &str) -> Result<String, String> {
+    println!("Stop command received with argument: {}", argument);
+    if let Some(job) = state.processes.remove(argument) {
+        job.handle.abort();
+        Ok(String::from("Stopped scraping ") + argument)
+    } else if state.queue.remove(argument).is_some() {
+        Ok(String::from("Removed from the queue ") + argument)
+    } else {
+        Err(String::from("The daemon is not scraping ") + argument)
+    }
+}
+
+async fn handle_start(state: &mut DaemonState, argument: &str) -> Result<String, String> {
+    //The url is on the first line and any crawl options follow on their own lines, along with force=true when
+    //a site crawled before should be crawled again
+    let mut lines = argument.lines();
+    let argument = lines.next().unwrap_or_default().trim();
+    println!("Start command received with argument: {}", argument);
+    let (force, lines): (Vec<&str>, Vec<&str>) =
+        lines.partition(|line| line.trim() == "force=true");
+    collect_finished(state).await;
+    if is_busy(state, argument) {
+        return Err(String::from("Already scraping ") + argument);
+    }
+    if state.completed.contains_key(argument) && force.is_empty() {
+        return Err(String::from("Already scraped ")
+            + argument
+            + ", start it with --force to crawl it again");
+    }
+    let st_url = parse_root(argument)?;
+    //The configured defaults come first so the job's own options override them, a client given --config sends
+    //the defaults of that file instead
+    let (client_defaults, lines) = take_client_defaults(lines.into_iter());
+    let defaults = client_defaults.unwrap_or_else(|| state.config.crawl_lines());
+    let options = CrawlOptions::from_lines(defaults.iter().map(|line| line.as_str()).chain(lines))
+        .map_err(|e| String::from("Failed to parse crawl options: ") + &e)?;
+    let client = build_client(&st_url, &options)
+        .map_err(|e| String::from("Failed to configure the crawl: ") + &e.to_string())?;
+    state.queue.push(QueuedJob {
+        site: argument.to_string(),
+        priority: options.priority,
+        task: Task::Crawl(st_url, client, Box::new(options)),
+        queued: SystemTime::now(),
+        scheduled: false,
+        previous: latest(&state.completed, argument).map(|crawl| crawl.tree.clone()),
+    });
+    start_queued(state);
+    queued_response(state, argument, "Started scraping ")
+}
+
+async fn handle_list(state: &mut DaemonState) -> Result<String, String> {
+    println!("List command received");
+    collect_finished(state).await;
+    start_queued(state);
+    Ok(sites_summary(state))
+}
+
+async fn handle_show(state: &mut DaemonState, argument: &str) -> Result<String, String> {
+    //The url is on the first line, followed by result=n for an older result
+    //Without a url the newest result of every site is sent
+    let mut lines = argument.lines();
+    let argument = lines.next().unwrap_or_default().trim();
+    println!("Show command received with argument: {}", argument);
+    collect_finished(state).await;
+    let id = lines.find_map(|line| line.trim().strip_prefix("result="));
+    if argument.is_empty() {
+        return Ok(state
+            .completed
+            .keys()
+            .filter_map(|site| latest(&state.completed, site))
+            .map(|crawl| format!("\n{}", crawl))
+            .collect());
+    }
+    match (state.completed.get(argument), id) {
+        (Some(results), None) => Ok(results
+            .last()
+            .map(|result| result.crawl.to_string())
+            .unwrap_or_default()),
+        (Some(results), Some(id)) => results
+            .iter()
+            .find(|result| result.id.to_string() == id)
+            .map(|result| result.crawl.to_string())
+            .ok_or_else(|| format!("There is no result {} of {}", id, argument)),
+        (None, _) if is_busy(state, argument) => {
+            Err(argument.to_string() + " is still being processed")
+        }
+        (None, _) => Err(String::from("There is no finished crawl of ") + argument),
+    }
+}
+
+fn handle_schedule(state: &mut DaemonState, argument: &str) -> Result<String, String> {
+    //The url is on the first line, followed by when to crawl it, how many runs to keep and the crawl options
+    let mut lines = argument.lines();
+    let argument = lines.next().unwrap_or_default().trim();
+    println!("Schedule command received with argument: {}", argument);
+    //The configured defaults are kept apart from the site's own options and run limit so a reload can change them
+    let schedule = Schedule::from_lines(lines, SystemTime::now()).and_then(|mut schedule| {
+        schedule.apply_defaults(&state.config.crawl_lines())?;
+        let url = parse_root(argument)?;
+        build_client(&url, &schedule.options)
+            .map_err(|e| String::from("Failed to configure the crawl: ") + &e.to_string())?;
+        Ok(schedule)
+    });
+    match schedule {
+        Ok(schedule) => {
+            let response = format!(
+                "Scheduled {} {}, next at {}",
+                argument,
+                schedule.timing,
+                format_time(schedule.next)
+            );
+            state.schedules.insert(argument.to_string(), schedule);
+            state.save();
+            Ok(response)
+        }
+        Err(e) => Err(String::from("Failed to schedule: ") + &e),
+    }
+}
+
+fn handle_unschedule(state: &mut DaemonState, argument: &str) -> Result<String, String> {
+    println!("Unschedule command received with argument: {}", argument);
+    //The site's history and any crawl already running are kept
+    match state.schedules.remove(argument) {
+        Some(_) => {
+            state.save();
+            Ok(String::from("Unscheduled ") + argument)
+        }
+        None => Err(String::from("There is no schedule for ") + argument),
+    }
+}
+
+async fn handle_status(state: &mut DaemonState, argument: &str) -> Result<String, String> {
+    println!("Status command received with argument: {}", argument);
+    collect_finished(state).await;
+    start_queued(state);
+    //Without a url every running and queued job is shown
+    if argument.is_empty() {
+        Ok(jobs_status(state))
+    } else {
+        Ok(site_status(state, argument))
+    }
+}
+
+fn handle_max_jobs(state: &mut DaemonState, argument: &str) -> Result<String, String> {
+    println!("Max jobs command received with argument: {}", argument);
+    match argument.parse::<usize>() {
+        Ok(jobs) if jobs > 0 => {
+            //Running jobs are left alone when the limit is lowered
+            state.max_jobs = jobs;
+            start_queued(state);
+            Ok(format!("At most {} jobs now run at once", jobs))
+        }
+        _ => Err(String::from(
+            "The job limit must be a whole number of at least 1",
+        )),
+    }
+}
+
+async fn handle_recrawl(state: &mut DaemonState, argument: &str) -> Result<String, String> {
+    println!("Recrawl command received with argument: {}", argument);
+    collect_finished(state).await;
+    if is_busy(state, argument) {
+        return Err(argument.to_string() + " is still being processed");
+    }
+    //The newest result is copied into the job and updated there, so the crawl it started from is kept as a
+    //result of its own
+    let crawl = latest(&state.completed, argument)
+        .cloned()
+        .ok_or_else(|| String::from("There is no finished crawl of ") + argument)?;
+    let client = build_client(&crawl.tree.current_site, &crawl.options)
+        .map_err(|e| String::from("Failed to configure the crawl: ") + &e.to_string())?;
+    state.queue.push(QueuedJob {
+        site: argument.to_string(),
+        priority: crawl.options.priority,
+        previous: Some(crawl.tree.clone()),
+        task: Task::Recrawl(Box::new(crawl), client),
+        queued: SystemTime::now(),
+        scheduled: false,
+    });
+    start_queued(state);
+    queued_response(state, argument, "Started recrawling ")
+}
+
+async fn handle_forget(state: &mut DaemonState, argument: &str) -> Result<String, String> {
+    //The url is on the first line, followed by result=n to only forget one result
+    let mut lines = argument.lines();
+    let argument = lines.next().unwrap_or_default().trim();
+    println!("Forget command received with argument: {}", argument);
+    collect_finished(state).await;
+    let id = lines.find_map(|line| line.trim().strip_prefix("result="));
+    match (state.completed.get_mut(argument), id) {
+        (None, _) => Err(String::from("There is no finished crawl of ") + argument),
+        (Some(results), None) => {
+            let forgotten = results.len();
+            state.completed.remove(argument);
+            Ok(format!("Forgot {} results of {}", forgotten, argument))
+        }
+        (Some(results), Some(id)) => {
+            match results
+                .iter()
+                .position(|result| result.id.to_string() == id)
+            {
+                Some(index) => {
+                    results.remove(index);
+                    if results.is_empty() {
+                        state.completed.remove(argument);
+                    }
+                    Ok(format!("Forgot result {} of {}", id, argument))
+                }
+                None => Err(format!("There is no result {} of {}", id, argument)),
+            }
+        }
+    }
+}
+
+async fn handle_snapshot(state: &mut DaemonState, argument: &str) -> Result<String, String> {
+    println!("Snapshot command received with argument: {}", argument);
+    collect_finished(state).await;
+    match latest(&state.completed, argument) {
+        Some(crawl) => save_snapshot(&crawl.tree)
+            .map_err(|e| String::from("Couldn't save the crawl: ") + &e.to_string()),
+        None if is_busy(state, argument) => Err(argument.to_string() + " is still being processed"),
+        None => Err(String::from("There is no finished crawl of ") + argument),
+    }
+}
+
+async fn handle_sitemap(state: &mut DaemonState, argument: &str) -> Result<String, String> {
+    //The url is on the first line, followed by the base url the files will be served from and whether to
+    //include lastmod
+    let mut lines = argument.lines();
+    let argument = lines.next().unwrap_or_default().trim();
+    println!("Sitemap command received with argument: {}", argument);
+    collect_finished(state).await;
+    let crawl = match latest(&state.completed, argument) {
+        Some(crawl) => crawl,
+        None if is_busy(state, argument) => {
+            return Err(argument.to_string() + " is still being processed")
+        }
+        None => return Err(String::from("There is no finished crawl of ") + argument),
+    };
+    let mut base = crawl.tree.current_site.join("/").ok();
+    let mut lastmod = false;
+    for line in lines {
+        match line.split_once('=') {
+            Some(("base-url", value)) => base = parse_url(value.trim()).ok(),
+            Some(("lastmod", value)) => lastmod = value.trim() == "true",
+            _ => {}
+        }
+    }
+    match base {
+        Some(base) => Ok(encode_files(&generate_sitemaps(
+            &crawl.tree,
+            &base,
+            lastmod,
+        ))),
+        None => Err(String::from("Failed to get valid base URL")),
+    }
+}
+
+fn handle_reload(state: &mut DaemonState) -> Result<String, String> {
+    println!("Reload command received");
+    let response =
+        reload_config(state).map_err(|e| String::from("Failed to reload the config: ") + &e);
+    start_queued(state);
+    response
+}
+
+//The reply is Err when the daemon couldn't do what it was asked
+async fn handle_command(
+    state: &mut DaemonState,
+    command: &str,
+    argument: &str,
+) -> Result<String, String> {
+    match command {
+        "stop" => handle_stop(state, argument),
+        "start" => handle_start(state, argument).await,
+        "list" => handle_list(state).await,
+        "show" => handle_show(state, argument).await,
+        "schedule" => handle_schedule(state, argument),
+        "unschedule" => handle_unschedule(state, argument),
+        "status" => handle_status(state, argument).await,
+        "max-jobs" => handle_max_jobs(state, argument),
+        "recrawl" => handle_recrawl(state, argument).await,
+        "forget" => handle_forget(state, argument).await,
+        "snapshot" => handle_snapshot(state, argument).await,
+        "sitemap" => handle_sitemap(state, argument).await,
+        "reload" => handle_reload(state),
+        _ => {
+            println!("Unknown command");
+            Err(String::from("Unknown command"))
+        }
+    }
+}
+
+pub fn daemon_server(config: Config, config_path: Option<PathBuf>) {
     unsafe {
         let mut sigset = SigSet::empty();
         sigset.add(Signal::SIGINT);
@@ -116,134 +847,58 @@ pub fn daemon_server() {
         );
         sigaction(SIGINT, &sig_action).expect("SigAction could not be set");
     }
-    let mut processes: HashMap<String, tokio::task::JoinHandle<SiteTree>> = HashMap::new();
-    let mut completed: HashMap<String, String> = HashMap::new();
+    let _ = DAEMON_CONFIG.set(config.clone());
+    let mut state = DaemonState::new(config, config_path);
+    state.restore();
 
     tokio::runtime::Builder::new_multi_thread()
-    .enable_all()
-    .build()
-    .unwrap()
-    .block_on(
-        async {
-            let listener = UnixListener::bind(STREAM_PATH)
+        .enable_all()
+        .build()
+        .unwrap()
+        .block_on(async {
+            let listener = UnixListener::bind(&state.config.stream_path)
                 .expect("Bind the unix listener to the path");
+            let mut ticker = tokio::time::interval(SCHEDULE_TICK);
+            let mut hangup =
+                signal(SignalKind::hangup()).expect("Couldn't listen for the SIGHUP signal");
             loop {
-                match listener.accept().await {
+                //Scheduled crawls are started between commands
+                let accepted = tokio::select! {
+                    accepted = listener.accept() => accepted,
+                    _ = ticker.tick() => {
+                        collect_finished(&mut state).await;
+                        queue_due_schedules(&mut state);
+                        start_queued(&mut state);
+                        continue;
+                    }
+                    _ = hangup.recv() => {
+                        match reload_config(&mut state) {
+                            Ok(reloaded) => println!("{}", reloaded),
+                            Err(e) => eprintln!("Failed to reload the config: {}", e),
+                        }
+                        start_queued(&mut state);
+                        continue;
+                    }
+                };
+                match accepted {
                     Ok((mut stream, _addr)) => {
-                        let mut buffer = [0; 1024];
-                        let bytes_read = stream.read(&mut buffer).await.unwrap();
-                        // Process the received command
-                        let received_data =
-                            String::from_utf8_lossy(&buffer[..bytes_read]);
-                        let mut parts = received_data.trim().splitn(2, ' ');
-                        let command = parts.next().unwrap_or("");
-                        let argument = if let Some(argument) = parts.next() {
-                            argument
-                        } else {
-                            ""
-                        };
-                        let mut response = String::new();
-                        match command {
-                            "stop" => {
-                                println!(
-                                    "Stop command received with argument: {}",
-                                    argument
-                                );
-                                if processes.contains_key(argument){
-                                    response = String::from("Stopped scraping ") + argument;
-                                    processes.get(argument).expect("Couldn't get value from hash map").abort();
-                                    processes.remove(argument);
-                                } else {
-                                    response = String::from("The daemon is not scraping ") + argument;
-                                }
+                        let response = match read_command(&mut stream, COMMAND_TIMEOUT).await {
+                            Ok(received_data) => {
+                                // Process the received command
+                                let mut parts = received_data.trim().splitn(2, ' ');
+                                let command = parts.next().unwrap_or("");
+                                let argument = parts.next().unwrap_or_default();
+                                reply(handle_command(&mut state, command, argument).await)
                             }
-                            "start" => {
-                                println!(
-                                    "Start command received with argument: {}",
-                                    argument
-                                );
-                                #[allow(clippy::map_entry)]
-                                if !processes.contains_key(&argument.to_string()) && !completed.contains_key(&argument.to_string()) {
-                                    response = String::from("Started scraping ")
-                                    + argument;
-                                let st_url = if let Ok(st_url) =
-                                    parse_url(&argument.to_string())
-                                {
-                                    st_url
-                                } else {
-                                    response =
-                                        String::from("Failed to get valid URL");
-                                        stream
-                                            .write_all(response.as_bytes())
-                                            .await
-                                            .unwrap();
-                                    continue;
-                                };
-                                let background_process = tokio::spawn(async move {
-
-                                    let mut node = SiteTree {
-                                        current_site: st_url.clone(),
-                                        sub_sites: SubSites::Nil,
-                                    };
-                                    let mut site_set: HashSet<String> = HashSet::new();
-                                    let mut job_queue: VecDeque<&mut SiteTree> = VecDeque::new();
-                                    job_queue.push_back(&mut node);
-                                    while let Some(task) = job_queue.pop_front() {
-                                        print!("Current site being scanned: {}", task);
-                                        let tree_result = tree_url_get(
-                                            &mut (*task),
-                                            st_url.clone().domain()
-                                                .expect("The Domain was unable to be extracted from the url"),
-                                            &mut site_set,
-                                            &mut job_queue,
-                                        )
-                                        .await;
-                                        if let Err(e) = tree_result {
-                                            eprintln!("There was an error in parsing the URL or scraping the site. The error is: {}",&*e)
-                                        }
-                                    }
-                                    node
-                                });
-                                processes.insert(argument.to_string(), background_process);
-                                } else {
-                                    response = String::from("Already scraping or scraped ")
-                                    + argument;
-                                }
-                            }
-                            "list" => {
-                                println!("List command received");
-                                for (site,job_handle) in processes.iter_mut() {
-                                    if job_handle.is_finished() {
-                                            match job_handle.await{
-                                                Ok(full_tree) => {
-                                                    completed.insert(site.clone(), format!("{}",full_tree));
-                                                },
-                                                Err(_) => {
-                                                    println!("The tree for {} didn't complete properly",site)
-                                                }
-                                            };
-                                    } else {
-                                        //Handle partial job
-                                        response = site.clone() + " is still being processed";
-                                    }
-                                }
-                                //Slightly inefficiant to try and remove values every time but it's a minor computation once every call vs another data structure
-                                for (site, tree) in completed.iter() {
-                                    processes.remove(site);
-                                    response = format!("{}\n{}",response,tree);
-                                }
-                            }
-                            _ => {
-                                println!("Unknown command");
-                                response = String::from("Unknown command");
+                            Err(e) => {
+                                eprintln!("{}", e);
+                                reply(Err(e))
                             }
+                        };
+                        // Write a response back to the client, which may have given up on it already
+                        if let Err(e) = stream.write_all(response.as_bytes()).await {
+                            eprintln!("Couldn't send the reply: {}", e);
                         }
-                        stream
-                            .write_all(response.as_bytes())
-                            .await
-                            .unwrap();
-
-                        // Write a response back to the client
                     }
                     Err(e) => {
                         panic!("Connection failed due to {}", e);
@@ -253,6 +908,122 @@ pub fn daemon_server() {
             //This is just used as a conveniant identifier for return type in the compiler and is optomized out as a value due to being unreachable
             #[allow(unreachable_code)]
             Result::<(), Box<dyn std::error::Error>>::Ok(())
-        },)
-    .expect("Could not create a tokio runtime environment");
+        })
+        .expect("Could not create a tokio runtime environment");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        apply_config, collect_finished, handle_command, read_command, read_reply, reply,
+        DaemonState,
+    };
+    use crate::config::Config;
+    use crate::live::LogLevel;
+    use crate::test_server::{http_response, request_lines, serve};
+    use std::fs::remove_file;
+    use std::time::Duration;
+    use tokio::io::AsyncWriteExt;
+    use tokio::time::{sleep, timeout};
+
+    #[test]
+    fn test_reply_status() {
+        //Replies which would once have looked like failures only fail when the daemon says so
+        let text = "There is no finished crawl yet\nFailed 2 pages".to_string();
+        assert_eq!(read_reply(&reply(Ok(text.clone()))), Ok(text));
+        assert_eq!(
+            read_reply(&reply(Err("Already scraping a site".to_string()))),
+            Err("Already scraping a site".to_string())
+        );
+        assert_eq!(read_reply(&reply(Ok(String::new()))), Ok(String::new()));
+        assert!(read_reply("Started scraping https://example.com/").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_read_command() {
+        let (mut client, mut daemon) = tokio::io::duplex(1024);
+        client.write_all(b"status").await.unwrap();
+        client.shutdown().await.unwrap();
+        assert_eq!(
+            read_command(&mut daemon, Duration::from_secs(5)).await,
+            Ok("status".to_string())
+        );
+
+        //A client which never closes its write half doesn't hold up the daemon
+        let (mut client, mut daemon) = tokio::io::duplex(1024);
+        client.write_all(b"status").await.unwrap();
+        assert!(timeout(
+            Duration::from_secs(5),
+            read_command(&mut daemon, Duration::from_millis(100))
+        )
+        .await
+        .expect("The read should have given up")
+        .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_reject_root() {
+        let mut state = DaemonState::new(Config::default(), None);
+        //A client which skipped its own checks still can't queue a job without an http root
+        assert_eq!(
+            handle_command(&mut state, "start", "mailto:a@b").await,
+            Err("mailto:a@b isn't an http or https url with a host".to_string())
+        );
+        assert_eq!(
+            handle_command(&mut state, "schedule", "data:,x\nevery=1h").await,
+            Err("Failed to schedule: data:,x isn't an http or https url with a host".to_string())
+        );
+        assert!(state.queue.len() == 0 && state.schedules.is_empty());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_reload_running_job() {
+        let (url, requests) = serve(vec![
+            (
+                "/",
+                http_response(
+                    "200 OK",
+                    "Content-Type: text/html\r\n",
+                    "<a href=\"/a\">a</a>",
+                ),
+            ),
+            (
+                "/a",
+                http_response("200 OK", "Content-Type: text/html\r\n", ""),
+            ),
+        ]);
+        //The config is built here rather than loaded so the machine's own files and environment can't change it
+        let config = Config {
+            state_path: std::env::temp_dir().join(format!("crawl-reload-{}", std::process::id())),
+            rate_limit: Some(0.01),
+            ..Config::default()
+        };
+        let mut state = DaemonState::new(config.clone(), None);
+
+        //The second page is 100s away at this rate
+        let reply = handle_command(&mut state, "start", url.as_str()).await;
+        assert_eq!(reply, Ok(format!("Started scraping {}", url)));
+        sleep(Duration::from_millis(500)).await;
+        assert_eq!(collect_finished(&mut state).await, vec![url.to_string()]);
+        assert_eq!(request_lines(&requests), vec!["GET / HTTP/1.1"]);
+
+        let reloaded = Config {
+            log_level: LogLevel::Warn,
+            rate_limit: None,
+            ..config.clone()
+        };
+        assert_eq!(
+            apply_config(&mut state, reloaded),
+            Ok("Reloaded the config, jobs now log at the warn level, jobs without their own rate limit are no longer limited".to_string())
+        );
+        timeout(Duration::from_secs(10), async {
+            while !collect_finished(&mut state).await.is_empty() {
+                sleep(Duration::from_millis(50)).await;
+            }
+        })
+        .await
+        .expect("The job should carry on without the rate limit");
+        assert_eq!(state.history[url.as_str()][0].pages, 2);
+        remove_file(config.state_path).unwrap();
+    }
 }
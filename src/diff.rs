@@ -0,0 +1,343 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+
+use serde::Serialize;
+
+use url::Url;
+
+use crate::sitemap::page_key;
+use crate::tree::{LinkKind, SiteTree, SubSites};
+
+//What changed between two crawls of a site, urls are matched by address wherever they sit in the trees
+#[derive(Debug, PartialEq, Default, Serialize)]
+pub struct CrawlDiff {
+    pub added: Vec<Url>,
+    pub removed: Vec<Url>,
+    pub status_changes: Vec<StatusChange>,
+    //Pages whose redirects now end somewhere else, including pages which started or stopped redirecting
+    pub moved: Vec<Move>,
+    //Links to pages or external urls which fail in the new crawl but didn't in the old one
+    pub new_broken_links: Vec<BrokenLink>,
+}
+
+#[derive(Debug, PartialEq, Serialize)]
+pub struct StatusChange {
+    pub url: Url,
+    pub before: Option<u16>,
+    pub after: Option<u16>,
+}
+
+#[derive(Debug, PartialEq, Serialize)]
+pub struct Move {
+    pub url: Url,
+    pub before: Option<Url>,
+    pub after: Option<Url>,
+}
+
+#[derive(Debug, PartialEq, Serialize)]
+pub struct BrokenLink {
+    pub page: Url,
+    pub link: Url,
+    pub problem: String,
+}
+
+//What a crawl found out about one url, taken from the first node for it which was fetched or checked
+struct UrlState {
+    url: Url,
+    kind: LinkKind,
+    status: Option<u16>,
+    error: Option<String>,
+    location: Option<Url>,
+}
+
+impl UrlState {
+    fn problem(&self) -> Option<String> {
+        match (self.status, &self.error) {
+            (Some(status), _) if status >= 400 => Some(status.to_string()),
+            (_, Some(error)) => Some(error.clone()),
+            _ => None,
+        }
+    }
+}
+
+//Every url in a tree in the order it was first seen, along with each http link as page and target
+struct CrawlUrls {
+    order: Vec<String>,
+    states: HashMap<String, UrlState>,
+    links: Vec<(String, String)>,
+}
+
+fn crawl_urls(tree: &SiteTree) -> CrawlUrls {
+    let mut order = Vec::new();
+    let mut states: HashMap<String, UrlState> = HashMap::new();
+    let mut links = Vec::new();
+    let mut seen_links: HashSet<(String, String)> = HashSet::new();
+    tree.visit(&mut |node| {
+        let key = page_key(&node.current_site);
+        let state = UrlState {
+            url: node.current_site.clone(),
+            kind: node.page.kind,
+            status: node.page.status,
+            error: node.page.error.clone(),
+            location: node
+                .page
+                .redirects
+                .last()
+                .map(|redirect| redirect.location.clone()),
+        };
+        match states.get(&key) {
+            None => {
+                order.push(key.clone());
+                states.insert(key.clone(), state);
+            }
+            //A url repeated in the tree is only fetched at its first place, or checked at its first external one
+            Some(known) if known.status.is_none() && known.error.is_none() => {
+                states.insert(key.clone(), state);
+            }
+            _ => {}
+        }
+        if let SubSites::List(sub_sites) = &node.sub_sites {
+            for sub_site in sub_sites.iter() {
+                let link = (key.clone(), page_key(&sub_site.current_site));
+                if sub_site.page.kind.is_http() && seen_links.insert(link.clone()) {
+                    links.push(link);
+                }
+            }
+        }
+    });
+    CrawlUrls {
+        order,
+        states,
+        links,
+    }
+}
+
+pub fn diff_crawls(old: &SiteTree, new: &SiteTree) -> CrawlDiff {
+    let CrawlUrls {
+        order: old_order,
+        states: old_states,
+        links: old_links,
+    } = crawl_urls(old);
+    let CrawlUrls {
+        order: new_order,
+        states: new_states,
+        links: new_links,
+    } = crawl_urls(new);
+    let is_page = |state: &UrlState| state.kind == LinkKind::Internal;
+    let mut diff = CrawlDiff::default();
+
+    for key in new_order.iter() {
+        let after = &new_states[key];
+        match old_states.get(key) {
+            Some(before) if is_page(before) && is_page(after) => {
+                if before.status != after.status {
+                    diff.status_changes.push(StatusChange {
+                        url: after.url.clone(),
+                        before: before.status,
+                        after: after.status,
+                    });
+                }
+                if before.location != after.location {
+                    diff.moved.push(Move {
+                        url: after.url.clone(),
+                        before: before.location.clone(),
+                        after: after.location.clone(),
+                    });
+                }
+            }
+            Some(before) if is_page(before) => {}
+            _ if is_page(after) => diff.added.push(after.url.clone()),
+            _ => {}
+        }
+    }
+    for key in old_order.iter() {
+        let before = &old_states[key];
+        if is_page(before) && !new_states.get(key).is_some_and(is_page) {
+            diff.removed.push(before.url.clone());
+        }
+    }
+
+    //A link only counts as newly broken when it's new or its target worked in the old crawl
+    let old_links: HashSet<&(String, String)> = old_links.iter().collect();
+    for link in new_links.iter() {
+        let problem = match new_states[&link.1].problem() {
+            Some(problem) => problem,
+            None => continue,
+        };
+        let was_broken = old_links.contains(link)
+            && old_states
+                .get(&link.1)
+                .is_some_and(|before| before.problem().is_some());
+        if !was_broken {
+            diff.new_broken_links.push(BrokenLink {
+                page: new_states[&link.0].url.clone(),
+                link: new_states[&link.1].url.clone(),
+                problem,
+            });
+        }
+    }
+    diff
+}
+
+fn write_status(f: &mut fmt::Formatter<'_>, status: Option<u16>) -> fmt::Result {
+    match status {
+        Some(status) => write!(f, "{}", status),
+        None => write!(f, "no response"),
+    }
+}
+
+fn write_location(f: &mut fmt::Formatter<'_>, location: &Option<Url>) -> fmt::Result {
+    match location {
+        Some(location) => write!(f, "{}", location),
+        None => write!(f, "itself"),
+    }
+}
+
+impl fmt::Display for CrawlDiff {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Added pages ({}):", self.added.len())?;
+        for url in self.added.iter() {
+            writeln!(f, "  {}", url)?;
+        }
+        writeln!(f, "Removed pages ({}):", self.removed.len())?;
+        for url in self.removed.iter() {
+            writeln!(f, "  {}", url)?;
+        }
+        writeln!(f, "Status changes ({}):", self.status_changes.len())?;
+        for change in self.status_changes.iter() {
+            write!(f, "  {} ", change.url)?;
+            write_status(f, change.before)?;
+            write!(f, " -> ")?;
+            write_status(f, change.after)?;
+            writeln!(f)?;
+        }
+        writeln!(f, "Moved pages ({}):", self.moved.len())?;
+        for moved in self.moved.iter() {
+            write!(f, "  {} now goes to ", moved.url)?;
+            write_location(f, &moved.after)?;
+            write!(f, ", was ")?;
+            write_location(f, &moved.before)?;
+            writeln!(f)?;
+        }
+        writeln!(f, "New broken links ({}):", self.new_broken_links.len())?;
+        for broken in self.new_broken_links.iter() {
+            writeln!(f, "  {} {} on {}", broken.problem, broken.link, broken.page)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{diff_crawls, BrokenLink, CrawlDiff, Move, StatusChange};
+    use crate::redirect::Redirect;
+    use crate::tree::{LinkKind, SiteTree, SubSites};
+    use url::Url;
+
+    fn page(url: &Url, path: &str, status: u16, links: Vec<SiteTree>) -> SiteTree {
+        let mut page = SiteTree::child(url.join(path).unwrap(), 1);
+        page.page.status = Some(status);
+        page.sub_sites = SubSites::List(links);
+        page
+    }
+
+    #[test]
+    fn test_diff_crawls() {
+        let url = Url::parse("https://example.com/").unwrap();
+        let mut external = SiteTree::child(Url::parse("https://other.org/docs").unwrap(), 2);
+        external.page.kind = LinkKind::External;
+        external.page.status = Some(200);
+        let old = page(
+            &url,
+            "/",
+            200,
+            vec![
+                page(&url, "/about", 200, vec![external.clone()]),
+                page(&url, "/old", 200, Vec::new()),
+                page(&url, "/gone", 404, Vec::new()),
+                page(&url, "/team", 200, Vec::new()),
+            ],
+        );
+
+        external.page.status = Some(404);
+        let mut moved = page(&url, "/team", 200, Vec::new());
+        moved.page.redirects = vec![Redirect {
+            status: 301,
+            location: url.join("/people").unwrap(),
+        }];
+        //The same page placed elsewhere in the tree isn't a change
+        let new = page(
+            &url,
+            "/",
+            200,
+            vec![
+                page(
+                    &url,
+                    "/blog",
+                    200,
+                    vec![page(&url, "/about", 500, vec![external])],
+                ),
+                page(&url, "/gone", 404, Vec::new()),
+                moved,
+            ],
+        );
+
+        assert_eq!(
+            diff_crawls(&old, &new),
+            CrawlDiff {
+                added: vec![url.join("/blog").unwrap()],
+                removed: vec![url.join("/old").unwrap()],
+                status_changes: vec![StatusChange {
+                    url: url.join("/about").unwrap(),
+                    before: Some(200),
+                    after: Some(500),
+                }],
+                moved: vec![Move {
+                    url: url.join("/team").unwrap(),
+                    before: None,
+                    after: Some(url.join("/people").unwrap()),
+                }],
+                new_broken_links: vec![
+                    BrokenLink {
+                        page: url.join("/blog").unwrap(),
+                        link: url.join("/about").unwrap(),
+                        problem: "500".to_string(),
+                    },
+                    BrokenLink {
+                        page: url.join("/about").unwrap(),
+                        link: Url::parse("https://other.org/docs").unwrap(),
+                        problem: "404".to_string(),
+                    },
+                ],
+            }
+        );
+    }
+
+    #[test]
+    fn test_failed_page() {
+        let url = Url::parse("https://example.com/").unwrap();
+        let old = page(&url, "/", 200, vec![page(&url, "/docs", 200, Vec::new())]);
+        let mut failed = SiteTree::child(url.join("/docs").unwrap(), 1);
+        failed.page.error = Some("error trying to connect: Connection refused".to_string());
+        let new = page(&url, "/", 200, vec![failed]);
+
+        //A page which stopped answering changes status and is a newly broken link
+        let diff = diff_crawls(&old, &new);
+        assert_eq!(
+            diff.status_changes,
+            vec![StatusChange {
+                url: url.join("/docs").unwrap(),
+                before: Some(200),
+                after: None,
+            }]
+        );
+        assert_eq!(
+            diff.new_broken_links,
+            vec![BrokenLink {
+                page: url.clone(),
+                link: url.join("/docs").unwrap(),
+                problem: "error trying to connect: Connection refused".to_string(),
+            }]
+        );
+    }
+}
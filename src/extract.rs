@@ -0,0 +1,291 @@
+use std::sync::mpsc::{channel, Sender};
+
+use html5ever::tendril::StrTendril;
+use html5ever::tokenizer::states::RawKind;
+use html5ever::tokenizer::{
+    BufferQueue, TagKind, Token, TokenSink, TokenSinkResult, Tokenizer, TokenizerOpts,
+};
+
+use tokio::sync::mpsc;
+use tokio::task::{spawn_blocking, JoinHandle};
+
+use crate::fingerprint::SimHasher;
+
+//Collects hrefs and the ids fragments can point at from the token stream without ever building a document
+struct LinkSink {
+    links: Vec<String>,
+    anchors: Vec<String>,
+    max_links: usize,
+    text: SimHasher,
+    //Script and style contents aren't visible text, this holds the element until it's closed
+    hidden: Option<String>,
+}
+
+//Everything taken from a page
+#[derive(Debug, PartialEq)]
+pub struct Extracted {
+    pub links: Vec<String>,
+    //Values of id attributes on any element and name attributes on anchors
+    pub anchors: Vec<String>,
+    //SimHash of the visible text
+    pub simhash: u64,
+}
+
+impl TokenSink for LinkSink {
+    type Handle = ();
+
+    fn process_token(&mut self, token: Token, _line_number: u64) -> TokenSinkResult<()> {
+        match &token {
+            Token::CharacterTokens(text) if self.hidden.is_none() => self.text.feed(text),
+            Token::TagToken(tag)
+                if tag.kind == TagKind::EndTag && self.hidden.as_deref() == Some(&*tag.name) =>
+            {
+                self.hidden = None
+            }
+            //Text on either side of a tag is kept apart, otherwise the last word of one block runs into the next
+            Token::TagToken(_) => self.text.feed(" "),
+            _ => {}
+        }
+        if let Token::TagToken(tag) = token {
+            if tag.kind == TagKind::StartTag {
+                for attr in tag.attrs.iter() {
+                    let name = &*attr.name.local;
+                    if name == "id" || (name == "name" && &*tag.name == "a") {
+                        self.anchors.push(attr.value.to_string());
+                    }
+                }
+                //Without a tree builder the tokenizer has to be told which elements hold raw text,
+                //otherwise markup inside scripts and styles would be read as links
+                match &*tag.name {
+                    "a" if self.links.len() < self.max_links => {
+                        if let Some(href) =
+                            tag.attrs.iter().find(|attr| &*attr.name.local == "href")
+                        {
+                            self.links.push(href.value.to_string());
+                        }
+                    }
+                    "script" => {
+                        self.hidden = Some(tag.name.to_string());
+                        return TokenSinkResult::RawData(RawKind::ScriptData);
+                    }
+                    "style" | "xmp" | "iframe" | "noembed" | "noframes" => {
+                        self.hidden = Some(tag.name.to_string());
+                        return TokenSinkResult::RawData(RawKind::Rawtext);
+                    }
+                    "title" | "textarea" => return TokenSinkResult::RawData(RawKind::Rcdata),
+                    "plaintext" => return TokenSinkResult::Plaintext,
+                    _ => {}
+                }
+            }
+        }
+        TokenSinkResult::Continue
+    }
+}
+
+//The tokenizer and any trailing bytes of a UTF-8 sequence split across chunks
+//Tendrils use non atomic reference counts so this never leaves the thread it was made on
+struct PageTokenizer {
+    tokenizer: Tokenizer<LinkSink>,
+    queue: BufferQueue,
+    partial: Vec<u8>,
+}
+
+impl PageTokenizer {
+    fn new(max_links: usize) -> PageTokenizer {
+        PageTokenizer {
+            tokenizer: Tokenizer::new(
+                LinkSink {
+                    links: Vec::new(),
+                    anchors: Vec::new(),
+                    max_links,
+                    text: SimHasher::default(),
+                    hidden: None,
+                },
+                TokenizerOpts::default(),
+            ),
+            queue: BufferQueue::new(),
+            partial: Vec::new(),
+        }
+    }
+
+    fn feed(&mut self, bytes: &[u8]) {
+        self.partial.extend_from_slice(bytes);
+
+        let mut text = String::new();
+        let mut rest: &[u8] = &self.partial;
+        while !rest.is_empty() {
+            match std::str::from_utf8(rest) {
+                Ok(valid) => {
+                    text.push_str(valid);
+                    rest = &[];
+                }
+                Err(error) => {
+                    let (valid, after) = rest.split_at(error.valid_up_to());
+                    text.push_str(&String::from_utf8_lossy(valid));
+                    if let Some(invalid_length) = error.error_len() {
+                        text.push(char::REPLACEMENT_CHARACTER);
+                        rest = &after[invalid_length..];
+                    } else {
+                        //Incomplete sequence at the end, wait for the next chunk
+                        rest = after;
+                        break;
+                    }
+                }
+            }
+        }
+        self.partial = rest.to_vec();
+        self.push(&text);
+    }
+
+    fn links(&self) -> usize {
+        self.tokenizer.sink.links.len()
+    }
+
+    fn finish(mut self) -> Extracted {
+        let rest = String::from_utf8_lossy(&self.partial).into_owned();
+        self.push(&rest);
+        self.tokenizer.end();
+        Extracted {
+            links: self.tokenizer.sink.links,
+            anchors: self.tokenizer.sink.anchors,
+            simhash: self.tokenizer.sink.text.finish(),
+        }
+    }
+
+    fn push(&mut self, text: &str) {
+        if !text.is_empty() {
+            self.queue.push_back(StrTendril::from_slice(text));
+            let _ = self.tokenizer.feed(&mut self.queue);
+        }
+    }
+}
+
+//Everything taken from a whole body at once
+#[cfg(test)]
+pub fn extract(body: &[u8], max_links: usize) -> Extracted {
+    let mut tokenizer = PageTokenizer::new(max_links);
+    tokenizer.feed(body);
+    tokenizer.finish()
+}
+
+//Extracts links from a page as its body arrives so only the current chunk is held in memory
+//The tokenizer runs on a blocking thread and is sent the chunks, so the crawl holding the extractor stays Send
+pub struct LinkExtractor {
+    chunks: Sender<Vec<u8>>,
+    //The tokenizer answers each chunk with the number of links found so far
+    found: mpsc::Receiver<usize>,
+    links: usize,
+    max_links: usize,
+    tokenizer: JoinHandle<Extracted>,
+}
+
+impl LinkExtractor {
+    pub fn new(max_links: usize) -> LinkExtractor {
+        let (chunks, received) = channel::<Vec<u8>>();
+        let (answer, found) = mpsc::channel(1);
+        let tokenizer = spawn_blocking(move || {
+            let mut tokenizer = PageTokenizer::new(max_links);
+            for chunk in received {
+                tokenizer.feed(&chunk);
+                let _ = answer.blocking_send(tokenizer.links());
+            }
+            tokenizer.finish()
+        });
+        LinkExtractor {
+            chunks,
+            found,
+            links: 0,
+            max_links,
+            tokenizer,
+        }
+    }
+
+    //Waits for the chunk to be tokenized, so is_full is up to date and chunks never pile up unread
+    pub async fn feed(&mut self, bytes: &[u8]) {
+        if self.chunks.send(bytes.to_vec()).is_ok() {
+            if let Some(links) = self.found.recv().await {
+                self.links = links;
+            }
+        }
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.links >= self.max_links
+    }
+
+    pub async fn finish(self) -> Extracted {
+        //Closing the channel ends the tokenizer's loop
+        drop(self.chunks);
+        self.tokenizer
+            .await
+            .expect("The tokenizer thread panicked while extracting links")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{extract, Extracted, LinkExtractor, PageTokenizer};
+    use crate::fingerprint::SimHasher;
+
+    #[test]
+    fn test_split_chunks() {
+        let page = "<html><body><a href=\"/caf\u{e9}\">Caf\u{e9}</a><script>var s = '<a href=\"/script\">';</script><a class=\"x\" href='/last'>Last</a></body></html>";
+        let mut tokenizer = PageTokenizer::new(10);
+        //Feeding a byte at a time splits both tags and multi-byte characters across chunks
+        for byte in page.as_bytes() {
+            tokenizer.feed(&[*byte]);
+        }
+        assert_eq!(tokenizer.finish().links, vec!["/caf\u{e9}", "/last"]);
+    }
+
+    #[test]
+    fn test_anchors() {
+        assert_eq!(
+            extract(b"<h1 id=\"intro\"><a href=\"#end\">Skip</a></h1><script>var s = '<p id=\"fake\">';</script><a name=\"end\" href=\"/last\">Last</a><p name=\"para\"></p>", 10),
+            Extracted {
+                links: vec!["#end".to_string(), "/last".to_string()],
+                anchors: vec!["intro".to_string(), "end".to_string()],
+                simhash: {
+                    let mut text = SimHasher::default();
+                    text.feed("Skip Last");
+                    text.finish()
+                },
+            }
+        );
+    }
+
+    #[test]
+    fn test_link_extractor() {
+        let page =
+            "<p>Caf\u{e9} hours</p><a href=\"/1\">1</a><a href=\"/2\">2</a><a href=\"/3\">3</a>";
+        tokio::runtime::Builder::new_multi_thread()
+            .enable_all()
+            .build()
+            .unwrap()
+            .block_on(async {
+                //Chunks sent to the tokenizer thread give the same result as the whole body
+                let mut extractor = LinkExtractor::new(10);
+                for chunk in page.as_bytes().chunks(7) {
+                    extractor.feed(chunk).await;
+                }
+                assert_eq!(extractor.finish().await, extract(page.as_bytes(), 10));
+            });
+    }
+
+    #[test]
+    fn test_max_links() {
+        tokio::runtime::Builder::new_multi_thread()
+            .enable_all()
+            .build()
+            .unwrap()
+            .block_on(async {
+                let mut extractor = LinkExtractor::new(2);
+                extractor
+                    .feed(b"<a href=\"/1\"></a><a href=\"/2\"></a>")
+                    .await;
+                assert!(extractor.is_full());
+                extractor.feed(b"<a href=\"/3\"></a>").await;
+                assert_eq!(extractor.finish().await.links, vec!["/1", "/2"]);
+            });
+    }
+}
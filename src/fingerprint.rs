@@ -0,0 +1,122 @@
+use std::collections::VecDeque;
+
+use serde::{Deserialize, Serialize};
+
+//Words hashed together as one feature, so pages only look alike when their wording does
+const SHINGLE_WORDS: usize = 3;
+//SimHashes at most this many bits apart are counted as near-duplicates
+const NEAR_DUPLICATE_BITS: u32 = 3;
+
+//What a page's content looked like, the md5 of the body and a SimHash over its visible text
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+pub struct Fingerprint {
+    pub digest: String,
+    pub simhash: u64,
+}
+
+//Builds a SimHash from text as it arrives, words split across chunks are joined back up
+#[derive(Debug, Default)]
+pub struct SimHasher {
+    weights: Vec<i64>,
+    word: String,
+    shingle: VecDeque<String>,
+    shingles: usize,
+}
+
+impl SimHasher {
+    pub fn feed(&mut self, text: &str) {
+        for c in text.chars() {
+            if c.is_alphanumeric() {
+                self.word.extend(c.to_lowercase());
+            } else {
+                self.end_word();
+            }
+        }
+    }
+
+    pub fn finish(mut self) -> u64 {
+        self.end_word();
+        //Pages with fewer words than a shingle still get a hash of what they have
+        if self.shingles == 0 && !self.shingle.is_empty() {
+            self.add_shingle();
+        }
+        let mut simhash = 0;
+        for (bit, weight) in self.weights.iter().enumerate() {
+            if *weight > 0 {
+                simhash |= 1 << bit;
+            }
+        }
+        simhash
+    }
+
+    fn end_word(&mut self) {
+        if self.word.is_empty() {
+            return;
+        }
+        self.shingle.push_back(std::mem::take(&mut self.word));
+        if self.shingle.len() == SHINGLE_WORDS {
+            self.add_shingle();
+            self.shingle.pop_front();
+        }
+    }
+
+    //md5 is used over a std hasher so fingerprints stay comparable between builds
+    fn add_shingle(&mut self) {
+        let words: Vec<&str> = self.shingle.iter().map(|word| word.as_str()).collect();
+        let digest = md5::compute(words.join(" "));
+        let mut bytes = [0; 8];
+        bytes.copy_from_slice(&digest.0[..8]);
+        let hash = u64::from_le_bytes(bytes);
+        if self.weights.is_empty() {
+            self.weights = vec![0; 64];
+        }
+        for (bit, weight) in self.weights.iter_mut().enumerate() {
+            if hash & (1 << bit) != 0 {
+                *weight += 1;
+            } else {
+                *weight -= 1;
+            }
+        }
+        self.shingles += 1;
+    }
+}
+
+pub fn is_near_duplicate(first: u64, second: u64) -> bool {
+    (first ^ second).count_ones() <= NEAR_DUPLICATE_BITS
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{is_near_duplicate, SimHasher};
+
+    fn simhash(chunks: &[&str]) -> u64 {
+        let mut hasher = SimHasher::default();
+        for chunk in chunks {
+            hasher.feed(chunk);
+        }
+        hasher.finish()
+    }
+
+    #[test]
+    fn test_simhash() {
+        let text =
+            "Opening hours for the main library are nine until five on weekdays and ten until \
+                    four on weekends, the reading room closes an hour earlier on public holidays";
+        let whole = simhash(&[text]);
+        //Splitting words between chunks and changing case or punctuation doesn't change the hash
+        assert_eq!(whole, simhash(&[&text[..23], &text[23..]]));
+        assert_eq!(whole, simhash(&[&text.to_uppercase().replace(',', ";")]));
+
+        let edited = text.replace("holidays", "holiday");
+        assert_ne!(whole, simhash(&[&edited]));
+        assert!(is_near_duplicate(whole, simhash(&[&edited])));
+        assert!(!is_near_duplicate(
+            whole,
+            simhash(&[
+                "Parking is free for visitors after six in the evening and all day on Sunday"
+            ])
+        ));
+        assert_eq!(simhash(&[""]), 0);
+        assert_ne!(simhash(&["Hello world"]), 0);
+    }
+}
@@ -0,0 +1,156 @@
+use std::fmt;
+use std::time::Duration;
+
+use tokio::sync::watch;
+use tokio::time::{sleep_until, Instant};
+
+use crate::options::CrawlOptions;
+
+//How much running jobs print to the daemon's output, each level includes the ones before it
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Clone, Copy, Default)]
+pub enum LogLevel {
+    //Pages which couldn't be crawled
+    Error,
+    //Retries, cut off bodies and suspected crawl traps
+    Warn,
+    //Every page as it's crawled
+    #[default]
+    Info,
+    //Waits for the rate limit
+    Debug,
+}
+
+impl LogLevel {
+    pub fn parse(value: &str) -> Result<LogLevel, String> {
+        match value {
+            "error" => Ok(LogLevel::Error),
+            "warn" => Ok(LogLevel::Warn),
+            "info" => Ok(LogLevel::Info),
+            "debug" => Ok(LogLevel::Debug),
+            _ => Err(format!(
+                "log-level expects error, warn, info or debug but was given {}",
+                value
+            )),
+        }
+    }
+}
+
+impl fmt::Display for LogLevel {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            LogLevel::Error => "error",
+            LogLevel::Warn => "warn",
+            LogLevel::Info => "info",
+            LogLevel::Debug => "debug",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+//The daemon settings a reload changes for jobs which are already running, sent to every job over a watch
+//channel so each page reads the newest ones
+#[derive(Debug, PartialEq, Clone, Copy, Default)]
+pub struct LiveSettings {
+    pub log_level: LogLevel,
+    //Most pages a second a job without its own --rate-limit fetches, None for no limit
+    pub rate_limit: Option<f64>,
+}
+
+pub type Live = watch::Receiver<LiveSettings>;
+
+//The default settings for crawls run without a daemon to change them
+#[cfg(test)]
+pub fn fixed() -> Live {
+    watch::channel(LiveSettings::default()).1
+}
+
+//Keeps a job to its rate limit, reading the newest settings before every page
+pub struct Pacer {
+    live: Live,
+    //When the job last fetched a page
+    last: Option<Instant>,
+}
+
+impl Pacer {
+    pub fn new(live: Live) -> Pacer {
+        Pacer { live, last: None }
+    }
+
+    pub fn settings(&self) -> LiveSettings {
+        *self.live.borrow()
+    }
+
+    //Waits until the job may fetch its next page, a change to the rate limit while waiting takes effect
+    //straight away
+    pub async fn wait(&mut self, options: &CrawlOptions) {
+        loop {
+            let settings = *self.live.borrow_and_update();
+            let ready = match (options.rate_limit.or(settings.rate_limit), self.last) {
+                (Some(rate), Some(last)) => last + Duration::from_secs_f64(1.0 / rate),
+                _ => break,
+            };
+            if ready <= Instant::now() {
+                break;
+            }
+            if settings.log_level >= LogLevel::Debug {
+                println!(
+                    "Waiting {:.1}s for the rate limit",
+                    (ready - Instant::now()).as_secs_f64()
+                );
+            }
+            tokio::select! {
+                _ = sleep_until(ready) => break,
+                changed = self.live.changed() => {
+                    //Without a daemon the settings can't change again
+                    if changed.is_err() {
+                        sleep_until(ready).await;
+                        break;
+                    }
+                }
+            }
+        }
+        self.last = Some(Instant::now());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{LiveSettings, LogLevel, Pacer};
+    use crate::options::CrawlOptions;
+    use std::time::Duration;
+    use tokio::sync::watch;
+    use tokio::time::Instant;
+
+    #[test]
+    fn test_log_level() {
+        assert_eq!(LogLevel::parse("warn"), Ok(LogLevel::Warn));
+        assert_eq!(LogLevel::default().to_string(), "info");
+        assert!(LogLevel::Debug > LogLevel::Info && LogLevel::Warn > LogLevel::Error);
+        assert!(LogLevel::parse("verbose").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_pacer() {
+        let (sender, live) = watch::channel(LiveSettings {
+            rate_limit: Some(0.01),
+            ..LiveSettings::default()
+        });
+        let mut pacer = Pacer::new(live);
+        let options = CrawlOptions::default();
+        let started = Instant::now();
+        pacer.wait(&options).await;
+        assert!(started.elapsed() < Duration::from_secs(1));
+
+        //The second page would wait 100s until the limit is lifted
+        let waiting = tokio::spawn(async move {
+            pacer.wait(&options).await;
+        });
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(!waiting.is_finished());
+        sender.send_replace(LiveSettings::default());
+        tokio::time::timeout(Duration::from_secs(5), waiting)
+            .await
+            .expect("Lifting the limit should end the wait")
+            .unwrap();
+    }
+}
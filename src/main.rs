@@ -1,196 +1,454 @@
 use std::env;
-use std::fs::{read_dir, remove_file, File};
-use std::io::{self, BufRead, Write};
+use std::fs::{read_to_string, remove_file, write, File};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
 use std::process::exit;
 
 use url::Url;
 
 use nix::sys::signal::{kill, Signal};
-use nix::unistd::{getgid, getuid, Pid};
+use nix::unistd::{getgid, getuid};
+
+use clap::{CommandFactory, Parser};
 
 use daemonize::Daemonize;
 
 mod tree;
 
+mod auth;
+use auth::strip_userinfo;
+
+mod cli;
+use cli::{legacy_args, Cli, Command, CrawlFlags};
+
+mod client;
+
+mod config;
+use config::{load_config, Config};
+
+mod cookies;
+
+mod diff;
+use diff::diff_crawls;
+
+mod extract;
+
+mod fingerprint;
+
+mod live;
+
 mod node;
-use node::parse_url;
+
+mod notify;
+
+mod options;
+
+mod queue;
+
+mod redirect;
+
+mod report;
+
+mod retry;
+
+mod schedule;
+
+mod sitemap;
+use sitemap::decode_files;
+
+mod snapshot;
+use snapshot::load_snapshot;
+
+mod state;
+
+mod trap;
+
+#[cfg(test)]
+mod test_server;
 
 mod daemon;
-use daemon::{check_daemon, daemon_server, message_daemon};
+use daemon::{check_daemon, daemon_pid, daemon_server, message_daemon, read_reply};
 
-const PID_PATH: &str = "/tmp/crawl.pid";
-const OUT_PATH: &str = "/tmp/crawl.out";
-const ERR_PATH: &str = "/tmp/crawl.err";
-const STREAM_PATH: &str = "/tmp/crawl.stream";
+//Exit codes for a request the daemon couldn't carry out, for usage and config errors and for a daemon which
+//isn't running or can't be reached
+const EXIT_FAILED: i32 = 1;
+const EXIT_USAGE: i32 = 2;
+const EXIT_NO_DAEMON: i32 = 3;
+
+//Why a command failed, which decides the exit code
+#[derive(Debug)]
+enum Failure {
+    Request(String),
+    Daemon(String),
+}
+
+impl From<String> for Failure {
+    fn from(message: String) -> Self {
+        Failure::Request(message)
+    }
+}
 
 fn main() {
-    let args: Vec<String> = env::args().collect();
-    if let Some(shell_option) = args.get(1) {
-        match shell_option.as_str() {
-            "-start" => {
-                if let Some(arg) = args.get(2) {
-                    let url = parse_url(arg).expect("No valid URL was given");
-                    start_option(url);
-                } else {
-                    println!("Starting daemon");
-                    start_crawl();
-                };
-            }
-            "-stop" => {
-                let url = parse_url(
-                    args.get(2)
-                        .expect("Could not find argument in position 2 of the input"),
-                )
-                .expect("No valid URL was given");
-                stop_option(url)
-            }
-            "-list" => list_option(),
-            "-clear" => {
-                if !check_daemon() {
-                    clear_option()
-                } else {
-                    eprintln!("The daemon is currently active, clearing the files without killing the server will cause unintended errors");
-                }
-            }
-            "-kill" => kill_option(),
-            "-print" => print_option(),
-            "-h" => help_option(),
-            _ => {
-                println!("No recognized option was given\n");
-                help_option();
-
-                exit(-1);
+    let cli = Cli::parse_from(legacy_args(env::args()));
+    let result = match cli.command {
+        //These never reach the daemon or its files, so they work whatever state the config is in
+        Command::Diff { old, new, json } => diff_option(&old, &new, json).map_err(Failure::from),
+        Command::Completions { shell } => {
+            clap_complete::generate(shell, &mut Cli::command(), "crawl", &mut io::stdout());
+            Ok(())
+        }
+        Command::Man { output } => man_option(output.as_deref()).map_err(Failure::from),
+        command => match load_config(cli.config.as_deref()) {
+            Ok(config) => run_command(command, config, cli.config),
+            Err(e) => {
+                eprintln!("{}", e);
+                exit(EXIT_USAGE);
             }
+        },
+    };
+    match result {
+        Ok(()) => {}
+        Err(Failure::Request(message)) => {
+            eprintln!("{}", message);
+            exit(EXIT_FAILED);
         }
-    } else {
-        println!("No valid option was specified");
+        Err(Failure::Daemon(message)) => {
+            eprintln!("{}", message);
+            exit(EXIT_NO_DAEMON);
+        }
+    }
+}
+
+//The commands which use the config, a job started with --config gets that file's crawl defaults rather than
+//the ones the daemon was started with
+fn run_command(
+    command: Command,
+    config: Config,
+    config_path: Option<PathBuf>,
+) -> Result<(), Failure> {
+    let defaults = match config_path {
+        Some(_) => config.client_lines(),
+        None => Vec::new(),
     };
+    match command {
+        Command::Start {
+            url: Some(url),
+            force,
+            mut flags,
+        } => {
+            flags.lines.splice(0..0, defaults);
+            start_option(&config, url, force, flags)
+        }
+        Command::Start { url: None, .. } => {
+            println!("Starting daemon");
+            start_crawl(config, config_path)
+        }
+        Command::Stop { url } => site_option(&config, "stop", Some(url)),
+        Command::Schedule {
+            url,
+            every,
+            cron,
+            keep_runs,
+            keep_for,
+            flags,
+        } => {
+            let mut lines = Vec::new();
+            lines.extend(every.map(|every| format!("every={}", every)));
+            lines.extend(cron.map(|cron| format!("cron={}", cron)));
+            lines.extend(keep_runs.map(|keep_runs| format!("keep-runs={}", keep_runs)));
+            lines.extend(keep_for.map(|keep_for| format!("keep-for={}", keep_for)));
+            lines.extend(defaults);
+            lines.extend(flags.lines);
+            schedule_option(&config, url, lines)
+        }
+        Command::Unschedule { url } => site_option(&config, "unschedule", Some(url)),
+        Command::Forget { url, result } => {
+            let argument = site_argument(url, result);
+            ask_daemon(&config, "forget", Some(argument)).map(|reply| println!("{}", reply))
+        }
+        //Without a url the daemon shows every running and queued job
+        Command::Status { url } => site_option(&config, "status", url),
+        Command::MaxJobs { jobs } => ask_daemon(&config, "max-jobs", Some(jobs.to_string()))
+            .map(|reply| println!("{}", reply)),
+        Command::Reload => ask_daemon(&config, "reload", None).map(|reply| println!("{}", reply)),
+        Command::Recrawl { url } => site_option(&config, "recrawl", Some(url)),
+        Command::Sitemap {
+            url,
+            lastmod,
+            base_url,
+            output,
+        } => sitemap_option(&config, url, lastmod, base_url, &output),
+        Command::Save { url, file } => save_option(&config, url, &file),
+        Command::List => list_option(&config),
+        Command::Show { url, result } => show_option(&config, url, result),
+        Command::Print {
+            url,
+            result,
+            output,
+        } => print_option(&config, url, result, &output),
+        Command::Clear => {
+            if !check_daemon(&config) {
+                clear_option(&config);
+                Ok(())
+            } else {
+                Err(Failure::Request("The daemon is currently active, clearing the files without killing the server will cause unintended errors".to_string()))
+            }
+        }
+        Command::Kill => kill_option(&config),
+        Command::Diff { .. } | Command::Completions { .. } | Command::Man { .. } => {
+            unreachable!("main runs the commands which don't need the config")
+        }
+    }
 }
 
-fn stop_option(url: Url) {
-    if check_daemon() {
-        let byte_response = message_daemon("stop".to_string(), Some(url.to_string()));
-        println!("{}", String::from_utf8_lossy(&byte_response));
-    } else {
-        eprintln!("The daemon hasn't been started yet. Please start it")
+//Sends a command to the daemon, a reply explaining why it couldn't be carried out is given back as a failure
+fn ask_daemon(config: &Config, command: &str, argument: Option<String>) -> Result<String, Failure> {
+    if !check_daemon(config) {
+        return Err(Failure::Daemon(
+            "The daemon hasn't been started yet. Please start it".to_string(),
+        ));
+    }
+    let byte_response =
+        message_daemon(config, command.to_string(), argument).map_err(Failure::Daemon)?;
+    read_reply(&String::from_utf8_lossy(&byte_response)).map_err(Failure::Request)
+}
+
+//The url followed by result=n when an older result is wanted
+fn site_argument(mut url: Url, result: Option<usize>) -> String {
+    strip_userinfo(&mut url);
+    match result {
+        Some(result) => format!("{}\nresult={}", url, result),
+        None => url.to_string(),
+    }
+}
+
+fn start_option(
+    config: &Config,
+    mut url: Url,
+    force: bool,
+    flags: CrawlFlags,
+) -> Result<(), Failure> {
+    let mut options = flags.lines;
+    //Credentials in the url are sent as an option so they're never shown with it
+    if let Some(credential) = strip_userinfo(&mut url) {
+        options.push(credential.to_option_line());
+    }
+    if force {
+        options.push("force=true".to_string());
+    }
+    //Options are sent on the lines following the url
+    let mut argument = url.to_string();
+    for option in options {
+        argument = argument + "\n" + &option;
+    }
+    let reply = ask_daemon(config, "start", Some(argument))?;
+    println!("{}", reply);
+    Ok(())
+}
+
+//Has the daemon crawl a url by itself every so often, the schedule lines come first and the rest are crawl options
+fn schedule_option(config: &Config, mut url: Url, mut lines: Vec<String>) -> Result<(), Failure> {
+    if let Some(credential) = strip_userinfo(&mut url) {
+        lines.push(credential.to_option_line());
+    }
+    let mut argument = url.to_string();
+    for line in lines {
+        argument = argument + "\n" + &line;
+    }
+    let reply = ask_daemon(config, "schedule", Some(argument))?;
+    println!("{}", reply);
+    Ok(())
+}
+
+//Commands which only name a site, such as status
+fn site_option(config: &Config, command: &str, url: Option<Url>) -> Result<(), Failure> {
+    let reply = ask_daemon(config, command, url.map(|url| site_argument(url, None)))?;
+    println!("{}", reply);
+    Ok(())
+}
+
+//Writes the sitemap files for a finished crawl into the output directory
+fn sitemap_option(
+    config: &Config,
+    url: Url,
+    lastmod: bool,
+    base_url: Option<Url>,
+    output: &Path,
+) -> Result<(), Failure> {
+    let mut argument = site_argument(url, None);
+    if lastmod {
+        argument += "\nlastmod=true";
+    }
+    if let Some(base) = base_url {
+        argument = argument + "\nbase-url=" + base.as_str();
+    }
+
+    let response = ask_daemon(config, "sitemap", Some(argument))?;
+    let files = decode_files(&response).ok_or_else(|| response.to_string())?;
+    for (name, contents) in files.iter() {
+        //Only plain file names are written so a reply can't reach outside the output directory
+        if name.contains('/') || name.starts_with('.') {
+            return Err(format!("Refusing to write the sitemap file {}", name).into());
+        }
+        let path = output.join(name);
+        let mut file = File::create(&path)
+            .map_err(|e| format!("Couldn't create {}: {}", path.display(), e))?;
+        file.write_all(contents.as_bytes())
+            .map_err(|e| format!("Couldn't write {}: {}", path.display(), e))?;
+        println!("Wrote {}", path.display());
     }
+    Ok(())
+}
+
+//Saves the tree of a finished crawl so later crawls can be compared with it
+fn save_option(config: &Config, url: Url, path: &Path) -> Result<(), Failure> {
+    let response = ask_daemon(config, "snapshot", Some(site_argument(url, None)))?;
+    //Anything which isn't a snapshot is the daemon explaining why it couldn't send one
+    load_snapshot(&response).map_err(|_| response.to_string())?;
+    write(path, response.as_bytes())
+        .map_err(|e| format!("Couldn't write {}: {}", path.display(), e))?;
+    println!("Wrote {}", path.display());
+    Ok(())
 }
 
-fn start_option(url: Url) {
-    if check_daemon() {
-        let byte_response = message_daemon("start".to_string(), Some(url.to_string()));
-        println!("{}", String::from_utf8_lossy(&byte_response));
+//Compares two saved crawls of a site, printing json instead of text with --json
+fn diff_option(old: &Path, new: &Path, json: bool) -> Result<(), String> {
+    let load = |path: &Path| {
+        let snapshot =
+            read_to_string(path).map_err(|e| format!("Couldn't read {}: {}", path.display(), e))?;
+        load_snapshot(&snapshot)
+            .map_err(|e| format!("{} isn't a saved crawl: {}", path.display(), e))
+    };
+    let diff = diff_crawls(&load(old)?, &load(new)?);
+    if json {
+        let json = serde_json::to_string_pretty(&diff).map_err(|e| e.to_string())?;
+        println!("{}", json);
     } else {
-        eprintln!("The daemon hasn't been started yet. Please start it")
+        print!("{}", diff);
     }
+    Ok(())
 }
 
-fn start_crawl() {
-    if check_daemon() {
+//Prints the man page, or writes one for crawl and each of its commands into the directory
+fn man_option(output: Option<&Path>) -> Result<(), String> {
+    match output {
+        Some(dir) => {
+            clap_mangen::generate_to(Cli::command(), dir)
+                .map_err(|e| format!("Couldn't write the man pages to {}: {}", dir.display(), e))?;
+            println!("Wrote the man pages to {}", dir.display());
+        }
+        None => clap_mangen::Man::new(Cli::command())
+            .render(&mut io::stdout())
+            .map_err(|e| format!("Couldn't print the man page: {}", e))?,
+    }
+    Ok(())
+}
+
+//The daemon reads the config files again when it's reloaded, so the path given with --config is kept for it
+fn start_crawl(config: Config, config_path: Option<PathBuf>) -> Result<(), Failure> {
+    if check_daemon(&config) {
         println!("Daemon already running");
     } else {
-        let stdout = File::create(OUT_PATH).expect("Couldn't create output file");
+        //The daemon runs from /tmp so a relative path wouldn't be found again
+        let config_path = config_path.map(|path| path.canonicalize().unwrap_or(path));
+        let create = |path: &Path| {
+            File::create(path).map_err(|e| format!("Couldn't create {}: {}", path.display(), e))
+        };
+        let stdout = create(&config.out_path)?;
 
-        let stderr = File::create(ERR_PATH).expect("Couldn't create error file");
+        let stderr = create(&config.err_path)?;
 
         let daemonize = Daemonize::new()
-            .pid_file(PID_PATH)
+            .pid_file(&config.pid_path)
             .chown_pid_file(true)
             .working_directory("/tmp")
             .umask(0o027) // Set umask, `0o027` by default. This is inverded to chmod in that 0o027 = -rw-r----
             .user(getuid().as_raw())
             .group(getgid().as_raw())
-            .stdout(stdout) // Redirect stdout to `/tmp/daemon.out`.
-            .stderr(stderr); // Redirect stderr to `/tmp/daemon.err`.
+            .stdout(stdout) // Redirect stdout to the configured output file, `/tmp/crawl.out` by default.
+            .stderr(stderr); // Redirect stderr to the configured error file, `/tmp/crawl.err` by default.
 
         match daemonize.start() {
             Ok(_) => {
                 println!("Success, daemonized");
-                daemon_server();
+                daemon_server(config, config_path);
             }
             Err(e) => {
-                eprintln!("Error, process already running: {}", e);
-                exit(-1);
+                return Err(Failure::Request(format!(
+                    "Error, process already running: {}",
+                    e
+                )))
             }
         }
     }
+    Ok(())
 }
 
-fn kill_option() {
+fn kill_option(config: &Config) -> Result<(), Failure> {
+    let main_task = daemon_pid(config).ok_or_else(|| {
+        Failure::Daemon("Can't read the pid file, it's likely the program hasn't been started or was stopped already".to_string())
+    })?;
     println!("Killing the daemon process and children");
-    let file = File::open(PID_PATH).expect("Cant open the pid file, it's likely the program hasn't been started or was stopped already");
-
-    let reader = io::BufReader::new(file);
-
-    let first_line = reader
-        .lines()
-        .next()
-        .expect("Their is nothing left to iterate over")
-        .expect("No String was able to be returned in the Result");
-
-    let main_task = Pid::from_raw(
-        first_line
-            .parse::<i32>()
-            .expect("Parse Error on PID resource"),
-    );
 
     if kill(main_task, Signal::SIGINT).is_err() {
-        clear_option();
-        eprint!("SIGINT signal can't be sent");
+        clear_option(config);
+        return Err(Failure::Daemon("SIGINT signal can't be sent".to_string()));
     }
+    Ok(())
 }
 
-fn list_option() {
-    if check_daemon() {
-        println!("Listing all scraped sites:");
-        let byte_response = message_daemon("list".to_string(), None);
-        println!("{}", String::from_utf8_lossy(&byte_response));
-    } else {
-        eprintln!("The daemon hasn't been started yet. Please start it");
-    }
+fn list_option(config: &Config) -> Result<(), Failure> {
+    let reply = ask_daemon(config, "list", None)?;
+    println!("Listing all scraped sites:");
+    println!("{}", reply);
+    Ok(())
 }
 
-fn print_option() {
-    if check_daemon() {
-        println!("Printing all scraped sites to output.txt");
-        let byte_response = message_daemon("list".to_string(), None);
-        let mut file = File::create("output.txt").expect("Couldn't create or open output.txt file");
+//Prints the tree and report of one site's newest or numbered result, or of every site without a url
+fn show_option(config: &Config, url: Option<Url>, result: Option<usize>) -> Result<(), Failure> {
+    let argument = url.map(|url| site_argument(url, result));
+    let reply = ask_daemon(config, "show", argument)?;
+    println!("{}", reply);
+    Ok(())
+}
 
+//Writes what show would print to the given path
+fn print_option(
+    config: &Config,
+    url: Option<Url>,
+    result: Option<usize>,
+    path: &Path,
+) -> Result<(), Failure> {
+    let all = url.is_none();
+    let argument = url.map(|url| site_argument(url, result));
+    let response = ask_daemon(config, "show", argument)?;
+    let mut file =
+        File::create(path).map_err(|e| format!("Couldn't create {}: {}", path.display(), e))?;
+    if all {
         file.write_all("Site list trees:".as_bytes())
-            .expect("Couldn't write the given response");
-        file.write_all(&byte_response)
-            .expect("Couldn't write the given response");
-    } else {
-        eprintln!("The daemon hasn't been started yet. Please start it");
-    }
-}
-
-fn clear_option() {
-    if let Ok(entries) = read_dir("/tmp/") {
-        for entry in entries.flatten() {
-            if let Some(file_name) = entry.file_name().to_str() {
-                if file_name.contains("crawl") {
-                    let file_path = entry.path();
-                    if let Err(err) = remove_file(&file_path) {
-                        eprintln!("Failed to remove file {:?}: {}", file_path, err);
-                    } else {
-                        println!("File {:?} removed", file_path);
-                    }
-                }
-            }
-        }
-    } else {
-        eprintln!("Failed to read directory.");
+            .map_err(|e| format!("Couldn't write {}: {}", path.display(), e))?;
     }
+    file.write_all(response.as_bytes())
+        .map_err(|e| format!("Couldn't write {}: {}", path.display(), e))?;
+    println!("Wrote {}", path.display());
+    Ok(())
 }
 
-fn help_option() {
-    println!("The application consists of a command line client and a local service (daemon) which performs the actual web crawling. The communication between client and server uses a form of IPC mechanism. For each URL, the Web Crawler creates a tree of links with the root of the tree being the root URL.\n");
-    println!("The commands for the application are as follows");
-    println!("-start url\n  This starts the application and tasks the daemon with scraping a url if both that url and daemon exist");
-    println!("-stop url\n   This stops the url from being scraped");
-    println!("-list\n   This lists all scraped urls to the terminal");
-    println!("-clear\n  This clears all files related to the daemon");
-    println!("-kill\n   This kills the daemon and then clears all files related to the daemon");
-    println!("-print\n  This prints out the scraped urls to output.txt")
+//Removes the daemon's pid file, socket and output files
+fn clear_option(config: &Config) {
+    for file_path in [
+        &config.pid_path,
+        &config.stream_path,
+        &config.out_path,
+        &config.err_path,
+    ] {
+        if !file_path.exists() {
+            continue;
+        }
+        if let Err(err) = remove_file(file_path) {
+            eprintln!("Failed to remove file {:?}: {}", file_path, err);
+        } else {
+            println!("File {:?} removed", file_path);
+        }
+    }
 }
@@ -1,16 +1,28 @@
-use std::collections::{HashSet, VecDeque};
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::error::Error;
 
-use crate::tree::{SiteTree, SubSites};
+use crate::auth::form_login;
+use crate::extract::LinkExtractor;
+use crate::fingerprint::Fingerprint;
+use crate::live::{Live, LogLevel, Pacer};
+use crate::options::CrawlOptions;
+use crate::redirect::{is_unfollowed, send_following};
+use crate::report::{
+    broken_anchors, duplicate_pages, external_domains, non_http_links, recrawl_report,
+    redirect_chains, CrawlReport, SiteCrawl,
+};
+use crate::retry::StatusError;
+use crate::sitemap::{discover_sitemaps, page_key, read_sitemaps, sitemap_report};
+use crate::trap::TrapDetector;
+use crate::tree::{LinkKind, PageInfo, SiteTree, SubSites};
 
-use reqwest::{header, Client};
+use reqwest::{header, Client, Method, StatusCode};
 
-use select::document::Document;
-use select::predicate::Name;
+use tokio::time::{sleep, timeout};
 
 use url::{ParseError, Url};
 
-pub fn parse_url(shell_arg: &String) -> Result<Url, ParseError> {
+pub fn parse_url(shell_arg: &str) -> Result<Url, ParseError> {
     if let Ok(url) = Url::parse(shell_arg) {
         Ok(url)
     } else if let Ok(url) = Url::parse(&("http://".to_string() + shell_arg)) {
@@ -22,13 +34,792 @@ pub fn parse_url(shell_arg: &String) -> Result<Url, ParseError> {
     }
 }
 
+//Every link is compared against the root of a crawl, so it has to be an http or https url with a host
+pub fn parse_root(shell_arg: &str) -> Result<Url, String> {
+    let url = parse_url(shell_arg).map_err(|_| format!("{} isn't a valid url", shell_arg))?;
+    match url.host_str() {
+        Some(host) if LinkKind::of(&url, host) == LinkKind::Internal => Ok(url),
+        _ => Err(format!(
+            "{} isn't an http or https url with a host",
+            shell_arg
+        )),
+    }
+}
+
+//Number of body bytes inspected when a response is missing its Content-Type header
+const SNIFF_LENGTH: usize = 512;
+
+//Tag openings which identify a body as HTML, taken from the WHATWG mime sniffing standard
+const HTML_SIGNATURES: [&[u8]; 17] = [
+    b"<!doctype html",
+    b"<html",
+    b"<head",
+    b"<script",
+    b"<iframe",
+    b"<h1",
+    b"<div",
+    b"<font",
+    b"<table",
+    b"<a",
+    b"<style",
+    b"<title",
+    b"<b",
+    b"<body",
+    b"<br",
+    b"<p",
+    b"<!--",
+];
+
+pub fn sniff_html(body: &[u8]) -> bool {
+    let body = body.strip_prefix(b"\xEF\xBB\xBF").unwrap_or(body);
+    let start = body
+        .iter()
+        .position(|byte| !byte.is_ascii_whitespace())
+        .unwrap_or(body.len());
+    let body = &body[start..];
+
+    HTML_SIGNATURES.iter().any(|signature| {
+        body.len() > signature.len()
+            && body[..signature.len()].eq_ignore_ascii_case(signature)
+            && (body[signature.len()] == b' ' || body[signature.len()] == b'>')
+    })
+}
+
+//Fetches the page with a single GET and only reads the body if it's HTML
+//Headers are checked first so non-HTML bodies are never downloaded, dropping the response aborts the transfer
+//When the Content-Type header is missing the start of the body is sniffed instead
+//The body is tokenized as it arrives so a poisoned response can't be held in memory, it's cut off at the size limit
+//Unless this is the last attempt a status the job retries on is returned as a StatusError instead of being parsed
+//The status, Last-Modified header and whether the body was HTML are recorded on the page
+//Redirects are only followed to urls follow allows, one it doesn't is where the page ends and nothing is read
+pub async fn fetch_links(
+    client: &Client,
+    url: &Url,
+    options: &CrawlOptions,
+    follow: &(dyn Fn(&Url) -> bool + Sync),
+    last_attempt: bool,
+    page: &mut PageInfo,
+    level: LogLevel,
+) -> Result<Option<Vec<String>>, Box<dyn Error>> {
+    //Pages read in full by an earlier crawl are only sent again if they changed since,
+    //a page only partly read on an earlier attempt is always fetched whole
+    let mut conditions = header::HeaderMap::new();
+    if page.fingerprint.is_some() {
+        if let Some(etag) = page.etag.as_ref().and_then(|etag| etag.parse().ok()) {
+            conditions.insert(header::IF_NONE_MATCH, etag);
+        }
+        if let Some(last_modified) = page
+            .last_modified
+            .as_ref()
+            .and_then(|last_modified| last_modified.parse().ok())
+        {
+            conditions.insert(header::IF_MODIFIED_SINCE, last_modified);
+        }
+    }
+    page.not_modified = false;
+    let sent = send_following(
+        client,
+        Method::GET,
+        url,
+        &conditions,
+        options,
+        follow,
+        &mut page.redirects,
+    )
+    .await;
+    //Without a response this time the status from an earlier crawl no longer holds
+    let mut response = sent.inspect_err(|_| page.status = None)?;
+    //Everything found the last time the page was read still holds
+    if response.status() == StatusCode::NOT_MODIFIED && !conditions.is_empty() {
+        page.not_modified = true;
+        return Ok(None);
+    }
+
+    let status = response.status().as_u16();
+    page.status = Some(status);
+    page.html = false;
+    page.anchors = None;
+    page.fingerprint = None;
+    let header_value = |name| {
+        response
+            .headers()
+            .get(name)
+            .and_then(|value| value.to_str().ok())
+            .map(|value| value.to_string())
+    };
+    page.etag = header_value(header::ETAG);
+    page.last_modified = header_value(header::LAST_MODIFIED);
+    if is_unfollowed(&response) {
+        return Ok(None);
+    }
+    if !last_attempt && options.retry.statuses.contains(&status) {
+        return Err(Box::new(StatusError(status)));
+    }
+
+    let content_type = response
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .map(|value| value.to_lowercase());
+
+    //The bytes read to sniff a body without a Content-Type are the start of the page
+    let sniffed: Vec<u8> = match content_type {
+        Some(content_type) if !content_type.starts_with("text/html") => return Ok(None),
+        Some(_) => Vec::new(),
+        None => {
+            let mut sniff_buffer: Vec<u8> = Vec::new();
+            while sniff_buffer.len() < SNIFF_LENGTH {
+                if let Some(chunk) = timeout(options.read_timeout, response.chunk()).await?? {
+                    sniff_buffer.extend_from_slice(&chunk);
+                } else {
+                    break;
+                }
+            }
+            if !sniff_html(&sniff_buffer) {
+                return Ok(None);
+            }
+            sniff_buffer
+        }
+    };
+    page.html = true;
+
+    //The extractor starts a tokenizer thread so it's only made once the page is known to be html
+    let mut extractor = LinkExtractor::new(options.max_links);
+    let mut digest = md5::Context::new();
+    let mut body_size = sniffed.len().min(options.max_body_size);
+    if body_size > 0 {
+        extractor.feed(&sniffed[..body_size]).await;
+        digest.consume(&sniffed[..body_size]);
+    }
+
+    let mut complete = false;
+    while body_size < options.max_body_size && !extractor.is_full() {
+        if let Some(chunk) = timeout(options.read_timeout, response.chunk()).await?? {
+            let remaining = options.max_body_size - body_size;
+            let chunk = &chunk[..chunk.len().min(remaining)];
+            body_size += chunk.len();
+            extractor.feed(chunk).await;
+            digest.consume(chunk);
+        } else {
+            complete = true;
+            break;
+        }
+    }
+    if body_size >= options.max_body_size && level >= LogLevel::Warn {
+        eprintln!(
+            "The body of {} exceeded {} bytes and was cut off",
+            url, options.max_body_size
+        );
+    }
+    let extracted = extractor.finish().await;
+    //Anchors from part of a page can't show a fragment is missing so they're only kept for whole pages
+    if complete {
+        page.anchors = Some(extracted.anchors.into_iter().collect());
+        page.fingerprint = Some(Fingerprint {
+            digest: format!("{:x}", digest.compute()),
+            simhash: extracted.simhash,
+        });
+    }
+    Ok(Some(extracted.links))
+}
+
+//Fetches a page, waiting out the job's backoff and trying again whenever its retry policy allows
+//Each retry is counted on the page
+pub async fn fetch_with_retries(
+    client: &Client,
+    url: &Url,
+    options: &CrawlOptions,
+    follow: &(dyn Fn(&Url) -> bool + Sync),
+    page: &mut PageInfo,
+    level: LogLevel,
+) -> Result<Option<Vec<String>>, Box<dyn Error>> {
+    loop {
+        let last_attempt = page.retries + 1 >= options.retry.max_attempts;
+        let error = match fetch_links(client, url, options, follow, last_attempt, page, level).await
+        {
+            Err(error) if !last_attempt && options.retry.should_retry(&*error) => error.to_string(),
+            result => return result,
+        };
+        page.retries += 1;
+        let delay = options.retry.backoff(page.retries);
+        if level >= LogLevel::Warn {
+            eprintln!(
+                "Retrying {} in {:.1}s after attempt {} failed: {}",
+                url,
+                delay.as_secs_f64(),
+                page.retries,
+                error
+            );
+        }
+        sleep(delay).await;
+    }
+}
+
+//Fetches a url with a single GET, giving back the body when the response is a success
+//Bodies larger than max_size are an error since a partial file can't be trusted
+pub async fn fetch_body(
+    client: &Client,
+    url: &Url,
+    options: &CrawlOptions,
+    max_size: usize,
+) -> Result<Option<Vec<u8>>, Box<dyn Error>> {
+    let mut response = send_following(
+        client,
+        Method::GET,
+        url,
+        &header::HeaderMap::new(),
+        options,
+        &|_| true,
+        &mut Vec::new(),
+    )
+    .await?;
+    if !response.status().is_success() {
+        return Ok(None);
+    }
+    let mut body: Vec<u8> = Vec::new();
+    while let Some(chunk) = timeout(options.read_timeout, response.chunk()).await?? {
+        if body.len() + chunk.len() > max_size {
+            return Err(format!("The body exceeded {} bytes", max_size).into());
+        }
+        body.extend_from_slice(&chunk);
+    }
+    Ok(Some(body))
+}
+
+//The daemon's live settings are read before every page, so a reload reaches the job while it runs
+pub async fn crawl_site(root: Url, client: Client, options: CrawlOptions, live: Live) -> SiteCrawl {
+    let crawl = SiteCrawl {
+        tree: SiteTree::new(root),
+        report: CrawlReport::default(),
+        options,
+    };
+    crawl_tree(crawl, client, live).await
+}
+
+//Crawls a finished job again with its options, pages read in full last time are requested with their ETag
+//and Last-Modified and only read again when they changed, so the tree is updated in place
+pub async fn recrawl_site(mut crawl: SiteCrawl, client: Client, live: Live) -> SiteCrawl {
+    crawl
+        .tree
+        .visit_mut(&mut |node| node.page.not_modified = false);
+    let mut crawl = crawl_tree(crawl, client, live).await;
+    crawl.report.recrawl = Some(recrawl_report(&crawl.tree));
+    crawl
+}
+
+//What a crawl has come across so far, kept from the pass over the links to the pass over the sitemap's pages
+#[derive(Default)]
+pub struct Visited {
+    //Pages fetched, by page_key
+    pub site_set: HashSet<String>,
+    //Digests of the bodies read, for skip-duplicates
+    pub contents: HashSet<String>,
+    pub traps: TrapDetector,
+}
+
+async fn crawl_tree(mut crawl: SiteCrawl, client: Client, live: Live) -> SiteCrawl {
+    let root = crawl.tree.current_site.clone();
+    let options = crawl.options.clone();
+    crawl.report = CrawlReport::default();
+    if let Some(login_url) = &options.login_url {
+        if let Err(e) = form_login(&client, login_url, &options).await {
+            eprintln!("Couldn't log in before crawling {}: {}", root, e);
+            return crawl;
+        }
+    }
+    let domain = root
+        .host_str()
+        .expect("The host was unable to be extracted from the url");
+    let mut visited = Visited::default();
+    let mut pacer = Pacer::new(live);
+    let mut job_queue: VecDeque<&mut SiteTree> = VecDeque::new();
+    job_queue.push_back(&mut crawl.tree);
+    crawl_queue(
+        job_queue,
+        domain,
+        &mut visited,
+        &client,
+        &options,
+        &mut pacer,
+    )
+    .await;
+
+    if options.sitemap {
+        let sitemaps = discover_sitemaps(&client, &root, &options).await;
+        let (sitemaps, listed) = read_sitemaps(&client, sitemaps, &options).await;
+
+        //Pages the links never reached are attached to the root and crawled like any other page
+        let mut in_tree: HashSet<String> = HashSet::new();
+        crawl.tree.visit(&mut |node| {
+            in_tree.insert(page_key(&node.current_site));
+        });
+        let mut seeds: Vec<SiteTree> = Vec::new();
+        for url in listed.iter() {
+            if url.host_str() == Some(domain) && in_tree.insert(page_key(url)) {
+                let mut seed = SiteTree::child(url.clone(), 1);
+                seed.page.from_sitemap = true;
+                seeds.push(seed);
+            }
+        }
+        if !seeds.is_empty() {
+            if let SubSites::Nil = crawl.tree.sub_sites {
+                crawl.tree.sub_sites = SubSites::List(Vec::new());
+            }
+            if let SubSites::List(sub_sites) = &mut crawl.tree.sub_sites {
+                let first_seed = sub_sites.len();
+                sub_sites.extend(seeds);
+                let job_queue: VecDeque<&mut SiteTree> =
+                    sub_sites[first_seed..].iter_mut().collect();
+                crawl_queue(
+                    job_queue,
+                    domain,
+                    &mut visited,
+                    &client,
+                    &options,
+                    &mut pacer,
+                )
+                .await;
+            }
+        }
+        crawl.report.sitemap = Some(sitemap_report(&crawl.tree, sitemaps, &listed));
+    }
+    if options.check_external {
+        check_external_links(&mut crawl.tree, &client, &options).await;
+    }
+    crawl.report.redirect_chains = redirect_chains(&crawl.tree);
+    crawl.report.external_domains = external_domains(&crawl.tree);
+    crawl.report.non_http = non_http_links(&crawl.tree);
+    crawl.report.broken_anchors = broken_anchors(&crawl.tree);
+    crawl.report.traps = visited.traps.traps;
+    (crawl.report.duplicates, crawl.report.near_duplicates) = duplicate_pages(&crawl.tree);
+    crawl
+}
+
+//Checks every distinct external http link once with a HEAD request, falling back to a GET for servers
+//which don't allow HEAD, and records the outcome on each node linking to it
+async fn check_external_links(tree: &mut SiteTree, client: &Client, options: &CrawlOptions) {
+    let mut external: Vec<Url> = Vec::new();
+    let mut seen: HashSet<String> = HashSet::new();
+    tree.visit(&mut |node| {
+        if node.page.kind == LinkKind::External && seen.insert(page_key(&node.current_site)) {
+            external.push(node.current_site.clone());
+        }
+    });
+
+    let mut checked: HashMap<String, PageInfo> = HashMap::new();
+    for url in external {
+        let mut page = PageInfo::default();
+        //Responses are dropped as soon as their status is known so bodies are never downloaded
+        let mut status = send_following(
+            client,
+            Method::HEAD,
+            &url,
+            &header::HeaderMap::new(),
+            options,
+            &|_| true,
+            &mut page.redirects,
+        )
+        .await
+        .map(|response| response.status().as_u16())
+        .map_err(|e| e.to_string());
+        if matches!(status, Ok(405 | 501)) {
+            status = send_following(
+                client,
+                Method::GET,
+                &url,
+                &header::HeaderMap::new(),
+                options,
+                &|_| true,
+                &mut page.redirects,
+            )
+            .await
+            .map(|response| response.status().as_u16())
+            .map_err(|e| e.to_string());
+        }
+        match status {
+            Ok(status) => page.status = Some(status),
+            Err(e) => page.error = Some(e),
+        }
+        checked.insert(page_key(&url), page);
+    }
+
+    tree.visit_mut(&mut |node| {
+        if node.page.kind == LinkKind::External {
+            if let Some(page) = checked.get(&page_key(&node.current_site)) {
+                node.page.status = page.status;
+                node.page.redirects = page.redirects.clone();
+                node.page.error = page.error.clone();
+            }
+        }
+    });
+}
+
+//Works through the queue breadth first, adding the links found on each page to the back of it
+//The log level and rate limit are read again for every page
+async fn crawl_queue(
+    mut job_queue: VecDeque<&mut SiteTree>,
+    domain: &str,
+    visited: &mut Visited,
+    client: &Client,
+    options: &CrawlOptions,
+    pacer: &mut Pacer,
+) {
+    while let Some(task) = job_queue.pop_front() {
+        //Only pages which would be fetched count towards the trap limits and the rate limit
+        let at_max_depth = options
+            .max_depth
+            .is_some_and(|max_depth| task.page.depth >= max_depth);
+        if !at_max_depth && !visited.site_set.contains(&page_key(&task.current_site)) {
+            if let Some(trap) = visited.traps.check(&task.current_site, options) {
+                if pacer.settings().log_level >= LogLevel::Warn {
+                    println!("Suspected crawl trap, not fetching {}", task.current_site);
+                }
+                task.page.trap = Some(trap);
+                continue;
+            }
+            pacer.wait(options).await;
+        }
+        let level = pacer.settings().log_level;
+        if level >= LogLevel::Info {
+            print!("Current site being scanned: {}", task);
+        }
+        let tree_result = tree_url_get(
+            &mut (*task),
+            domain,
+            visited,
+            &mut job_queue,
+            client,
+            options,
+            level,
+        )
+        .await;
+        if let Err(e) = tree_result {
+            eprintln!(
+                "There was an error in parsing the URL or scraping the site. The error is: {}",
+                &*e
+            )
+        }
+    }
+}
+
+pub async fn tree_url_get<'a>(
+    node: &'a mut SiteTree,
+    domain: &str,
+    visited: &mut Visited,
+    job_queue: &mut VecDeque<&'a mut SiteTree>,
+    client: &Client,
+    options: &CrawlOptions,
+    level: LogLevel,
+) -> Result<(), Box<dyn Error>> {
+    let Visited {
+        site_set, contents, ..
+    } = visited;
+    //Check if the domain can be determined
+    let node_domain: &str = if let Some(node_domain) = node.current_site.host_str() {
+        node_domain
+    } else {
+        return Ok(());
+    };
+    //Pages at the depth limit are kept as leaves without being fetched
+    if options
+        .max_depth
+        .is_some_and(|max_depth| node.page.depth >= max_depth)
+    {
+        return Ok(());
+    }
+    //Check if the domain already exists
+    //Fragments never reach the server so a page is only fetched once whichever fragments link to it
+    if node_domain != domain || site_set.contains(&page_key(&node.current_site)) {
+        return Ok(());
+    } else {
+        site_set.insert(page_key(&node.current_site));
+    }
+
+    //A single GET is used for the page, non-HTML responses are dropped after their headers arrive
+    //Redirects off the site or to a page already crawled are recorded without being requested
+    let follow = |location: &Url| {
+        location.host_str() == Some(domain) && !site_set.contains(&page_key(location))
+    };
+    let fetched = fetch_with_retries(
+        client,
+        &node.current_site,
+        options,
+        &follow,
+        &mut node.page,
+        level,
+    )
+    .await;
+    //A page which couldn't be fetched keeps the reason so diffs and alerts count it as broken
+    node.page.error = fetched.as_ref().err().map(|e| e.to_string());
+    let hrefs: Vec<String> = match fetched? {
+        Some(hrefs) => hrefs,
+        None if node.page.not_modified => Vec::new(),
+        None => return Ok(()),
+    };
+
+    //A redirected page is known by where it ended up, so it's only expanded once however many links lead to it
+    //and never when it left the site
+    let final_site = match node.page.redirects.last() {
+        Some(redirect) => {
+            let final_site = redirect.location.clone();
+            if final_site.host_str() != Some(domain) || !site_set.insert(page_key(&final_site)) {
+                return Ok(());
+            }
+            final_site
+        }
+        None => node.current_site.clone(),
+    };
+    //Pages with the same body as one already crawled would only lead to the same links again
+    if options.skip_duplicates {
+        if let Some(fingerprint) = &node.page.fingerprint {
+            if !contents.insert(fingerprint.digest.clone()) {
+                return Ok(());
+            }
+        }
+    }
+    //Depth is where a page sits in this crawl, pages kept from an earlier crawl may have been found elsewhere
+    let child_depth = node.page.depth + 1;
+    //An unchanged page links to the same pages as before, which are checked again in turn
+    if node.page.not_modified {
+        if let SubSites::List(sub_sites_list) = &mut node.sub_sites {
+            for site_tree in sub_sites_list.iter_mut() {
+                site_tree.page.depth = child_depth;
+                if site_tree.page.kind == LinkKind::Internal {
+                    job_queue.push_back(site_tree);
+                }
+            }
+        }
+        return Ok(());
+    }
+
+    let mut sub_sites: Vec<SiteTree> = Vec::new();
+
+    let mut href_errors: Vec<Result<(), Box<dyn Error>>> = Vec::new();
+
+    let mut local_duplicate_set: HashSet<Url> = HashSet::new();
+
+    //Resolve the links gathered from the html code
+    for href in hrefs.iter() {
+        if let Ok(url) = Url::parse(href) {
+            if local_duplicate_set.contains(&url) {
+            } else {
+                //One can use this region to modify duplicate nodes with an identifier
+                local_duplicate_set.insert(url.clone());
+                sub_sites.push(SiteTree::child(url, child_depth));
+                href_errors.push(Ok(()));
+            }
+        } else if let Ok(url) = {
+            //Relative links are relative to the page the content came from
+            let sub_site_host = final_site.clone();
+            sub_site_host.join(href)
+        } {
+            if local_duplicate_set.contains(&url) {
+            } else {
+                local_duplicate_set.insert(url.clone());
+
+                sub_sites.push(SiteTree::child(url, child_depth));
+                // Handle the case when URL parsing fails
+                // Error will cause the rest to not propogate
+                href_errors.push(Ok(()))
+            };
+        } else {
+            href_errors.push(Err(Box::new(ParseError::IdnaError)));
+        }
+    }
+
+    //Links a changed page still has keep what was found below them by the earlier crawl
+    if let SubSites::List(previous) = std::mem::replace(&mut node.sub_sites, SubSites::Nil) {
+        let mut previous: HashMap<Url, SiteTree> = previous
+            .into_iter()
+            .map(|sub_site| (sub_site.current_site.clone(), sub_site))
+            .collect();
+        for sub_site in sub_sites.iter_mut() {
+            if let Some(kept) = previous.remove(&sub_site.current_site) {
+                *sub_site = kept;
+            }
+        }
+    }
+
+    //Off-site and non-http links are kept as leaves so they can be reported but are never crawled
+    for sub_site in sub_sites.iter_mut() {
+        sub_site.page.depth = child_depth;
+        sub_site.page.kind = LinkKind::of(&sub_site.current_site, domain);
+    }
+    if options.drop_non_http {
+        sub_sites.retain(|sub_site| sub_site.page.kind.is_http());
+    }
+
+    //Attach the links gathered on the page to the passed in node and add references to all internal sub-nodes to the queue
+    node.sub_sites = SubSites::List(sub_sites);
+    if let SubSites::List(sub_sites_list) = &mut node.sub_sites {
+        for site_tree in sub_sites_list.iter_mut() {
+            if site_tree.page.kind == LinkKind::Internal {
+                job_queue.push_back(site_tree);
+            }
+        }
+    }
+
+    //Check for if all values are errors. If one value isn't it still gets passed without error but if all are this panics with the expectation that something is wrong
+    let (oks, errs): (Vec<_>, Vec<_>) = href_errors.into_iter().partition(Result::is_ok);
+
+    if !oks.is_empty() {
+        //The vector contains at least one Ok value
+        Ok(())
+    } else {
+        //The vector does not contain any Ok values
+        if !errs.is_empty() {
+            //The vector contains at least one Err value
+            Err(Box::new(ParseError::IdnaError))
+        } else {
+            //The vector does not contain any Err values
+            Ok(())
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
-    use super::{parse_url, tree_url_get};
-    use crate::tree::{SiteTree, SubSites};
+    use super::{
+        crawl_site, fetch_with_retries, parse_root, parse_url, recrawl_site, sniff_html,
+        tree_url_get, Visited,
+    };
+    use crate::client::build_client;
+    use crate::extract::extract;
+    use crate::fingerprint::Fingerprint;
+    use crate::live::{fixed, LogLevel};
+    use crate::options::CrawlOptions;
+    use crate::redirect::Redirect;
+    use crate::report::{ExternalDomain, NonHttpLinks, RecrawlReport};
+    use crate::retry::RetryPolicy;
+    use crate::test_server::{header, http_response, request_lines, serve};
+    use crate::trap::{Trap, TrapKind};
+    use crate::tree::{LinkKind, PageInfo, SiteTree, SubSites};
+    use reqwest::Client;
     use std::collections::{HashSet, VecDeque};
+    use std::time::Duration;
     use url::Url;
 
+    fn fingerprint(body: &str) -> Option<Fingerprint> {
+        Some(Fingerprint {
+            digest: format!("{:x}", md5::compute(body)),
+            simhash: extract(body.as_bytes(), usize::MAX).simhash,
+        })
+    }
+
+    #[test]
+    fn test_sniff_html() {
+        assert!(sniff_html(b"\xEF\xBB\xBF  <!DOCTYPE html><html></html>"));
+        assert!(sniff_html(b"\n<html>"));
+        assert!(sniff_html(b"<p class=\"intro\">Hello</p>"));
+        assert!(!sniff_html(b"<pre>"));
+        assert!(!sniff_html(b"%PDF-1.7"));
+        assert!(!sniff_html(b"{\"html\": true}"));
+        assert!(!sniff_html(b""));
+    }
+
+    #[test]
+    fn test_single_get_request() {
+        let (url, requests) = serve(vec![
+            (
+                "/",
+                http_response(
+                    "200 OK",
+                    "",
+                    "<!doctype html><a href=\"/report.pdf\">Report</a><a href=\"/about\">About</a>",
+                ),
+            ),
+            (
+                "/report.pdf",
+                http_response(
+                    "200 OK",
+                    "Content-Type: application/pdf\r\n",
+                    "%PDF-1.7 <a href=\"/hidden\">",
+                ),
+            ),
+            (
+                "/about",
+                http_response("200 OK", "Content-Type: text/html\r\n", "<p>About</p>"),
+            ),
+        ]);
+        let mut node = SiteTree {
+            page: PageInfo::default(),
+            current_site: url.clone(),
+            sub_sites: SubSites::Nil,
+        };
+        let mut visited = Visited::default();
+        let mut job_queue: VecDeque<&mut SiteTree> = VecDeque::new();
+
+        job_queue.push_back(&mut node);
+
+        tokio::runtime::Builder::new_multi_thread()
+            .enable_all()
+            .build()
+            .unwrap()
+            .block_on(async {
+                while let Some(task) = job_queue.pop_front() {
+                    tree_url_get(
+                        &mut (*task),
+                        url.host_str()
+                            .expect("The host was unable to be extracted from the url"),
+                        &mut visited,
+                        &mut job_queue,
+                        &Client::new(),
+                        &CrawlOptions::default(),
+                        LogLevel::Info,
+                    )
+                    .await
+                    .expect("Unable to parse the tree URL");
+                }
+            });
+
+        assert_eq!(
+            node,
+            SiteTree {
+                page: PageInfo {
+                    status: Some(200),
+                    html: true,
+                    anchors: Some(HashSet::new()),
+                    fingerprint: fingerprint(
+                        "<!doctype html><a href=\"/report.pdf\">Report</a><a href=\"/about\">About</a>",
+                    ),
+                    ..PageInfo::default()
+                },
+                current_site: url.clone(),
+                sub_sites: SubSites::List(vec![
+                    SiteTree {
+                        page: PageInfo {
+                            depth: 1,
+                            status: Some(200),
+                            ..PageInfo::default()
+                        },
+                        current_site: url.join("/report.pdf").unwrap(),
+                        sub_sites: SubSites::Nil,
+                    },
+                    SiteTree {
+                        page: PageInfo {
+                            depth: 1,
+                            status: Some(200),
+                            html: true,
+                            anchors: Some(HashSet::new()),
+                            fingerprint: fingerprint("<p>About</p>"),
+                            ..PageInfo::default()
+                        },
+                        current_site: url.join("/about").unwrap(),
+                        sub_sites: SubSites::List(Vec::new()),
+                    },
+                ]),
+            }
+        );
+        assert_eq!(
+            request_lines(&requests),
+            vec![
+                "GET / HTTP/1.1",
+                "GET /report.pdf HTTP/1.1",
+                "GET /about HTTP/1.1"
+            ]
+        );
+    }
+
     #[test]
     fn test_parse_url() {
         let url_test = String::from("www.example.com");
@@ -38,23 +829,47 @@ mod tests {
             Url::parse("http://www.example.com").expect("Couldn't parse the given URL"),
             parsed_url
         );
+
+        //Only http roots with a host can be crawled
+        assert_eq!(
+            parse_root("example.com/docs").unwrap().as_str(),
+            "http://example.com/docs"
+        );
+        assert_eq!(
+            parse_root("mailto:a@b"),
+            Err("mailto:a@b isn't an http or https url with a host".to_string())
+        );
+        assert!(parse_root("data:,x").is_err());
+        assert!(parse_root("file:///tmp/site").is_err());
     }
 
     #[test]
     fn test_tree_url_get() {
         let site_tree = SiteTree {
+            page: PageInfo {
+                status: Some(200),
+                html: true,
+                anchors: Some(HashSet::new()),
+                ..PageInfo::default()
+            },
             current_site: Url::parse("http://www.example.com").unwrap(),
             sub_sites: SubSites::List(vec![SiteTree {
+                page: PageInfo {
+                    kind: LinkKind::External,
+                    depth: 1,
+                    ..PageInfo::default()
+                },
                 current_site: Url::parse("https://www.iana.org/domains/example").unwrap(),
                 sub_sites: SubSites::Nil,
             }]),
         };
         let url = Url::parse("http://www.example.com").expect("Couldn't parse the given URL");
         let mut node = SiteTree {
+            page: PageInfo::default(),
             current_site: url.clone(),
             sub_sites: SubSites::Nil,
         };
-        let mut site_set: HashSet<String> = HashSet::new();
+        let mut visited = Visited::default();
         let mut job_queue: VecDeque<&mut SiteTree> = VecDeque::new();
 
         tokio::runtime::Builder::new_multi_thread()
@@ -67,8 +882,11 @@ mod tests {
                         &mut node,
                         url.domain()
                             .expect("The Domain was unable to be extracted from the url"),
-                        &mut site_set,
+                        &mut visited,
                         &mut job_queue,
+                        &Client::new(),
+                        &CrawlOptions::default(),
+                        LogLevel::Info,
                     )
                     .await
                     .expect("Wasn't able to parse the tree URL");
@@ -81,11 +899,14 @@ mod tests {
         println!();
         println!("Generated node:\n{}", node);
         println!("Hash set iterator: ");
-        for value in site_set.iter() {
+        for value in visited.site_set.iter() {
             println!("{}", value);
         }
 
         println!();
+        //The fingerprint follows whatever the live page currently says
+        let mut site_tree = site_tree;
+        site_tree.page.fingerprint = node.page.fingerprint.clone();
         assert_eq!(site_tree, node);
     }
 
@@ -93,10 +914,11 @@ mod tests {
     fn test_long_queue() {
         let url = Url::parse("https://spideroak.com").expect("Couldn't parse the given URL");
         let mut node = SiteTree {
+            page: PageInfo::default(),
             current_site: url.clone(),
             sub_sites: SubSites::Nil,
         };
-        let mut site_set: HashSet<String> = HashSet::new();
+        let mut visited = Visited::default();
         let mut job_queue: VecDeque<&mut SiteTree> = VecDeque::new();
 
         job_queue.push_back(&mut node);
@@ -113,8 +935,11 @@ mod tests {
                             &mut (*task),
                             url.domain()
                                 .expect("The Domain was unable to be extracted from the url"),
-                            &mut site_set,
+                            &mut visited,
                             &mut job_queue,
+                            &Client::new(),
+                        &CrawlOptions::default(),
+                            LogLevel::Info,
                         )
                         .await;
                         if let Err(e) = tree_result {
@@ -130,7 +955,7 @@ mod tests {
         println!();
         println!("Generated node:\n{}", node);
         println!("Hash set iterator: ");
-        for value in site_set.iter() {
+        for value in visited.site_set.iter() {
             println!("{}", value);
         }
     }
@@ -139,10 +964,11 @@ mod tests {
     fn test_empty_site() {
         let url = Url::parse("http://itcorp.com/").expect("Couldn't parse the given URL");
         let mut node = SiteTree {
+            page: PageInfo::default(),
             current_site: url.clone(),
             sub_sites: SubSites::Nil,
         };
-        let mut site_set: HashSet<String> = HashSet::new();
+        let mut visited = Visited::default();
         let mut job_queue: VecDeque<&mut SiteTree> = VecDeque::new();
 
         job_queue.push_back(&mut node);
@@ -158,8 +984,11 @@ mod tests {
                             &mut (*task),
                             url.domain()
                                 .expect("The Domain was unable to be extracted from the url"),
-                            &mut site_set,
+                            &mut visited,
                             &mut job_queue,
+                            &Client::new(),
+                            &CrawlOptions::default(),
+                            LogLevel::Info,
                         )
                         .await
                         .expect("Unable to parse the tree URL");
@@ -173,7 +1002,7 @@ mod tests {
         println!();
         println!("Generated node:\n{}", node);
         println!("Hash set iterator: ");
-        for value in site_set.iter() {
+        for value in visited.site_set.iter() {
             println!("{}", value);
         }
     }
@@ -183,10 +1012,11 @@ mod tests {
         let url = Url::parse("https://spideroak.com/release/crossclave/osx")
             .expect("Couldn't parse the given URL");
         let mut node = SiteTree {
+            page: PageInfo::default(),
             current_site: url.clone(),
             sub_sites: SubSites::Nil,
         };
-        let mut site_set: HashSet<String> = HashSet::new();
+        let mut visited = Visited::default();
         let mut job_queue: VecDeque<&mut SiteTree> = VecDeque::new();
 
         job_queue.push_back(&mut node);
@@ -203,8 +1033,11 @@ mod tests {
                             &mut (*task),
                             url.domain()
                                 .expect("The Domain was unable to be extracted from the url"),
-                            &mut site_set,
+                            &mut visited,
                             &mut job_queue,
+                            &Client::new(),
+                            &CrawlOptions::default(),
+                            LogLevel::Info,
                         )
                         .await
                         .expect("Unable to parse the tree URL");
@@ -218,135 +1051,913 @@ mod tests {
         println!();
         println!("Generated node:\n{}", node);
         println!("Hash set iterator: ");
-        for value in site_set.iter() {
+        for value in visited.site_set.iter() {
             println!("{}", value);
         }
     }
-}
 
-pub async fn tree_url_get<'a>(
-    node: &'a mut SiteTree,
-    domain: &str,
-    site_set: &mut HashSet<String>,
-    job_queue: &mut VecDeque<&'a mut SiteTree>,
-) -> Result<(), Box<dyn Error>> {
-    //Check if the domain can be determined
-    let node_domain: &str = if let Some(node_domain) = node.current_site.domain() {
-        node_domain
-    } else {
-        return Ok(());
-    };
-    //Check if the domain already exists
-    if node_domain != domain || site_set.contains(&node.current_site.to_string()) {
-        return Ok(());
-    } else {
-        site_set.insert(node.current_site.to_string());
+    #[test]
+    fn test_failed_page() {
+        //The same host on a port nothing listens on is still part of the site
+        let closed = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let missing = format!("http://{}/gone", closed.local_addr().unwrap());
+        drop(closed);
+        let (url, _) = serve(vec![(
+            "/",
+            http_response(
+                "200 OK",
+                "Content-Type: text/html\r\n",
+                &format!("<a href=\"{}\">Gone</a>", missing),
+            ),
+        )]);
+        let options = CrawlOptions {
+            retry: RetryPolicy {
+                max_attempts: 1,
+                ..RetryPolicy::default()
+            },
+            ..CrawlOptions::default()
+        };
+
+        let crawl = tokio::runtime::Builder::new_multi_thread()
+            .enable_all()
+            .build()
+            .unwrap()
+            .block_on(crawl_site(url.clone(), Client::new(), options, fixed()));
+
+        let SubSites::List(sub_sites) = &crawl.tree.sub_sites else {
+            panic!("The root should have been crawled");
+        };
+        assert_eq!(sub_sites[0].current_site.as_str(), missing);
+        assert_eq!(sub_sites[0].page.status, None);
+        assert!(sub_sites[0].page.error.is_some());
+        assert_eq!(crawl.tree.page.error, None);
     }
 
-    let client = Client::new();
+    #[test]
+    fn test_crawl_limits() {
+        let (url, requests) = serve(vec![
+            (
+                "/",
+                http_response(
+                    "200 OK",
+                    "Content-Type: text/html\r\n",
+                    "<a href=\"/a\">A</a><a href=\"/b\">B</a><a href=\"/c\">C</a>",
+                ),
+            ),
+            (
+                "/a",
+                http_response(
+                    "200 OK",
+                    "Content-Type: text/html\r\n",
+                    "<a href=\"/a/1\">1</a>",
+                ),
+            ),
+        ]);
+        let options = CrawlOptions {
+            max_depth: Some(1),
+            max_links: 1,
+            ..CrawlOptions::default()
+        };
 
-    //Get the header from the html request to determine necessary featrues about the pages
-    //This can be maliciously poisened to cause a stack overflow
-    let head_req = client.head(node.current_site.clone()).send().await?;
-    if let Some(content_length) = head_req.headers().get(header::CONTENT_LENGTH) {
-        println!("{:?}", content_length);
-    };
-    let content_type = head_req
-        .headers()
-        .get(header::CONTENT_TYPE)
-        .and_then(|value| value.to_str().ok())
-        .map(|value| value.to_lowercase());
+        let crawl = tokio::runtime::Builder::new_multi_thread()
+            .enable_all()
+            .build()
+            .unwrap()
+            .block_on(crawl_site(url.clone(), Client::new(), options, fixed()));
 
-    let html_req: String = if let Some(content_type) = content_type {
-        if content_type.starts_with("text/html") {
-            // If the response is HTML, proceed with fetching and parsing the text
+        assert_eq!(
+            crawl.tree,
+            SiteTree {
+                page: PageInfo {
+                    status: Some(200),
+                    html: true,
+                    ..PageInfo::default()
+                },
+                current_site: url.clone(),
+                sub_sites: SubSites::List(vec![SiteTree::child(url.join("/a").unwrap(), 1)]),
+            }
+        );
+        assert_eq!(request_lines(&requests), vec!["GET / HTTP/1.1"]);
+    }
 
-            client
-                .get(node.current_site.clone())
-                .send()
-                .await?
-                .text()
-                .await?
+    #[test]
+    fn test_retry_status() {
+        let (url, requests) = serve(vec![
+            ("/", http_response("503 Service Unavailable", "", "")),
+            ("/", http_response("429 Too Many Requests", "", "")),
+            (
+                "/",
+                http_response(
+                    "200 OK",
+                    "Content-Type: text/html\r\n",
+                    "<a href=\"/a\">A</a>",
+                ),
+            ),
+        ]);
+        let mut options = CrawlOptions::default();
+        options.retry.backoff_base = Duration::from_millis(1);
+        let mut page = PageInfo::default();
 
-            // Continue with the rest of your code to parse the HTML response
-        } else {
-            // Handle non-HTML response, if necessary
-            return Ok(());
-        }
-    } else {
-        // Handle missing Content-Type header, if necessary
-        return Ok(());
-    }; //Can still stack overflow due to a poisoned html request
+        let links = tokio::runtime::Builder::new_multi_thread()
+            .enable_all()
+            .build()
+            .unwrap()
+            .block_on(fetch_with_retries(
+                &Client::new(),
+                &url,
+                &options,
+                &|_| true,
+                &mut page,
+                LogLevel::Info,
+            ))
+            .expect("The page should succeed on its last attempt");
 
-    let mut sub_sites: Vec<SiteTree> = Vec::new();
+        assert_eq!(links, Some(vec!["/a".to_string()]));
+        assert_eq!(page.retries, 2);
+        assert_eq!(request_lines(&requests).len(), 3);
 
-    let mut href_errors: Vec<Result<(), Box<dyn Error>>> = Vec::new();
+        //Once the attempts run out the last response is used as is
+        options.retry.max_attempts = 2;
+        let (url, requests) = serve(vec![(
+            "/",
+            http_response("503 Service Unavailable", "Content-Type: text/html\r\n", ""),
+        )]);
+        let mut page = PageInfo::default();
+        let links = tokio::runtime::Builder::new_multi_thread()
+            .enable_all()
+            .build()
+            .unwrap()
+            .block_on(fetch_with_retries(
+                &Client::new(),
+                &url,
+                &options,
+                &|_| true,
+                &mut page,
+                LogLevel::Info,
+            ))
+            .expect("The last attempt shouldn't be retried");
+        assert_eq!(links, Some(Vec::new()));
+        assert_eq!(page.retries, 1);
+        assert_eq!(request_lines(&requests).len(), 2);
+    }
 
-    let mut local_duplicate_set: HashSet<Url> = HashSet::new();
+    #[test]
+    fn test_resolve_override() {
+        let (server, requests) = serve(vec![(
+            "/",
+            http_response(
+                "200 OK",
+                "Content-Type: text/html\r\n",
+                "<a href=\"/about\">About</a>",
+            ),
+        )]);
+        let port = server.port().unwrap();
+        let url = Url::parse(&format!("http://staging.crawl.invalid:{}/", port)).unwrap();
+        let mut options = CrawlOptions::default();
+        options
+            .set(
+                "resolve",
+                &format!("staging.crawl.invalid:{}:127.0.0.1", port),
+            )
+            .unwrap();
+        let client = build_client(&url, &options).expect("Couldn't build the client");
+        //Connections would go to the url's port whatever the override says
+        let mut other_port = CrawlOptions::default();
+        other_port
+            .set("resolve", "staging.crawl.invalid:443:127.0.0.1")
+            .unwrap();
+        assert!(build_client(&url, &other_port).is_err());
 
-    //Filter out the links from the html code
-    Document::from(html_req.as_str())
-        .find(Name("a"))
-        .filter_map(|n| n.attr("href"))
-        .try_for_each(|href| {
-            if let Ok(url) = Url::parse(href) {
-                if local_duplicate_set.contains(&url) {
-                } else {
-                    //One can use this region to modify duplicate nodes with an identifier
-                    local_duplicate_set.insert(url.clone());
-                    let site_tree = SiteTree {
-                        current_site: url,
-                        sub_sites: SubSites::Nil,
-                    };
-                    sub_sites.push(site_tree);
-                    href_errors.push(Ok(()));
-                }
-            } else if let Ok(url) = {
-                let sub_site_host = node.current_site.clone();
-                sub_site_host.join(href)
-            } {
-                if local_duplicate_set.contains(&url) {
-                } else {
-                    local_duplicate_set.insert(url.clone());
+        let crawl = tokio::runtime::Builder::new_multi_thread()
+            .enable_all()
+            .build()
+            .unwrap()
+            .block_on(crawl_site(url.clone(), client, options, fixed()));
 
-                    let site_tree = SiteTree {
-                        current_site: url,
-                        sub_sites: SubSites::Nil,
-                    };
-                    sub_sites.push(site_tree);
-                    // Handle the case when URL parsing fails
-                    // Error will cause the rest to not propogate
-                    href_errors.push(Ok(()))
-                };
-            } else {
-                href_errors.push(Err(Box::new(ParseError::IdnaError)));
-            }
-            Result::<(), ()>::Ok(())
-        })
-        .expect("Couldn't handle the result from parsing");
+        let mut about = SiteTree::child(url.join("/about").unwrap(), 1);
+        about.page.status = Some(404);
+        assert_eq!(crawl.tree.sub_sites, SubSites::List(vec![about]));
+        let requests = requests.lock().unwrap();
+        assert_eq!(requests.len(), 2);
+        assert_eq!(
+            header(&requests[0], "host"),
+            Some(format!("staging.crawl.invalid:{}", port).as_str())
+        );
+    }
 
-    //Attach the links gathered on the page to the passed in node and add references to all sub-nodes to the queue
-    node.sub_sites = SubSites::List(sub_sites);
-    if let SubSites::List(sub_sites_list) = &mut node.sub_sites {
-        for site_tree in sub_sites_list.iter_mut() {
-            job_queue.push_back(site_tree);
+    #[test]
+    fn test_sitemap_crawl() {
+        let (url, requests) = serve(vec![
+            (
+                "/",
+                http_response(
+                    "200 OK",
+                    "Content-Type: text/html\r\n",
+                    "<a href=\"/about\">About</a><a href=\"/blog#latest\">Blog</a>",
+                ),
+            ),
+            (
+                "/about",
+                http_response("200 OK", "Content-Type: text/html\r\n", ""),
+            ),
+            (
+                "/blog",
+                http_response("200 OK", "Content-Type: text/html\r\n", ""),
+            ),
+            (
+                "/hidden",
+                http_response(
+                    "200 OK",
+                    "Content-Type: text/html\r\n",
+                    "<a href=\"/hidden/child\">Child</a>",
+                ),
+            ),
+            (
+                "/hidden/child",
+                http_response("200 OK", "Content-Type: text/html\r\n", ""),
+            ),
+            (
+                "/robots.txt",
+                http_response("200 OK", "", "Sitemap: /sitemap-index.xml\n"),
+            ),
+            (
+                "/pages.xml",
+                http_response(
+                    "200 OK",
+                    "Content-Type: application/xml\r\n",
+                    "<urlset><url><loc>{origin}/</loc></url><url><loc>{origin}/blog</loc></url>\
+                     <url><loc>{origin}/hidden</loc></url></urlset>",
+                ),
+            ),
+            (
+                "/sitemap-index.xml",
+                http_response(
+                    "200 OK",
+                    "",
+                    "<sitemapindex><sitemap><loc>{origin}/pages.xml</loc></sitemap></sitemapindex>",
+                ),
+            ),
+        ]);
+        let options = CrawlOptions {
+            sitemap: true,
+            ..CrawlOptions::default()
+        };
+
+        let crawl = tokio::runtime::Builder::new_multi_thread()
+            .enable_all()
+            .build()
+            .unwrap()
+            .block_on(crawl_site(url.clone(), Client::new(), options, fixed()));
+
+        let mut hidden = SiteTree::child(url.join("/hidden").unwrap(), 1);
+        hidden.page.status = Some(200);
+        hidden.page.html = true;
+        hidden.page.anchors = Some(HashSet::new());
+        hidden.page.fingerprint = fingerprint("<a href=\"/hidden/child\">Child</a>");
+        hidden.page.from_sitemap = true;
+        let mut child = SiteTree::child(url.join("/hidden/child").unwrap(), 2);
+        child.page.status = Some(200);
+        child.page.html = true;
+        child.page.anchors = Some(HashSet::new());
+        child.page.fingerprint = fingerprint("");
+        child.sub_sites = SubSites::List(Vec::new());
+        hidden.sub_sites = SubSites::List(vec![child]);
+        if let SubSites::List(sub_sites) = &crawl.tree.sub_sites {
+            assert_eq!(sub_sites.len(), 3);
+            assert_eq!(sub_sites[2], hidden);
+        } else {
+            panic!("The root should have been crawled");
         }
+
+        let report = crawl
+            .report
+            .sitemap
+            .expect("The sitemap should be reported");
+        assert_eq!(
+            report.sitemaps,
+            vec![
+                url.join("/sitemap-index.xml").unwrap(),
+                url.join("/pages.xml").unwrap()
+            ]
+        );
+        assert_eq!(report.listed, 3);
+        assert_eq!(report.orphans, vec![url.join("/hidden").unwrap()]);
+        assert_eq!(
+            report.unlisted,
+            vec![
+                url.join("/about").unwrap(),
+                url.join("/hidden/child").unwrap()
+            ]
+        );
+        assert!(request_lines(&requests).contains(&"GET /sitemap.xml HTTP/1.1".to_string()));
     }
 
-    //Check for if all values are errors. If one value isn't it still gets passed without error but if all are this panics with the expectation that something is wrong
-    let (oks, errs): (Vec<_>, Vec<_>) = href_errors.into_iter().partition(Result::is_ok);
+    #[test]
+    fn test_redirect_crawl() {
+        let (url, requests) = serve(vec![
+            (
+                "/",
+                http_response(
+                    "200 OK",
+                    "Content-Type: text/html\r\n",
+                    "<a href=\"/old\">Old</a><a href=\"/docs/\">Docs</a><a href=\"/loop\">Loop</a>\
+                    <a href=\"/away\">Away</a><a href=\"/again\">Again</a>",
+                ),
+            ),
+            (
+                "/old",
+                http_response("301 Moved Permanently", "Location: /older\r\n", ""),
+            ),
+            (
+                "/older",
+                http_response("302 Found", "Location: /docs/\r\n", ""),
+            ),
+            (
+                "/docs/",
+                http_response(
+                    "200 OK",
+                    "Content-Type: text/html\r\n",
+                    "<a href=\"intro\">Intro</a>",
+                ),
+            ),
+            (
+                "/docs/intro",
+                http_response("200 OK", "Content-Type: text/html\r\n", ""),
+            ),
+            (
+                "/loop",
+                http_response("301 Moved Permanently", "Location: /loop\r\n", ""),
+            ),
+            (
+                "/away",
+                http_response(
+                    "302 Found",
+                    "Location: http://elsewhere.invalid/page\r\n",
+                    "",
+                ),
+            ),
+            (
+                "/again",
+                http_response("301 Moved Permanently", "Location: /docs/\r\n", ""),
+            ),
+        ]);
+        let options = CrawlOptions {
+            retry: RetryPolicy {
+                max_attempts: 1,
+                ..RetryPolicy::default()
+            },
+            ..CrawlOptions::default()
+        };
+        let client = build_client(&url, &options).expect("Couldn't build the client");
 
-    if !oks.is_empty() {
-        //The vector contains at least one Ok value
-        Ok(())
-    } else {
-        //The vector does not contain any Ok values
-        if !errs.is_empty() {
-            //The vector contains at least one Err value
-            Err(Box::new(ParseError::IdnaError))
+        let crawl = tokio::runtime::Builder::new_multi_thread()
+            .enable_all()
+            .build()
+            .unwrap()
+            .block_on(crawl_site(url.clone(), client, options, fixed()));
+
+        let old_redirects = vec![
+            Redirect {
+                status: 301,
+                location: url.join("/older").unwrap(),
+            },
+            Redirect {
+                status: 302,
+                location: url.join("/docs/").unwrap(),
+            },
+        ];
+        let sub_sites = match &crawl.tree.sub_sites {
+            SubSites::List(sub_sites) => sub_sites,
+            SubSites::Nil => panic!("The root should have been crawled"),
+        };
+        //Relative links resolve against where the redirects ended
+        assert_eq!(sub_sites[0].page.redirects, old_redirects);
+        assert_eq!(sub_sites[0].page.status, Some(200));
+        if let SubSites::List(children) = &sub_sites[0].sub_sites {
+            assert_eq!(children[0].current_site, url.join("/docs/intro").unwrap());
         } else {
-            //The vector does not contain any Err values
-            Ok(())
+            panic!("The redirected page should have been expanded");
         }
+        //The direct link reaches a page which was already crawled through the redirect
+        assert_eq!(sub_sites[1].sub_sites, SubSites::Nil);
+        assert_eq!(sub_sites[1].page.status, None);
+        assert_eq!(sub_sites[2].page.status, None);
+        //Redirects off the site or to a page already crawled end at the hop without requesting it
+        assert_eq!(sub_sites[3].page.status, Some(302));
+        assert_eq!(sub_sites[3].page.error, None);
+        assert_eq!(sub_sites[4].page.status, Some(301));
+        assert_eq!(
+            sub_sites[4].page.redirects,
+            vec![Redirect {
+                status: 301,
+                location: url.join("/docs/").unwrap()
+            }]
+        );
+        assert_eq!(
+            request_lines(&requests)
+                .iter()
+                .filter(|line| line.starts_with("GET /docs/ "))
+                .count(),
+            1
+        );
+
+        assert_eq!(
+            crawl.report.redirect_chains,
+            vec![
+                (url.join("/old").unwrap(), old_redirects),
+                (
+                    url.join("/loop").unwrap(),
+                    vec![Redirect {
+                        status: 301,
+                        location: url.join("/loop").unwrap()
+                    }]
+                )
+            ]
+        );
+        let report = crawl.report.to_string();
+        assert!(report.contains(&format!(
+            "  {} (loop)\n    301 -> {}",
+            url.join("/loop").unwrap(),
+            url.join("/loop").unwrap()
+        )));
+        assert!(crawl.tree.to_string().contains(&format!(
+            "{} -> {}",
+            url.join("/old").unwrap(),
+            url.join("/docs/").unwrap()
+        )));
+    }
+
+    #[test]
+    fn test_external_links() {
+        let (external, external_requests) = serve(vec![
+            ("/ok", http_response("200 OK", "", "")),
+            ("/missing", http_response("404 Not Found", "", "")),
+            ("/no-head", http_response("405 Method Not Allowed", "", "")),
+            ("/no-head", http_response("200 OK", "", "")),
+        ]);
+        //localhost and 127.0.0.1 are different hosts to the crawler
+        let external =
+            Url::parse(&format!("http://localhost:{}/", external.port().unwrap())).unwrap();
+        let links: String = ["/ok", "/ok", "/missing", "/no-head"]
+            .iter()
+            .map(|path| format!("<a href=\"{}\">Link</a>", external.join(path).unwrap()))
+            .collect();
+        let (url, _) = serve(vec![(
+            "/",
+            http_response("200 OK", "Content-Type: text/html\r\n", &links),
+        )]);
+        let options = CrawlOptions {
+            check_external: true,
+            ..CrawlOptions::default()
+        };
+        let client = build_client(&url, &options).expect("Couldn't build the client");
+
+        let crawl = tokio::runtime::Builder::new_multi_thread()
+            .enable_all()
+            .build()
+            .unwrap()
+            .block_on(crawl_site(url.clone(), client, options, fixed()));
+
+        if let SubSites::List(sub_sites) = &crawl.tree.sub_sites {
+            assert!(sub_sites
+                .iter()
+                .all(|sub_site| sub_site.page.kind == LinkKind::External
+                    && sub_site.sub_sites == SubSites::Nil));
+            let statuses: Vec<Option<u16>> = sub_sites
+                .iter()
+                .map(|sub_site| sub_site.page.status)
+                .collect();
+            assert_eq!(statuses, vec![Some(200), Some(404), Some(200)]);
+        } else {
+            panic!("The root should have been crawled");
+        }
+        //Each distinct link is only checked once
+        assert_eq!(
+            request_lines(&external_requests),
+            vec![
+                "HEAD /ok HTTP/1.1",
+                "HEAD /missing HTTP/1.1",
+                "HEAD /no-head HTTP/1.1",
+                "GET /no-head HTTP/1.1"
+            ]
+        );
+        assert_eq!(
+            crawl.report.external_domains,
+            vec![ExternalDomain {
+                host: "localhost".to_string(),
+                links: 3,
+                urls: 3,
+                broken: vec![(external.join("/missing").unwrap(), "404".to_string())],
+            }]
+        );
+    }
+
+    #[test]
+    fn test_non_http_links() {
+        let (url, requests) = serve(vec![(
+            "/",
+            http_response(
+                "200 OK",
+                "Content-Type: text/html\r\n",
+                "<a href=\"mailto:sales@example.com?subject=Hi\">Sales</a>\
+                 <a href=\"mailto:support@example.com,sales@example.com\">Support</a>\
+                 <a href=\"tel:+1-555-0100\">Call</a>\
+                 <a href=\"javascript:void(0)\">Menu</a>\
+                 <a href=\"data:text/plain,hello\">Hello</a>\
+                 <a href=\"ftp://127.0.0.1/files\">Files</a>",
+            ),
+        )]);
+        let ftp = Url::parse("ftp://127.0.0.1/files").unwrap();
+        let runtime = tokio::runtime::Builder::new_multi_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        let crawl = runtime.block_on(crawl_site(
+            url.clone(),
+            Client::new(),
+            CrawlOptions::default(),
+            fixed(),
+        ));
+
+        let kinds: Vec<LinkKind> = match &crawl.tree.sub_sites {
+            SubSites::List(sub_sites) => sub_sites
+                .iter()
+                .map(|sub_site| sub_site.page.kind)
+                .collect(),
+            SubSites::Nil => panic!("The root should have been crawled"),
+        };
+        assert_eq!(
+            kinds,
+            vec![
+                LinkKind::Email,
+                LinkKind::Email,
+                LinkKind::Phone,
+                LinkKind::Script,
+                LinkKind::Data,
+                LinkKind::Other
+            ]
+        );
+        //None of the links are fetched, even the ftp link on the same host
+        assert_eq!(request_lines(&requests), vec!["GET / HTTP/1.1"]);
+        assert_eq!(
+            crawl.report.non_http,
+            NonHttpLinks {
+                emails: vec![
+                    "sales@example.com".to_string(),
+                    "support@example.com".to_string()
+                ],
+                phones: vec!["+1-555-0100".to_string()],
+                scripts: 1,
+                data: 1,
+                other: vec![ftp],
+            }
+        );
+
+        let options = CrawlOptions {
+            drop_non_http: true,
+            ..CrawlOptions::default()
+        };
+        let crawl = runtime.block_on(crawl_site(url.clone(), Client::new(), options, fixed()));
+        assert_eq!(crawl.tree.sub_sites, SubSites::List(Vec::new()));
+        assert_eq!(crawl.report.non_http, NonHttpLinks::default());
+    }
+
+    #[test]
+    fn test_broken_anchors() {
+        let (url, requests) = serve(vec![
+            (
+                "/",
+                http_response(
+                    "200 OK",
+                    "Content-Type: text/html\r\n",
+                    "<h2 id=\"intro\">Intro</h2><a href=\"#intro\">1</a><a href=\"#missing\">2</a>\
+                     <a href=\"/guide#install\">3</a><a href=\"/guide#gone\">4</a><a href=\"/guide#top\">5</a>\
+                     <a href=\"/guide#caf%C3%A9\">6</a>",
+                ),
+            ),
+            (
+                "/guide",
+                http_response(
+                    "200 OK",
+                    "Content-Type: text/html\r\n",
+                    "<a name=\"install\"></a><p id=\"caf\u{e9}\">Caf\u{e9}</p>",
+                ),
+            ),
+        ]);
+
+        let crawl = tokio::runtime::Builder::new_multi_thread()
+            .enable_all()
+            .build()
+            .unwrap()
+            .block_on(crawl_site(
+                url.clone(),
+                Client::new(),
+                CrawlOptions::default(),
+                fixed(),
+            ));
+
+        assert_eq!(
+            crawl.report.broken_anchors,
+            vec![
+                (url.clone(), url.join("#missing").unwrap()),
+                (url.clone(), url.join("/guide#gone").unwrap())
+            ]
+        );
+        //Links differing only by fragment share one request
+        assert_eq!(
+            request_lines(&requests),
+            vec!["GET / HTTP/1.1", "GET /guide HTTP/1.1"]
+        );
+    }
+
+    #[test]
+    fn test_crawl_traps() {
+        //Every page links one level deeper so the path keeps growing
+        let page = || {
+            http_response(
+                "200 OK",
+                "Content-Type: text/html\r\n",
+                "<a href=\"a/\">Deeper</a>",
+            )
+        };
+        let (url, requests) = serve(vec![
+            ("/", page()),
+            ("/a/", page()),
+            ("/a/a/", page()),
+            ("/a/a/a/", page()),
+            ("/a/a/a/a/", page()),
+        ]);
+
+        let crawl = tokio::runtime::Builder::new_multi_thread()
+            .enable_all()
+            .build()
+            .unwrap()
+            .block_on(crawl_site(
+                url.clone(),
+                Client::new(),
+                CrawlOptions::default(),
+                fixed(),
+            ));
+
+        let trap = url.join("/a/a/a/a/").unwrap();
+        assert_eq!(
+            crawl.report.traps,
+            vec![Trap {
+                kind: TrapKind::RepeatedPath,
+                pattern: "/a/".to_string(),
+                example: trap.clone(),
+                stopped: 1,
+            }]
+        );
+        let mut flagged = Vec::new();
+        crawl.tree.visit(&mut |node| {
+            if node.page.trap.is_some() {
+                flagged.push(node.current_site.clone());
+            }
+        });
+        assert_eq!(flagged, vec![trap]);
+        assert_eq!(
+            request_lines(&requests),
+            vec![
+                "GET / HTTP/1.1",
+                "GET /a/ HTTP/1.1",
+                "GET /a/a/ HTTP/1.1",
+                "GET /a/a/a/ HTTP/1.1"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_duplicate_pages() {
+        let text =
+            "Opening hours for the main library are nine until five on weekdays and ten until \
+                    four on weekends, the reading room closes an hour earlier on public holidays";
+        let copy = format!("<p>{}</p><a href=\"next\">Next</a>", text);
+        let (url, requests) = serve(vec![
+            (
+                "/",
+                http_response(
+                    "200 OK",
+                    "Content-Type: text/html\r\n",
+                    "<a href=\"/one/page\">1</a><a href=\"/two/page\">2</a><a href=\"/edited\">3</a>",
+                ),
+            ),
+            (
+                "/one/page",
+                http_response("200 OK", "Content-Type: text/html\r\n", &copy),
+            ),
+            (
+                "/two/page",
+                http_response("200 OK", "Content-Type: text/html\r\n", &copy),
+            ),
+            (
+                "/edited",
+                http_response(
+                    "200 OK",
+                    "Content-Type: text/html\r\n",
+                    &format!(
+                        "<div>{}</div><a href=\"next\">Next</a>",
+                        text.replace("public holidays", "bank holidays")
+                    ),
+                ),
+            ),
+        ]);
+        let options = CrawlOptions {
+            skip_duplicates: true,
+            ..CrawlOptions::default()
+        };
+
+        let crawl = tokio::runtime::Builder::new_multi_thread()
+            .enable_all()
+            .build()
+            .unwrap()
+            .block_on(crawl_site(url.clone(), Client::new(), options, fixed()));
+
+        let one = url.join("/one/page").unwrap();
+        let two = url.join("/two/page").unwrap();
+        assert_eq!(
+            crawl.report.duplicates,
+            vec![vec![one.clone(), two.clone()]]
+        );
+        assert_eq!(
+            crawl.report.near_duplicates,
+            vec![vec![one, two, url.join("/edited").unwrap()]]
+        );
+        //The links on the second copy aren't followed
+        assert_eq!(
+            request_lines(&requests),
+            vec![
+                "GET / HTTP/1.1",
+                "GET /one/page HTTP/1.1",
+                "GET /two/page HTTP/1.1",
+                "GET /edited HTTP/1.1",
+                "GET /one/next HTTP/1.1",
+                "GET /next HTTP/1.1"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_recrawl() {
+        let html = "Content-Type: text/html\r\n";
+        let last_modified = "Wed, 21 Oct 2015 07:28:00 GMT";
+        let (url, requests) = serve(vec![
+            (
+                "/",
+                http_response(
+                    "200 OK",
+                    "Content-Type: text/html\r\nETag: \"v1\"\r\n",
+                    "<a href=\"/a\">A</a><a href=\"/b\">B</a>",
+                ),
+            ),
+            ("/", http_response("304 Not Modified", "", "")),
+            (
+                "/a",
+                http_response(
+                    "200 OK",
+                    &format!("{}Last-Modified: {}\r\n", html, last_modified),
+                    "<a href=\"/a/deep\">Deep</a>",
+                ),
+            ),
+            (
+                "/a",
+                http_response(
+                    "200 OK",
+                    html,
+                    "<a href=\"/a/deep\">Deep</a><a href=\"/c\">C</a>",
+                ),
+            ),
+            (
+                "/a/deep",
+                http_response("200 OK", "Content-Type: text/html\r\nETag: \"d1\"\r\n", ""),
+            ),
+            ("/a/deep", http_response("304 Not Modified", "", "")),
+            ("/b", http_response("200 OK", html, "")),
+            ("/c", http_response("200 OK", html, "")),
+        ]);
+
+        let runtime = tokio::runtime::Builder::new_multi_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        let crawl = runtime.block_on(crawl_site(
+            url.clone(),
+            Client::new(),
+            CrawlOptions::default(),
+            fixed(),
+        ));
+        let first_crawl = requests.lock().unwrap().len();
+        let crawl = runtime.block_on(recrawl_site(crawl, Client::new(), fixed()));
+
+        let requests: Vec<Vec<String>> = requests.lock().unwrap()[first_crawl..].to_vec();
+        let lines: Vec<&str> = requests.iter().map(|request| request[0].as_str()).collect();
+        assert_eq!(
+            lines,
+            vec![
+                "GET / HTTP/1.1",
+                "GET /a HTTP/1.1",
+                "GET /b HTTP/1.1",
+                "GET /a/deep HTTP/1.1",
+                "GET /c HTTP/1.1"
+            ]
+        );
+        assert_eq!(header(&requests[0], "if-none-match"), Some("\"v1\""));
+        assert_eq!(
+            header(&requests[1], "if-modified-since"),
+            Some(last_modified)
+        );
+        assert_eq!(header(&requests[2], "if-none-match"), None);
+        assert_eq!(header(&requests[2], "if-modified-since"), None);
+        assert_eq!(header(&requests[3], "if-none-match"), Some("\"d1\""));
+
+        //The unchanged root keeps what it had and the changed page gains a link while keeping the one it had
+        assert!(crawl.tree.page.not_modified);
+        assert_eq!(crawl.tree.page.status, Some(200));
+        assert_eq!(crawl.tree.page.etag.as_deref(), Some("\"v1\""));
+        let mut pages = Vec::new();
+        crawl.tree.visit(&mut |node| {
+            pages.push((node.current_site.path().to_string(), node.page.not_modified))
+        });
+        assert_eq!(
+            pages,
+            vec![
+                ("/".to_string(), true),
+                ("/a".to_string(), false),
+                ("/a/deep".to_string(), true),
+                ("/c".to_string(), false),
+                ("/b".to_string(), false)
+            ]
+        );
+        assert_eq!(
+            crawl.report.recrawl,
+            Some(RecrawlReport {
+                unchanged: 2,
+                refetched: 3
+            })
+        );
+    }
+
+    #[test]
+    fn test_recrawl_depth() {
+        let html = "Content-Type: text/html\r\n";
+        let (url, _requests) = serve(vec![
+            (
+                "/",
+                http_response(
+                    "200 OK",
+                    "Content-Type: text/html\r\nETag: \"r1\"\r\n",
+                    "<a href=\"/a\">A</a>",
+                ),
+            ),
+            ("/", http_response("304 Not Modified", "", "")),
+            (
+                "/a",
+                http_response("200 OK", html, "<a href=\"/a/deep\">Deep</a>"),
+            ),
+            (
+                "/a",
+                http_response("200 OK", html, "<a href=\"/a/deep\">Deep</a>"),
+            ),
+            (
+                "/a/deep",
+                http_response("200 OK", "Content-Type: text/html\r\nETag: \"d1\"\r\n", ""),
+            ),
+            ("/a/deep", http_response("304 Not Modified", "", "")),
+        ]);
+
+        let runtime = tokio::runtime::Builder::new_multi_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        let mut crawl = runtime.block_on(crawl_site(
+            url.clone(),
+            Client::new(),
+            CrawlOptions::default(),
+            fixed(),
+        ));
+        //Depths recorded by an earlier crawl aren't trusted, whether the page is unchanged or kept below a
+        //changed one
+        crawl.tree.visit_mut(&mut |node| {
+            if node.page.depth > 0 {
+                node.page.depth = 9
+            }
+        });
+        let crawl = runtime.block_on(recrawl_site(crawl, Client::new(), fixed()));
+        let mut depths = Vec::new();
+        crawl.tree.visit(&mut |node| {
+            depths.push((
+                node.current_site.path().to_string(),
+                node.page.depth,
+                node.page.not_modified,
+            ))
+        });
+        assert_eq!(
+            depths,
+            vec![
+                ("/".to_string(), 0, true),
+                ("/a".to_string(), 1, false),
+                ("/a/deep".to_string(), 2, true)
+            ]
+        );
     }
 }
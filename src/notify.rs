@@ -0,0 +1,327 @@
+use std::process::Stdio;
+use std::time::{Duration, SystemTime};
+
+use reqwest::header::CONTENT_TYPE;
+use serde::Serialize;
+
+use nix::sys::signal::{killpg, Signal};
+use nix::unistd::Pid;
+
+use tokio::io::AsyncWriteExt;
+use tokio::process::Command;
+use tokio::time::timeout;
+
+use url::Url;
+
+use crate::diff::{diff_crawls, BrokenLink};
+use crate::options::CrawlOptions;
+use crate::report::SiteCrawl;
+use crate::schedule::{fetched_pages, format_time};
+use crate::tree::SiteTree;
+
+//What a finished crawl of a site set off, sent as json to webhooks and commands
+#[derive(Debug, PartialEq, Serialize)]
+pub struct Notification {
+    pub site: Url,
+    pub finished: String,
+    pub alerts: Vec<String>,
+    pub pages: usize,
+    pub previous_pages: usize,
+    pub new_broken_links: Vec<BrokenLink>,
+}
+
+//Checks the crawl's alert rules against the previous crawl of the site, None when no rule was broken
+pub fn check_alerts(
+    previous: &SiteTree,
+    crawl: &SiteCrawl,
+    finished: SystemTime,
+) -> Option<Notification> {
+    let options = &crawl.options;
+    let (pages, _) = fetched_pages(&crawl.tree);
+    let (previous_pages, _) = fetched_pages(previous);
+    let mut notification = Notification {
+        site: crawl.tree.current_site.clone(),
+        finished: format_time(finished),
+        alerts: Vec::new(),
+        pages,
+        previous_pages,
+        new_broken_links: Vec::new(),
+    };
+    if options.alert_broken_links {
+        let broken = diff_crawls(previous, &crawl.tree).new_broken_links;
+        if !broken.is_empty() {
+            notification
+                .alerts
+                .push(format!("{} new broken links", broken.len()));
+            notification.new_broken_links = broken;
+        }
+    }
+    if let Some(percent) = options.alert_page_drop {
+        if pages * 100 <= previous_pages * (100 - percent) && pages < previous_pages {
+            notification.alerts.push(format!(
+                "Pages fell from {} to {}, down {}%",
+                previous_pages,
+                pages,
+                (previous_pages - pages) * 100 / previous_pages
+            ));
+        }
+    }
+    if notification.alerts.is_empty() {
+        None
+    } else {
+        Some(notification)
+    }
+}
+
+//Posts the notification to every webhook and pipes it into every command, giving back what failed
+//Commands are run with sh and also get the site in CRAWL_SITE
+pub async fn send_notification(notification: &Notification, options: &CrawlOptions) -> Vec<String> {
+    let mut failures = Vec::new();
+    let payload = match serde_json::to_string(notification) {
+        Ok(payload) => payload,
+        Err(e) => return vec![format!("Couldn't encode the notification: {}", e)],
+    };
+    let client = reqwest::Client::builder()
+        .user_agent(options.user_agent.as_str())
+        .connect_timeout(options.connect_timeout)
+        .timeout(options.timeout)
+        .build();
+    for webhook in options.webhooks.iter() {
+        let sent = match &client {
+            Ok(client) => client
+                .post(webhook.clone())
+                .header(CONTENT_TYPE, "application/json")
+                .body(payload.clone())
+                .send()
+                .await
+                .map_err(|e| e.to_string())
+                .and_then(|response| match response.status() {
+                    status if status.is_success() => Ok(()),
+                    status => Err(format!("it responded with {}", status)),
+                }),
+            Err(e) => Err(e.to_string()),
+        };
+        //Webhook urls often hold a token so only their host is shown
+        if let Err(e) = sent {
+            failures.push(format!(
+                "Couldn't notify the webhook on {}: {}",
+                webhook.host_str().unwrap_or_default(),
+                e
+            ));
+        }
+    }
+    for command in options.notify_commands.iter() {
+        if let Err(e) = run_command(command, notification, &payload, options.timeout).await {
+            failures.push(format!("Couldn't run {}: {}", command, e));
+        }
+    }
+    failures
+}
+
+async fn run_command(
+    command: &str,
+    notification: &Notification,
+    payload: &str,
+    limit: Duration,
+) -> Result<(), String> {
+    //The command gets a process group of its own so anything it starts can be killed along with it
+    let mut child = Command::new("sh")
+        .arg("-c")
+        .arg(command)
+        .env("CRAWL_SITE", notification.site.as_str())
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .process_group(0)
+        .spawn()
+        .map_err(|e| e.to_string())?;
+    let stdin = child.stdin.take();
+    //The command gets as long as a request would, so one that hangs can't hold up the daemon
+    let finished = timeout(limit, async {
+        if let Some(mut stdin) = stdin {
+            //A command which doesn't read its input isn't a failure
+            let _ = stdin.write_all(payload.as_bytes()).await;
+        }
+        child.wait().await
+    })
+    .await;
+    let status = match finished {
+        Ok(status) => status.map_err(|e| e.to_string())?,
+        Err(_) => {
+            if let Some(id) = child.id() {
+                let _ = killpg(Pid::from_raw(id as i32), Signal::SIGKILL);
+            }
+            let _ = child.kill().await;
+            return Err(format!(
+                "it was still running after {}s and was killed",
+                limit.as_secs_f64()
+            ));
+        }
+    };
+    if status.success() {
+        Ok(())
+    } else {
+        Err(format!("it exited with {}", status))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{check_alerts, send_notification, Notification};
+    use crate::options::CrawlOptions;
+    use crate::report::{CrawlReport, SiteCrawl};
+    use crate::schedule::fetched_pages;
+    use crate::test_server::{header, http_response, serve};
+    use crate::tree::{SiteTree, SubSites};
+    use std::fs::{read_to_string, remove_file};
+    use std::time::{Duration, UNIX_EPOCH};
+    use url::Url;
+
+    fn site(statuses: &[u16]) -> SiteTree {
+        let url = Url::parse("https://example.com/").unwrap();
+        let mut tree = SiteTree::new(url.clone());
+        tree.page.status = Some(200);
+        let pages = statuses
+            .iter()
+            .enumerate()
+            .map(|(index, status)| {
+                let mut page = SiteTree::child(url.join(&format!("/{}", index)).unwrap(), 1);
+                page.page.status = Some(*status);
+                page
+            })
+            .collect();
+        tree.sub_sites = SubSites::List(pages);
+        tree
+    }
+
+    #[test]
+    fn test_check_alerts() {
+        let finished = UNIX_EPOCH + Duration::from_secs(86400);
+        let previous = site(&[200, 200, 404, 200, 200, 200, 200, 200, 200]);
+        let mut crawl = SiteCrawl {
+            tree: site(&[200, 500, 404, 200, 200, 200]),
+            report: CrawlReport::default(),
+            options: CrawlOptions::default(),
+        };
+        //Without any rules nothing is sent
+        assert_eq!(check_alerts(&previous, &crawl, finished), None);
+
+        crawl.options.alert_broken_links = true;
+        crawl.options.alert_page_drop = Some(30);
+        let notification = check_alerts(&previous, &crawl, finished).unwrap();
+        assert_eq!(
+            notification.alerts,
+            vec![
+                "1 new broken links".to_string(),
+                "Pages fell from 10 to 7, down 30%".to_string()
+            ]
+        );
+        assert_eq!(notification.finished, "1970-01-02 00:00:00 UTC");
+        assert_eq!(
+            notification.new_broken_links[0].link.as_str(),
+            "https://example.com/1"
+        );
+
+        crawl.options.alert_page_drop = Some(31);
+        crawl.options.alert_broken_links = false;
+        assert_eq!(check_alerts(&previous, &crawl, finished), None);
+
+        //A page which stopped answering is a broken link too
+        crawl.tree = site(&[200, 200, 404, 200, 200, 200, 200, 200, 200]);
+        if let SubSites::List(pages) = &mut crawl.tree.sub_sites {
+            pages[0].page.status = None;
+            pages[0].page.error = Some("error sending request".to_string());
+        }
+        crawl.options.alert_broken_links = true;
+        let notification = check_alerts(&previous, &crawl, finished).unwrap();
+        assert_eq!(notification.alerts, vec!["1 new broken links".to_string()]);
+        assert_eq!(
+            notification.new_broken_links[0].problem,
+            "error sending request"
+        );
+        assert_eq!(fetched_pages(&crawl.tree), (9, 2));
+    }
+
+    #[tokio::test]
+    async fn test_send_notification() {
+        let (url, requests) = serve(vec![
+            ("/hook", http_response("204 No Content", "", "")),
+            (
+                "/broken",
+                http_response("500 Internal Server Error", "", ""),
+            ),
+        ]);
+        let output = std::env::temp_dir().join(format!("crawl-notify-{}", std::process::id()));
+        let notification = Notification {
+            site: Url::parse("https://example.com/").unwrap(),
+            finished: "1970-01-02 00:00:00 UTC".to_string(),
+            alerts: vec!["Pages fell from 10 to 7, down 30%".to_string()],
+            pages: 7,
+            previous_pages: 10,
+            new_broken_links: Vec::new(),
+        };
+        let options = CrawlOptions {
+            webhooks: vec![url.join("/hook").unwrap(), url.join("/broken").unwrap()],
+            notify_commands: vec![
+                format!(
+                    "cat > {}; echo $CRAWL_SITE >> {}",
+                    output.display(),
+                    output.display()
+                ),
+                "exit 3".to_string(),
+            ],
+            ..CrawlOptions::default()
+        };
+        let failures = send_notification(&notification, &options).await;
+        assert_eq!(
+            failures,
+            vec![
+                "Couldn't notify the webhook on 127.0.0.1: it responded with 500 Internal Server Error"
+                    .to_string(),
+                "Couldn't run exit 3: it exited with exit status: 3".to_string()
+            ]
+        );
+
+        let payload = serde_json::to_string(&notification).unwrap();
+        let hook = requests.lock().unwrap()[0].clone();
+        assert_eq!(hook[0], "POST /hook HTTP/1.1");
+        assert_eq!(header(&hook, "content-type"), Some("application/json"));
+        assert_eq!(hook.last(), Some(&payload));
+        assert_eq!(
+            read_to_string(&output).unwrap(),
+            format!("{}https://example.com/\n", payload)
+        );
+        remove_file(&output).unwrap();
+
+        //A command that runs past the timeout is killed rather than waited for, along with what it started
+        let command = format!("sleep 30 & echo $! > {}; wait", output.display());
+        let options = CrawlOptions {
+            notify_commands: vec![command.clone()],
+            timeout: Duration::from_millis(500),
+            ..CrawlOptions::default()
+        };
+        let started = std::time::Instant::now();
+        let failures = send_notification(&notification, &options).await;
+        assert_eq!(
+            failures,
+            vec![format!(
+                "Couldn't run {}: it was still running after 0.5s and was killed",
+                command
+            )]
+        );
+        assert!(started.elapsed() < Duration::from_secs(10));
+        let sleep = read_to_string(&output).unwrap();
+        remove_file(output).unwrap();
+        //The killed sleep may linger as a zombie until something reaps it
+        let running = || {
+            read_to_string(format!("/proc/{}/stat", sleep.trim()))
+                .is_ok_and(|stat| !stat.contains(") Z "))
+        };
+        for _ in 0..50 {
+            if !running() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+        assert!(!running());
+    }
+}
@@ -0,0 +1,529 @@
+use std::fs::canonicalize;
+use std::net::IpAddr;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use reqwest::header::{HeaderName, HeaderValue};
+
+use url::Url;
+
+use crate::auth::{Credential, Secret};
+use crate::retry::{RetryError, RetryPolicy};
+
+//Per job crawl settings. The client sends these to the daemon as `key=value` lines after the url,
+//with each key matching the long flag used on the command line without its leading dashes
+#[derive(Debug, PartialEq, Clone)]
+pub struct CrawlOptions {
+    pub max_body_size: usize,
+    pub max_links: usize,
+    pub max_depth: Option<usize>,
+    //Redirects followed for one page before giving up on it
+    pub max_redirects: usize,
+    //Limits past which a url is treated as a crawl trap and left unfetched
+    pub max_url_length: usize,
+    //Times one path segment may appear in a url, /a/a/a/a/ has a repeated four times
+    pub max_path_repeats: usize,
+    //Distinct urls sharing a pattern, with numbers and ids in the path and query values ignored
+    pub max_template_urls: usize,
+    pub max_directory_pages: usize,
+    pub connect_timeout: Duration,
+    //Longest wait for the response headers or any single chunk of the body
+    pub read_timeout: Duration,
+    //Longest a whole request may take including its body
+    pub timeout: Duration,
+    //Most pages a second the job fetches, when None the daemon's rate-limit applies and can change while it runs
+    pub rate_limit: Option<f64>,
+    pub retry: RetryPolicy,
+    pub user_agent: String,
+    pub headers: Vec<(String, String)>,
+    //Netscape cookies.txt files loaded into the job's cookie jar
+    pub cookie_files: Vec<PathBuf>,
+    pub cookies: Vec<String>,
+    pub credentials: Vec<Credential>,
+    //Form posted before the crawl starts so the session cookie it sets is used for every page
+    pub login_url: Option<Url>,
+    pub login_fields: Vec<(String, Secret)>,
+    //Proxies for every scheme or only http or https urls, when none are set the daemon's
+    //HTTP_PROXY, HTTPS_PROXY, ALL_PROXY and NO_PROXY environment variables are used instead
+    pub proxy: Option<Url>,
+    pub http_proxy: Option<Url>,
+    pub https_proxy: Option<Url>,
+    //Comma separated hosts, domains and IP ranges reached without the job's proxies
+    pub no_proxy: Option<String>,
+    pub proxy_auth: Option<(String, Secret)>,
+    //Hosts connected to at a chosen address instead of the one DNS gives, as host, port and address
+    pub resolve: Vec<(String, u16, IpAddr)>,
+    //Read the site's sitemaps, crawl the pages only they list and report how they compare to the links
+    pub sitemap: bool,
+    //HEAD request every distinct external link once after the crawl to find broken ones
+    pub check_external: bool,
+    //Leave mailto, tel, javascript, data, ftp and other non-http links out of the tree
+    pub drop_non_http: bool,
+    //Don't follow the links on a page whose body is identical to one already crawled
+    pub skip_duplicates: bool,
+    //Rules checked against the previous crawl of the site whenever a job finishes, alerts go to every
+    //webhook as a json POST and to every command on its standard input
+    pub alert_broken_links: bool,
+    //Percentage the number of fetched pages has to fall by
+    pub alert_page_drop: Option<usize>,
+    pub webhooks: Vec<Url>,
+    pub notify_commands: Vec<String>,
+    //Queued jobs with a higher priority are started first
+    pub priority: i32,
+}
+
+//Options naming files, which the client resolves before sending since the daemon runs from another directory
+pub const PATH_OPTIONS: [&str; 1] = ["cookies"];
+//Every option as its flag name, the name of its value or None for switches, and its help text. Switches are
+//turned on by their flag alone and sent to the daemon as `key=true`
+pub const CRAWL_FLAGS: [(&str, Option<&str>, &str); 41] = [
+    ("max-depth", Some("N"), "Pages more than n links away from the url are recorded but not fetched"),
+    ("max-redirects", Some("N"), "Redirects followed for a page before giving up on it (default 10)"),
+    ("max-url-length", Some("N"), "Longer urls are treated as crawl traps and not fetched (default 2048)"),
+    ("max-path-repeats", Some("N"), "Urls with one path segment more than n times are treated as traps (default 3)"),
+    ("max-template-urls", Some("N"), "Urls fetched per pattern, with numbers and ids in the path and query values ignored (default 500)"),
+    ("max-directory-pages", Some("N"), "Pages fetched from one directory (default 1000)"),
+    ("max-links", Some("N"), "At most n links are taken from each page (default 5000)"),
+    ("max-body-size", Some("BYTES"), "Page bodies are cut off after this many bytes (default 10485760)"),
+    ("connect-timeout", Some("SECONDS"), "Longest wait to connect to a server (default 10)"),
+    ("read-timeout", Some("SECONDS"), "Longest wait for the headers or any part of the body (default 30)"),
+    ("timeout", Some("SECONDS"), "Longest a whole request may take (default 120)"),
+    ("rate-limit", Some("PAGES"), "Most pages fetched a second, fractions like 0.5 allowed (default the daemon's rate-limit)"),
+    ("max-attempts", Some("N"), "Requests made for a page before giving up on it (default 3)"),
+    ("backoff-base", Some("SECONDS"), "Wait before the first retry, doubled for each retry after (default 0.5)"),
+    ("backoff-max", Some("SECONDS"), "Longest wait between retries (default 30)"),
+    ("retry-status", Some("CODES"), "Comma separated statuses which are retried (default 429,500,502,503,504)"),
+    ("retry-on", Some("KINDS"), "Comma separated failures which are retried out of connect, timeout and body (default all)"),
+    ("user-agent", Some("STRING"), "User-Agent sent with every request (default crawl/version)"),
+    ("header", Some("NAME: VALUE"), "Extra header sent with every request, can be repeated"),
+    ("cookies", Some("PATH"), "Netscape cookies.txt file loaded before the crawl, can be repeated"),
+    ("cookie", Some("NAME=VALUE"), "Cookie sent to the url's host, can be repeated"),
+    ("basic-auth", Some("HOST=USER:PASSWORD"), "HTTP Basic credentials only sent to host, can be repeated"),
+    ("digest-auth", Some("HOST=USER:PASSWORD"), "HTTP Digest credentials only sent to host, can be repeated"),
+    ("bearer-token", Some("HOST=TOKEN"), "Bearer token only sent to host, can be repeated"),
+    ("login-url", Some("URL"), "Login form posted to before the crawl, its session cookie is kept"),
+    ("login-field", Some("NAME=VALUE"), "Field posted to the login form, can be repeated"),
+    ("proxy", Some("URL"), "Proxy used for every request, http, https, socks5 and socks5h urls are supported"),
+    ("http-proxy", Some("URL"), "Proxy used for http urls"),
+    ("https-proxy", Some("URL"), "Proxy used for https urls"),
+    ("no-proxy", Some("HOSTS"), "Comma separated hosts, domains and IP ranges reached without the proxy"),
+    ("proxy-auth", Some("USER:PASSWORD"), "Credentials sent to the proxy"),
+    ("resolve", Some("HOST:PORT:ADDRESS"), "Connect to address whenever host is requested, keeping the host in the Host header and the tree, port must be the one the url uses, can be repeated"),
+    ("check-external", None, "Check every distinct link to another host once with a HEAD request and list the broken ones by host"),
+    ("drop-non-http", None, "Leave mailto, tel, javascript, data, ftp and other non-http links out of the tree and report"),
+    ("skip-duplicates", None, "Don't follow the links on a page whose body is identical to one already crawled"),
+    ("priority", Some("N"), "Queued jobs with a higher priority are started first (default 0)"),
+    ("alert-broken-links", None, "Alert when a later crawl of the site finds links which are broken but weren't last time"),
+    ("alert-page-drop", Some("PERCENT"), "Alert when a later crawl of the site fetches at least this many percent fewer pages"),
+    ("webhook", Some("URL"), "Url alerts are posted to as json, can be repeated"),
+    ("notify-command", Some("COMMAND"), "Command run with sh for each alert, getting the json on standard input and the site in CRAWL_SITE, can be repeated, it and anything it starts are killed after --timeout"),
+    ("sitemap", None, "Read the sitemaps from robots.txt and /sitemap.xml, crawl the pages only they list and report orphaned and unlisted pages"),
+];
+
+impl Default for CrawlOptions {
+    fn default() -> Self {
+        CrawlOptions {
+            max_body_size: 10 * 1024 * 1024,
+            max_links: 5000,
+            max_depth: None,
+            max_redirects: 10,
+            max_url_length: 2048,
+            max_path_repeats: 3,
+            max_template_urls: 500,
+            max_directory_pages: 1000,
+            connect_timeout: Duration::from_secs(10),
+            read_timeout: Duration::from_secs(30),
+            timeout: Duration::from_secs(120),
+            rate_limit: None,
+            retry: RetryPolicy::default(),
+            user_agent: concat!("crawl/", env!("CARGO_PKG_VERSION")).to_string(),
+            headers: Vec::new(),
+            cookie_files: Vec::new(),
+            cookies: Vec::new(),
+            credentials: Vec::new(),
+            login_url: None,
+            login_fields: Vec::new(),
+            proxy: None,
+            http_proxy: None,
+            https_proxy: None,
+            no_proxy: None,
+            proxy_auth: None,
+            resolve: Vec::new(),
+            sitemap: false,
+            check_external: false,
+            drop_non_http: false,
+            skip_duplicates: false,
+            alert_broken_links: false,
+            alert_page_drop: None,
+            webhooks: Vec::new(),
+            notify_commands: Vec::new(),
+            priority: 0,
+        }
+    }
+}
+
+impl CrawlOptions {
+    pub fn set(&mut self, key: &str, value: &str) -> Result<(), String> {
+        match key {
+            "max-body-size" => self.max_body_size = parse_number(key, value)?,
+            "max-links" => self.max_links = parse_number(key, value)?,
+            "max-depth" => self.max_depth = Some(parse_number(key, value)?),
+            "max-redirects" => self.max_redirects = parse_number(key, value)?,
+            "max-url-length" => self.max_url_length = parse_number(key, value)?,
+            "max-path-repeats" => self.max_path_repeats = parse_number(key, value)?,
+            "max-template-urls" => self.max_template_urls = parse_number(key, value)?,
+            "max-directory-pages" => self.max_directory_pages = parse_number(key, value)?,
+            "connect-timeout" => self.connect_timeout = parse_seconds(key, value)?,
+            "read-timeout" => self.read_timeout = parse_seconds(key, value)?,
+            "timeout" => self.timeout = parse_seconds(key, value)?,
+            "rate-limit" => self.rate_limit = Some(parse_rate(key, value)?),
+            "max-attempts" => {
+                self.retry.max_attempts = parse_number(key, value)?
+                    .try_into()
+                    .map_err(|_| format!("--{} is too large", key))?;
+                if self.retry.max_attempts == 0 {
+                    return Err(format!("--{} must be at least 1", key));
+                }
+            }
+            "backoff-base" => self.retry.backoff_base = parse_seconds(key, value)?,
+            "backoff-max" => self.retry.backoff_max = parse_seconds(key, value)?,
+            "retry-status" => {
+                self.retry.statuses = parse_list(value)
+                    .map(|status| {
+                        status
+                            .parse::<u16>()
+                            .ok()
+                            .filter(|status| (100..600).contains(status))
+                            .ok_or_else(|| {
+                                format!("--{} was given an invalid status {}", key, status)
+                            })
+                    })
+                    .collect::<Result<_, _>>()?
+            }
+            "retry-on" => {
+                self.retry.errors = parse_list(value)
+                    .map(RetryError::parse)
+                    .collect::<Result<_, _>>()?
+            }
+            "user-agent" => {
+                HeaderValue::from_str(value).map_err(|_| {
+                    format!("--{} contains characters not allowed in a header", key)
+                })?;
+                self.user_agent = value.to_string()
+            }
+            "header" => self.headers.push(parse_header(value)?),
+            "cookies" => self.cookie_files.push(PathBuf::from(value)),
+            "cookie" => {
+                if !value.contains('=') {
+                    return Err(format!(
+                        "--{} expects name=value but was given {}",
+                        key, value
+                    ));
+                }
+                self.cookies.push(value.to_string())
+            }
+            "basic-auth" | "digest-auth" | "bearer-token" => {
+                self.credentials.push(Credential::parse(key, value)?)
+            }
+            "login-url" => {
+                self.login_url = Some(
+                    Url::parse(value)
+                        .map_err(|e| format!("--{} was given an invalid url: {}", key, e))?,
+                )
+            }
+            "login-field" => {
+                let (name, field_value) = value
+                    .split_once('=')
+                    .ok_or_else(|| format!("--{} expects name=value", key))?;
+                self.login_fields
+                    .push((name.to_string(), Secret::new(field_value)))
+            }
+            "proxy" => self.proxy = Some(self.parse_proxy(key, value)?),
+            "http-proxy" => self.http_proxy = Some(self.parse_proxy(key, value)?),
+            "https-proxy" => self.https_proxy = Some(self.parse_proxy(key, value)?),
+            "no-proxy" => self.no_proxy = Some(value.to_string()),
+            "proxy-auth" => {
+                let (user, password) = value
+                    .split_once(':')
+                    .ok_or_else(|| format!("--{} expects user:password", key))?;
+                self.proxy_auth = Some((user.to_string(), Secret::new(password)))
+            }
+            "resolve" => self.resolve.push(parse_resolve(value)?),
+            "sitemap" => self.sitemap = parse_bool(key, value)?,
+            "check-external" => self.check_external = parse_bool(key, value)?,
+            "drop-non-http" => self.drop_non_http = parse_bool(key, value)?,
+            "skip-duplicates" => self.skip_duplicates = parse_bool(key, value)?,
+            "alert-broken-links" => self.alert_broken_links = parse_bool(key, value)?,
+            "alert-page-drop" => {
+                let percent = parse_number(key, value)?;
+                if !(1..=100).contains(&percent) {
+                    return Err(format!("--{} expects a percentage from 1 to 100", key));
+                }
+                self.alert_page_drop = Some(percent)
+            }
+            "webhook" => {
+                let webhook = Url::parse(value)
+                    .map_err(|e| format!("--{} was given an invalid url: {}", key, e))?;
+                if !["http", "https"].contains(&webhook.scheme()) {
+                    return Err(format!("--{} must be an http or https url", key));
+                }
+                self.webhooks.push(webhook)
+            }
+            "notify-command" => self.notify_commands.push(value.to_string()),
+            "priority" => {
+                self.priority = value.parse::<i32>().map_err(|_| {
+                    format!("--{} expects a whole number but was given {}", key, value)
+                })?
+            }
+            _ => return Err(format!("Unknown option --{}", key)),
+        }
+        Ok(())
+    }
+
+    //Credentials in a proxy url are moved to the proxy auth so the url can be shown safely
+    fn parse_proxy(&mut self, key: &str, value: &str) -> Result<Url, String> {
+        let mut proxy =
+            Url::parse(value).map_err(|_| format!("--{} was given an invalid url", key))?;
+        if !["http", "https", "socks5", "socks5h"].contains(&proxy.scheme()) {
+            return Err(format!(
+                "--{} must be an http, https, socks5 or socks5h url",
+                key
+            ));
+        }
+        if !proxy.username().is_empty() {
+            self.proxy_auth = Some((
+                proxy.username().to_string(),
+                Secret::new(proxy.password().unwrap_or_default()),
+            ));
+            let _ = proxy.set_username("");
+            let _ = proxy.set_password(None);
+        }
+        Ok(proxy)
+    }
+
+    pub fn from_lines<'a>(lines: impl Iterator<Item = &'a str>) -> Result<CrawlOptions, String> {
+        let mut options = CrawlOptions::default();
+        for line in lines.filter(|line| !line.trim().is_empty()) {
+            let (key, value) = line
+                .split_once('=')
+                .ok_or_else(|| "Malformed option, expected key=value".to_string())?;
+            options.set(key.trim(), value.trim())?;
+        }
+        Ok(options)
+    }
+}
+
+//Turns `--key value` pairs from the command line into the `key=value` lines sent to the daemon
+//Every option is applied to a default set of options first so mistakes are reported by the client
+pub fn parse_flags(args: &[String]) -> Result<Vec<String>, String> {
+    let mut options = CrawlOptions::default();
+    let mut lines = Vec::new();
+    let mut args = args.iter();
+    while let Some(flag) = args.next() {
+        let key = flag
+            .strip_prefix("--")
+            .ok_or_else(|| format!("Expected an option starting with -- but found {}", flag))?;
+        let switch = |key: &str| {
+            CRAWL_FLAGS
+                .iter()
+                .any(|(name, value, _)| *name == key && value.is_none())
+        };
+        //--no-sitemap and the like turn off a switch a config default turned on
+        let (key, value) = match key.strip_prefix("no-").filter(|name| switch(name)) {
+            Some(name) => (name, "false"),
+            None if switch(key) => (key, "true"),
+            None => (
+                key,
+                args.next()
+                    .ok_or_else(|| format!("No value was given for --{}", key))?
+                    .as_str(),
+            ),
+        };
+        let value = if PATH_OPTIONS.contains(&key) {
+            canonicalize(value)
+                .map_err(|e| format!("Couldn't find {} given to --{}: {}", value, key, e))?
+                .display()
+                .to_string()
+        } else {
+            value.to_string()
+        };
+        options.set(key, &value)?;
+        lines.push(format!("{}={}", key, value));
+    }
+    Ok(lines)
+}
+
+fn parse_bool(key: &str, value: &str) -> Result<bool, String> {
+    value
+        .parse::<bool>()
+        .map_err(|_| format!("--{} expects true or false but was given {}", key, value))
+}
+
+fn parse_number(key: &str, value: &str) -> Result<usize, String> {
+    value
+        .parse::<usize>()
+        .map_err(|_| format!("--{} expects a whole number but was given {}", key, value))
+}
+
+//Headers are given as `Name: value`
+fn parse_header(value: &str) -> Result<(String, String), String> {
+    let (name, header_value) = value
+        .split_once(':')
+        .ok_or_else(|| format!("--header expects Name: value but was given {}", value))?;
+    let (name, header_value) = (name.trim(), header_value.trim());
+    HeaderName::from_bytes(name.as_bytes())
+        .map_err(|_| format!("{} is not a valid header name", name))?;
+    HeaderValue::from_str(header_value)
+        .map_err(|_| format!("The value of header {} contains invalid characters", name))?;
+    Ok((name.to_string(), header_value.to_string()))
+}
+
+//Resolve overrides are given as `host:port:address` like curl, IPv6 addresses may be in brackets
+fn parse_resolve(value: &str) -> Result<(String, u16, IpAddr), String> {
+    let mut parts = value.splitn(3, ':');
+    let (host, port, address) = match (parts.next(), parts.next(), parts.next()) {
+        (Some(host), Some(port), Some(address)) if !host.is_empty() => (host, port, address),
+        _ => {
+            return Err(format!(
+                "--resolve expects host:port:address but was given {}",
+                value
+            ))
+        }
+    };
+    let port = port
+        .parse::<u16>()
+        .map_err(|_| format!("--resolve was given an invalid port {}", port))?;
+    let address = address
+        .trim_start_matches('[')
+        .trim_end_matches(']')
+        .parse::<IpAddr>()
+        .map_err(|_| format!("--resolve was given an invalid address {}", address))?;
+    Ok((host.to_lowercase(), port, address))
+}
+
+fn parse_seconds(key: &str, value: &str) -> Result<Duration, String> {
+    value
+        .parse::<f64>()
+        .ok()
+        .and_then(|seconds| Duration::try_from_secs_f64(seconds).ok())
+        .filter(|duration| !duration.is_zero())
+        .ok_or_else(|| {
+            format!(
+                "--{} expects a positive number of seconds but was given {}",
+                key, value
+            )
+        })
+}
+
+//Pages a second, which can be a fraction for pages further apart than a second
+pub fn parse_rate(key: &str, value: &str) -> Result<f64, String> {
+    value
+        .parse::<f64>()
+        .ok()
+        .filter(|rate| rate.is_finite() && *rate > 0.0)
+        .ok_or_else(|| {
+            format!(
+                "--{} expects a positive number of pages a second but was given {}",
+                key, value
+            )
+        })
+}
+
+//Comma separated values, an empty value gives an empty list
+fn parse_list(value: &str) -> impl Iterator<Item = &str> {
+    value
+        .split(',')
+        .map(|item| item.trim())
+        .filter(|item| !item.is_empty())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_flags, CrawlOptions};
+    use crate::retry::{RetryError, RetryPolicy};
+    use std::time::Duration;
+
+    #[test]
+    fn test_options_round_trip() {
+        let args: Vec<String> = [
+            "--max-depth",
+            "3",
+            "--max-links",
+            "20",
+            "--max-redirects",
+            "0",
+            "--read-timeout",
+            "2.5",
+            "--retry-status",
+            "503, 429",
+            "--retry-on",
+            "timeout",
+            "--header",
+            "Accept-Language: en",
+            "--header",
+            "X-Team:docs",
+            "--cookie",
+            "theme=dark",
+            "--resolve",
+            "www.example.com:443:[::1]",
+            "--rate-limit",
+            "0.5",
+            "--sitemap",
+        ]
+        .iter()
+        .map(|arg| arg.to_string())
+        .collect();
+        let lines = parse_flags(&args).expect("Couldn't parse the given flags");
+        let options = CrawlOptions::from_lines(lines.iter().map(|line| line.as_str()))
+            .expect("Couldn't parse the given options");
+
+        assert_eq!(
+            options,
+            CrawlOptions {
+                max_depth: Some(3),
+                max_links: 20,
+                max_redirects: 0,
+                read_timeout: Duration::from_millis(2500),
+                retry: RetryPolicy {
+                    statuses: vec![503, 429],
+                    errors: vec![RetryError::Timeout],
+                    ..RetryPolicy::default()
+                },
+                headers: vec![
+                    ("Accept-Language".to_string(), "en".to_string()),
+                    ("X-Team".to_string(), "docs".to_string())
+                ],
+                cookies: vec!["theme=dark".to_string()],
+                resolve: vec![("www.example.com".to_string(), 443, "::1".parse().unwrap())],
+                rate_limit: Some(0.5),
+                sitemap: true,
+                ..CrawlOptions::default()
+            }
+        );
+        assert!(parse_flags(&["--max-depth".to_string()]).is_err());
+        assert!(parse_flags(&["--max-depth".to_string(), "deep".to_string()]).is_err());
+        assert!(parse_flags(&["--colour".to_string(), "red".to_string()]).is_err());
+        assert!(parse_flags(&["--timeout".to_string(), "-1".to_string()]).is_err());
+        assert!(parse_flags(&["--retry-on".to_string(), "dns".to_string()]).is_err());
+        assert!(parse_flags(&["--rate-limit".to_string(), "0".to_string()]).is_err());
+        assert!(parse_flags(&["--header".to_string(), "X-Team docs".to_string()]).is_err());
+        assert!(parse_flags(&["--resolve".to_string(), "example.com:80".to_string()]).is_err());
+        assert!(
+            parse_flags(&["--cookies".to_string(), "/missing/cookies.txt".to_string()]).is_err()
+        );
+
+        //Switches can be turned off again, --no-proxy is still an option of its own
+        assert_eq!(
+            parse_flags(&[
+                "--no-sitemap".to_string(),
+                "--no-proxy".to_string(),
+                "localhost".to_string()
+            ]),
+            Ok(vec![
+                "sitemap=false".to_string(),
+                "no-proxy=localhost".to_string()
+            ])
+        );
+        assert!(parse_flags(&["--no-max-depth".to_string(), "2".to_string()]).is_err());
+    }
+}
@@ -0,0 +1,155 @@
+use std::cmp::Reverse;
+use std::time::SystemTime;
+
+use reqwest::Client;
+
+use tokio::task::JoinHandle;
+
+use url::Url;
+
+use crate::live::Live;
+use crate::node::{crawl_site, recrawl_site};
+use crate::options::CrawlOptions;
+use crate::report::SiteCrawl;
+use crate::tree::SiteTree;
+
+//Jobs running at once when the daemon isn't told otherwise
+pub const DEFAULT_MAX_JOBS: usize = 4;
+
+//What a job does once it leaves the queue, the client is built when it's queued so mistakes are reported straight away
+pub enum Task {
+    Crawl(Url, Client, Box<CrawlOptions>),
+    //The finished crawl is handed to the job and comes back updated once it's done
+    Recrawl(Box<SiteCrawl>, Client),
+}
+
+impl Task {
+    //The job follows the daemon's live settings as they change
+    pub fn spawn(self, live: Live) -> JoinHandle<SiteCrawl> {
+        match self {
+            Task::Crawl(url, client, options) => {
+                tokio::spawn(crawl_site(url, client, *options, live))
+            }
+            Task::Recrawl(crawl, client) => tokio::spawn(recrawl_site(*crawl, client, live)),
+        }
+    }
+}
+
+pub struct QueuedJob {
+    pub site: String,
+    pub task: Task,
+    pub priority: i32,
+    pub queued: SystemTime,
+    pub scheduled: bool,
+    //The site's last crawl, which the job's alert rules are checked against
+    pub previous: Option<SiteTree>,
+}
+
+//Jobs waiting for one of the daemon's running slots, the highest priority goes first and ties go in the order
+//they were queued
+#[derive(Default)]
+pub struct JobQueue {
+    pending: Vec<QueuedJob>,
+}
+
+impl JobQueue {
+    pub fn push(&mut self, job: QueuedJob) {
+        self.pending.push(job);
+    }
+
+    fn next_index(&self) -> Option<usize> {
+        self.pending
+            .iter()
+            .enumerate()
+            .min_by_key(|(index, job)| (Reverse(job.priority), *index))
+            .map(|(index, _)| index)
+    }
+
+    pub fn pop(&mut self) -> Option<QueuedJob> {
+        self.next_index().map(|index| self.pending.remove(index))
+    }
+
+    pub fn remove(&mut self, site: &str) -> Option<QueuedJob> {
+        let index = self.pending.iter().position(|job| job.site == site)?;
+        Some(self.pending.remove(index))
+    }
+
+    pub fn contains(&self, site: &str) -> bool {
+        self.pending.iter().any(|job| job.site == site)
+    }
+
+    pub fn len(&self) -> usize {
+        self.pending.len()
+    }
+
+    //The jobs in the order they'll leave the queue
+    pub fn ordered(&self) -> Vec<&QueuedJob> {
+        let mut ordered: Vec<(usize, &QueuedJob)> = self.pending.iter().enumerate().collect();
+        ordered.sort_by_key(|(index, job)| (Reverse(job.priority), *index));
+        ordered.into_iter().map(|(_, job)| job).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{JobQueue, QueuedJob, Task};
+    use reqwest::Client;
+    use std::time::SystemTime;
+    use url::Url;
+
+    fn job(site: &str, priority: i32) -> QueuedJob {
+        QueuedJob {
+            site: site.to_string(),
+            task: Task::Crawl(Url::parse(site).unwrap(), Client::new(), Box::default()),
+            priority,
+            queued: SystemTime::now(),
+            scheduled: false,
+            previous: None,
+        }
+    }
+
+    #[test]
+    fn test_job_queue() {
+        let mut queue = JobQueue::default();
+        queue.push(job("https://a.example/", 0));
+        queue.push(job("https://b.example/", 5));
+        queue.push(job("https://c.example/", -1));
+        queue.push(job("https://d.example/", 5));
+        queue.push(job("https://e.example/", 0));
+
+        let sites = |queue: &JobQueue| -> Vec<String> {
+            queue.ordered().iter().map(|job| job.site.clone()).collect()
+        };
+        assert_eq!(
+            sites(&queue),
+            vec![
+                "https://b.example/",
+                "https://d.example/",
+                "https://a.example/",
+                "https://e.example/",
+                "https://c.example/"
+            ]
+        );
+        assert!(queue.contains("https://c.example/"));
+        assert_eq!(
+            queue.remove("https://d.example/").unwrap().site,
+            "https://d.example/"
+        );
+        assert!(queue.remove("https://d.example/").is_none());
+
+        let mut popped = Vec::new();
+        while let Some(job) = queue.pop() {
+            popped.push(job.site);
+        }
+        assert_eq!(
+            popped,
+            vec![
+                "https://b.example/",
+                "https://a.example/",
+                "https://e.example/",
+                "https://c.example/"
+            ]
+        );
+        assert_eq!(queue.len(), 0);
+    }
+}
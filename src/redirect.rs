@@ -0,0 +1,221 @@
+use std::error::Error;
+use std::fmt;
+
+use reqwest::header::{HeaderMap, LOCATION};
+use reqwest::{Client, Method, Response};
+
+use serde::{Deserialize, Serialize};
+
+use tokio::time::timeout;
+
+use url::Url;
+
+use crate::auth::send_authorized;
+use crate::options::CrawlOptions;
+
+//One hop of a redirect chain, the status of the redirect and where it pointed
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+pub struct Redirect {
+    pub status: u16,
+    pub location: Url,
+}
+
+#[derive(Debug)]
+pub enum RedirectError {
+    Loop(Url),
+    TooMany(usize),
+}
+
+impl fmt::Display for RedirectError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RedirectError::Loop(url) => write!(f, "Redirect loop back to {}", url),
+            RedirectError::TooMany(max) => write!(f, "More than {} redirects", max),
+        }
+    }
+}
+
+impl Error for RedirectError {}
+
+//Sends the request for a url and follows redirects itself so every hop is recorded in redirects
+//Each hop goes through send_authorized so credentials are only sent to the hosts they're for,
+//the given headers are sent on every hop
+//A loop or a chain longer than the job allows is an error, with the hops up to that point kept
+//A hop to a url follow turns down is recorded but not requested, the redirect itself is given back
+pub async fn send_following(
+    client: &Client,
+    method: Method,
+    url: &Url,
+    headers: &HeaderMap,
+    options: &CrawlOptions,
+    follow: &(dyn Fn(&Url) -> bool + Sync),
+    redirects: &mut Vec<Redirect>,
+) -> Result<Response, Box<dyn Error>> {
+    redirects.clear();
+    let mut current = url.clone();
+    loop {
+        let response = timeout(
+            options.read_timeout,
+            send_authorized(client, method.clone(), &current, headers, options),
+        )
+        .await??;
+        if !response.status().is_redirection() {
+            return Ok(response);
+        }
+        //Responses like 304 which carry no Location are final
+        let location = match response
+            .headers()
+            .get(LOCATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| current.join(value).ok())
+        {
+            Some(location) => location,
+            None => return Ok(response),
+        };
+
+        let looped = location == *url || redirects.iter().any(|hop| hop.location == location);
+        redirects.push(Redirect {
+            status: response.status().as_u16(),
+            location: location.clone(),
+        });
+        if looped {
+            return Err(Box::new(RedirectError::Loop(location)));
+        }
+        if redirects.len() > options.max_redirects {
+            return Err(Box::new(RedirectError::TooMany(options.max_redirects)));
+        }
+        if !follow(&location) {
+            return Ok(response);
+        }
+        current = location;
+    }
+}
+
+//Whether the response is a redirect send_following stopped at rather than where the chain ended
+pub fn is_unfollowed(response: &Response) -> bool {
+    response.status().is_redirection() && response.headers().contains_key(LOCATION)
+}
+
+//Whether a recorded chain ended by pointing back at a url it had already visited
+pub fn is_loop(url: &Url, redirects: &[Redirect]) -> bool {
+    redirects.last().is_some_and(|last| {
+        last.location == *url
+            || redirects[..redirects.len() - 1]
+                .iter()
+                .any(|hop| hop.location == last.location)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{is_loop, is_unfollowed, send_following, Redirect};
+    use crate::options::CrawlOptions;
+    use crate::test_server::{http_response, serve};
+    use reqwest::header::HeaderMap;
+    use reqwest::redirect::Policy;
+    use reqwest::{Client, Method};
+
+    #[test]
+    fn test_send_following() {
+        let (url, _) = serve(vec![
+            (
+                "/old",
+                http_response("301 Moved Permanently", "Location: /older\r\n", ""),
+            ),
+            (
+                "/older",
+                http_response("302 Found", "Location: {origin}/new\r\n", ""),
+            ),
+            ("/new", http_response("200 OK", "", "")),
+            (
+                "/loop",
+                http_response("307 Temporary Redirect", "Location: /loop-back\r\n", ""),
+            ),
+            (
+                "/loop-back",
+                http_response("308 Permanent Redirect", "Location: /loop\r\n", ""),
+            ),
+        ]);
+        let client = Client::builder().redirect(Policy::none()).build().unwrap();
+        let runtime = tokio::runtime::Builder::new_multi_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        let mut options = CrawlOptions::default();
+        let mut redirects = Vec::new();
+
+        let response = runtime
+            .block_on(send_following(
+                &client,
+                Method::GET,
+                &url.join("/old").unwrap(),
+                &HeaderMap::new(),
+                &options,
+                &|_| true,
+                &mut redirects,
+            ))
+            .expect("The chain should be followed");
+        assert_eq!(response.status().as_u16(), 200);
+        assert_eq!(
+            redirects,
+            vec![
+                Redirect {
+                    status: 301,
+                    location: url.join("/older").unwrap()
+                },
+                Redirect {
+                    status: 302,
+                    location: url.join("/new").unwrap()
+                }
+            ]
+        );
+
+        let looping = url.join("/loop").unwrap();
+        let error = runtime
+            .block_on(send_following(
+                &client,
+                Method::GET,
+                &looping,
+                &HeaderMap::new(),
+                &options,
+                &|_| true,
+                &mut redirects,
+            ))
+            .expect_err("The loop should be stopped");
+        assert!(error.to_string().starts_with("Redirect loop"));
+        assert_eq!(redirects.len(), 2);
+        assert!(is_loop(&looping, &redirects));
+
+        options.max_redirects = 1;
+        let error = runtime
+            .block_on(send_following(
+                &client,
+                Method::GET,
+                &url.join("/old").unwrap(),
+                &HeaderMap::new(),
+                &options,
+                &|_| true,
+                &mut redirects,
+            ))
+            .expect_err("The chain is longer than allowed");
+        assert_eq!(error.to_string(), "More than 1 redirects");
+        assert!(!is_loop(&url.join("/old").unwrap(), &redirects));
+
+        //The hop that isn't followed is still recorded and its redirect comes back
+        options.max_redirects = 10;
+        let response = runtime
+            .block_on(send_following(
+                &client,
+                Method::GET,
+                &url.join("/old").unwrap(),
+                &HeaderMap::new(),
+                &options,
+                &|location| location.path() != "/new",
+                &mut redirects,
+            ))
+            .expect("The chain should stop without an error");
+        assert_eq!(response.status().as_u16(), 302);
+        assert!(is_unfollowed(&response));
+        assert_eq!(redirects.len(), 2);
+    }
+}
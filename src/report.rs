@@ -0,0 +1,410 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+
+use percent_encoding::percent_decode_str;
+
+use url::Url;
+
+use crate::fingerprint::is_near_duplicate;
+use crate::options::CrawlOptions;
+use crate::redirect::{is_loop, Redirect};
+use crate::sitemap::page_key;
+use crate::trap::Trap;
+use crate::tree::{LinkKind, SiteTree, SubSites};
+
+//A finished crawl, the tree of links along with anything found out about the site as a whole
+//The options are kept so the site can be recrawled the same way
+#[derive(Debug, PartialEq, Clone)]
+pub struct SiteCrawl {
+    pub tree: SiteTree,
+    pub report: CrawlReport,
+    pub options: CrawlOptions,
+}
+
+impl fmt::Display for SiteCrawl {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}{}", self.tree, self.report)
+    }
+}
+
+//Sections are left out when the job didn't ask for them or they have nothing to show
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct CrawlReport {
+    pub recrawl: Option<RecrawlReport>,
+    pub sitemap: Option<SitemapReport>,
+    //Pages reached through more than one redirect or a redirect loop, with every hop
+    pub redirect_chains: Vec<(Url, Vec<Redirect>)>,
+    pub external_domains: Vec<ExternalDomain>,
+    pub non_http: NonHttpLinks,
+    //In-site links whose fragment names no element on the page they point to, with the page they're on
+    pub broken_anchors: Vec<(Url, Url)>,
+    //Patterns of urls the frontier stopped following, in the order they were first seen
+    pub traps: Vec<Trap>,
+    //Groups of pages with identical bodies, and groups whose text only differs slightly
+    pub duplicates: Vec<Vec<Url>>,
+    pub near_duplicates: Vec<Vec<Url>>,
+}
+
+//How many pages a recrawl found unchanged and how many it had to read again
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct RecrawlReport {
+    pub unchanged: usize,
+    pub refetched: usize,
+}
+
+pub fn recrawl_report(tree: &SiteTree) -> RecrawlReport {
+    let mut report = RecrawlReport::default();
+    tree.visit(&mut |node| {
+        if node.page.kind != LinkKind::Internal {
+            return;
+        }
+        if node.page.not_modified {
+            report.unchanged += 1;
+        } else if node.page.status.is_some() {
+            report.refetched += 1;
+        }
+    });
+    report
+}
+
+//Groups fetched pages by their fingerprints, a near-duplicate group joins every exact group
+//whose text is close to another in it so the same page is never split between near groups
+pub fn duplicate_pages(tree: &SiteTree) -> (Vec<Vec<Url>>, Vec<Vec<Url>>) {
+    let mut contents: Vec<(&str, u64, Vec<Url>)> = Vec::new();
+    tree.visit(&mut |node| {
+        if let Some(fingerprint) = &node.page.fingerprint {
+            match contents
+                .iter_mut()
+                .find(|(digest, _, _)| *digest == fingerprint.digest)
+            {
+                Some((_, _, urls)) => urls.push(node.current_site.clone()),
+                None => contents.push((
+                    &fingerprint.digest,
+                    fingerprint.simhash,
+                    vec![node.current_site.clone()],
+                )),
+            }
+        }
+    });
+    let duplicates = contents
+        .iter()
+        .filter(|(_, _, urls)| urls.len() > 1)
+        .map(|(_, _, urls)| urls.clone())
+        .collect();
+
+    //Each content starts in its own group and groups are merged whenever two of their hashes are close
+    let mut group: Vec<usize> = (0..contents.len()).collect();
+    for first in 0..contents.len() {
+        //Pages without any text all hash to 0 and say nothing about each other
+        if contents[first].1 == 0 {
+            continue;
+        }
+        for second in first + 1..contents.len() {
+            if contents[second].1 != 0 && is_near_duplicate(contents[first].1, contents[second].1) {
+                let (from, to) = (group[second], group[first]);
+                for current in group.iter_mut() {
+                    if *current == from {
+                        *current = to;
+                    }
+                }
+            }
+        }
+    }
+    let mut near_duplicates = Vec::new();
+    for first in 0..contents.len() {
+        let members: Vec<usize> = (0..contents.len())
+            .filter(|index| group[*index] == first)
+            .collect();
+        if members.len() > 1 {
+            near_duplicates.push(
+                members
+                    .iter()
+                    .flat_map(|index| contents[*index].2.iter().cloned())
+                    .collect(),
+            );
+        }
+    }
+    (duplicates, near_duplicates)
+}
+
+pub fn broken_anchors(tree: &SiteTree) -> Vec<(Url, Url)> {
+    //Anchors are found under the url a page was linked by and wherever its redirects ended
+    let mut anchors: HashMap<String, &HashSet<String>> = HashMap::new();
+    tree.visit(&mut |node| {
+        if let Some(page_anchors) = &node.page.anchors {
+            anchors.insert(page_key(&node.current_site), page_anchors);
+            if let Some(redirect) = node.page.redirects.last() {
+                anchors.insert(page_key(&redirect.location), page_anchors);
+            }
+        }
+    });
+
+    let mut broken = Vec::new();
+    tree.visit(&mut |node| {
+        if let SubSites::List(sub_sites) = &node.sub_sites {
+            for sub_site in sub_sites.iter() {
+                let link = &sub_site.current_site;
+                //An empty fragment and #top always go to the top of the page
+                let fragment = match link.fragment() {
+                    Some(fragment) if !fragment.is_empty() && fragment != "top" => {
+                        percent_decode_str(fragment).decode_utf8_lossy()
+                    }
+                    _ => continue,
+                };
+                if sub_site.page.kind == LinkKind::Internal
+                    && anchors
+                        .get(&page_key(link))
+                        .is_some_and(|page_anchors| !page_anchors.contains(fragment.as_ref()))
+                {
+                    broken.push((node.current_site.clone(), link.clone()));
+                }
+            }
+        }
+    });
+    broken
+}
+
+//Distinct links the crawler doesn't fetch because of their scheme
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct NonHttpLinks {
+    pub emails: Vec<String>,
+    pub phones: Vec<String>,
+    pub scripts: usize,
+    pub data: usize,
+    //ftp and any other scheme
+    pub other: Vec<Url>,
+}
+
+impl NonHttpLinks {
+    pub fn is_empty(&self) -> bool {
+        self.emails.is_empty()
+            && self.phones.is_empty()
+            && self.scripts == 0
+            && self.data == 0
+            && self.other.is_empty()
+    }
+}
+
+//Email addresses and phone numbers are collected from the links so it's clear what the site exposes
+pub fn non_http_links(tree: &SiteTree) -> NonHttpLinks {
+    let mut links = NonHttpLinks::default();
+    let mut seen: HashSet<String> = HashSet::new();
+    tree.visit(&mut |node| {
+        let url = &node.current_site;
+        if node.page.kind.is_http() || !seen.insert(url.to_string()) {
+            return;
+        }
+        //A mailto link can hold several addresses separated by commas, anything after ? is ignored
+        let target = percent_decode_str(url.path()).decode_utf8_lossy();
+        match node.page.kind {
+            LinkKind::Email => {
+                for address in target.split(',').map(|address| address.trim()) {
+                    if !address.is_empty() && !links.emails.iter().any(|email| email == address) {
+                        links.emails.push(address.to_string());
+                    }
+                }
+            }
+            LinkKind::Phone => {
+                if !links.phones.iter().any(|phone| *phone == target) {
+                    links.phones.push(target.to_string());
+                }
+            }
+            LinkKind::Script => links.scripts += 1,
+            LinkKind::Data => links.data += 1,
+            _ => links.other.push(url.clone()),
+        }
+    });
+    links.emails.sort();
+    links.phones.sort();
+    links
+}
+
+//Links leaving the site grouped by the host they point to
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct ExternalDomain {
+    pub host: String,
+    pub links: usize,
+    pub urls: usize,
+    //Checked urls which failed or responded with an error status, along with what went wrong
+    pub broken: Vec<(Url, String)>,
+}
+
+pub fn external_domains(tree: &SiteTree) -> Vec<ExternalDomain> {
+    let mut domains: Vec<ExternalDomain> = Vec::new();
+    let mut seen: HashSet<String> = HashSet::new();
+    tree.visit(&mut |node| {
+        if node.page.kind != LinkKind::External {
+            return;
+        }
+        let url = &node.current_site;
+        let host = url.host_str().unwrap_or(url.scheme()).to_string();
+        let index = match domains.iter().position(|domain| domain.host == host) {
+            Some(index) => index,
+            None => {
+                domains.push(ExternalDomain {
+                    host,
+                    ..ExternalDomain::default()
+                });
+                domains.len() - 1
+            }
+        };
+        let domain = &mut domains[index];
+        domain.links += 1;
+        if !seen.insert(page_key(url)) {
+            return;
+        }
+        domain.urls += 1;
+        if let Some(error) = &node.page.error {
+            domain.broken.push((url.clone(), error.clone()));
+        } else if let Some(status) = node.page.status.filter(|status| *status >= 400) {
+            domain.broken.push((url.clone(), status.to_string()));
+        }
+    });
+    domains.sort_by(|a, b| b.links.cmp(&a.links).then_with(|| a.host.cmp(&b.host)));
+    domains
+}
+
+pub fn redirect_chains(tree: &SiteTree) -> Vec<(Url, Vec<Redirect>)> {
+    let mut redirect_chains = Vec::new();
+    tree.visit(&mut |node| {
+        let redirects = &node.page.redirects;
+        if redirects.len() > 1 || is_loop(&node.current_site, redirects) {
+            redirect_chains.push((node.current_site.clone(), redirects.clone()));
+        }
+    });
+    redirect_chains
+}
+
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct SitemapReport {
+    //Sitemap files which were read, including those listed in sitemap indexes
+    pub sitemaps: Vec<Url>,
+    pub listed: usize,
+    //Listed in the sitemap but never linked from a crawled page
+    pub orphans: Vec<Url>,
+    //Crawled html pages the sitemap doesn't list
+    pub unlisted: Vec<Url>,
+}
+
+impl fmt::Display for CrawlReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(recrawl) = &self.recrawl {
+            writeln!(
+                f,
+                "Recrawl: {} pages unchanged, {} read again",
+                recrawl.unchanged, recrawl.refetched
+            )?;
+        }
+        if !self.redirect_chains.is_empty() {
+            writeln!(f, "Redirect chains ({}):", self.redirect_chains.len())?;
+            for (url, redirects) in self.redirect_chains.iter() {
+                if is_loop(url, redirects) {
+                    writeln!(f, "  {} (loop)", url)?;
+                } else {
+                    writeln!(f, "  {}", url)?;
+                }
+                for redirect in redirects.iter() {
+                    writeln!(f, "    {} -> {}", redirect.status, redirect.location)?;
+                }
+            }
+        }
+        if !self.external_domains.is_empty() {
+            writeln!(f, "External links ({} hosts):", self.external_domains.len())?;
+            for domain in self.external_domains.iter() {
+                write!(
+                    f,
+                    "  {}: {} links to {} urls",
+                    domain.host, domain.links, domain.urls
+                )?;
+                if domain.broken.is_empty() {
+                    writeln!(f)?;
+                } else {
+                    writeln!(f, ", {} broken", domain.broken.len())?;
+                }
+                for (url, problem) in domain.broken.iter() {
+                    writeln!(f, "    {} {}", problem, url)?;
+                }
+            }
+        }
+        if !self.traps.is_empty() {
+            writeln!(f, "Suspected crawl traps ({}):", self.traps.len())?;
+            for trap in self.traps.iter() {
+                writeln!(
+                    f,
+                    "  {} {}: {} urls not fetched, first {}",
+                    trap.kind, trap.pattern, trap.stopped, trap.example
+                )?;
+            }
+        }
+        write_groups(f, "Duplicate pages", &self.duplicates)?;
+        write_groups(f, "Near-duplicate pages", &self.near_duplicates)?;
+        if !self.broken_anchors.is_empty() {
+            writeln!(
+                f,
+                "Links to missing anchors ({}):",
+                self.broken_anchors.len()
+            )?;
+            for (page, link) in self.broken_anchors.iter() {
+                writeln!(f, "  {} on {}", link, page)?;
+            }
+        }
+        if !self.non_http.is_empty() {
+            let non_http = &self.non_http;
+            writeln!(f, "Non-HTTP links:")?;
+            write_list(f, "Email addresses", &non_http.emails)?;
+            write_list(f, "Phone numbers", &non_http.phones)?;
+            write_list(f, "Other schemes", &non_http.other)?;
+            writeln!(f, "  javascript: links: {}", non_http.scripts)?;
+            writeln!(f, "  data: links: {}", non_http.data)?;
+        }
+        if let Some(sitemap) = &self.sitemap {
+            writeln!(
+                f,
+                "Sitemap: {} urls listed in {} files",
+                sitemap.listed,
+                sitemap.sitemaps.len()
+            )?;
+            write_urls(
+                f,
+                "Orphan pages listed in the sitemap but never linked",
+                &sitemap.orphans,
+            )?;
+            write_urls(f, "Pages missing from the sitemap", &sitemap.unlisted)?;
+        }
+        Ok(())
+    }
+}
+
+//Each group starts with the first page found and the others are listed under it
+fn write_groups(f: &mut fmt::Formatter<'_>, heading: &str, groups: &[Vec<Url>]) -> fmt::Result {
+    if !groups.is_empty() {
+        writeln!(f, "{} ({} groups):", heading, groups.len())?;
+        for group in groups.iter() {
+            writeln!(f, "  {}", group[0])?;
+            for url in group[1..].iter() {
+                writeln!(f, "    {}", url)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+fn write_list<T: fmt::Display>(
+    f: &mut fmt::Formatter<'_>,
+    heading: &str,
+    items: &[T],
+) -> fmt::Result {
+    writeln!(f, "  {} ({}):", heading, items.len())?;
+    for item in items.iter() {
+        writeln!(f, "    {}", item)?;
+    }
+    Ok(())
+}
+
+fn write_urls(f: &mut fmt::Formatter<'_>, heading: &str, urls: &[Url]) -> fmt::Result {
+    writeln!(f, "{} ({}):", heading, urls.len())?;
+    for url in urls.iter() {
+        writeln!(f, "  {}", url)?;
+    }
+    Ok(())
+}
@@ -0,0 +1,125 @@
+use std::collections::hash_map::RandomState;
+use std::error::Error;
+use std::fmt;
+use std::hash::{BuildHasher, Hasher};
+use std::time::Duration;
+
+use tokio::time::error::Elapsed;
+
+//Kinds of request failure a job can choose to retry
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum RetryError {
+    Connect,
+    Timeout,
+    Body,
+}
+
+impl RetryError {
+    pub fn parse(name: &str) -> Result<RetryError, String> {
+        match name {
+            "connect" => Ok(RetryError::Connect),
+            "timeout" => Ok(RetryError::Timeout),
+            "body" => Ok(RetryError::Body),
+            _ => Err(format!(
+                "Unknown error kind {}, expected connect, timeout or body",
+                name
+            )),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct RetryPolicy {
+    //Total number of requests made for a page, including the first
+    pub max_attempts: u32,
+    pub backoff_base: Duration,
+    pub backoff_max: Duration,
+    pub statuses: Vec<u16>,
+    pub errors: Vec<RetryError>,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_attempts: 3,
+            backoff_base: Duration::from_millis(500),
+            backoff_max: Duration::from_secs(30),
+            statuses: vec![429, 500, 502, 503, 504],
+            errors: vec![RetryError::Connect, RetryError::Timeout, RetryError::Body],
+        }
+    }
+}
+
+impl RetryPolicy {
+    pub fn should_retry(&self, error: &(dyn Error + 'static)) -> bool {
+        if let Some(StatusError(status)) = error.downcast_ref::<StatusError>() {
+            self.statuses.contains(status)
+        } else if error.is::<Elapsed>() {
+            self.errors.contains(&RetryError::Timeout)
+        } else if let Some(error) = error.downcast_ref::<reqwest::Error>() {
+            //Connect timeouts report as both so either kind being allowed is enough
+            (error.is_timeout() && self.errors.contains(&RetryError::Timeout))
+                || (error.is_connect() && self.errors.contains(&RetryError::Connect))
+                || ((error.is_body() || error.is_decode())
+                    && self.errors.contains(&RetryError::Body))
+        } else {
+            false
+        }
+    }
+
+    //Exponential backoff capped at the maximum, with the delay picked at random from its upper half
+    //so pages which failed together don't all retry at the same moment
+    pub fn backoff(&self, retry: u32) -> Duration {
+        let exponent = retry.saturating_sub(1).min(31);
+        let delay = self
+            .backoff_base
+            .saturating_mul(1 << exponent)
+            .min(self.backoff_max);
+        let jitter = RandomState::new().build_hasher().finish() as f64 / u64::MAX as f64;
+        delay.mul_f64(0.5 + jitter / 2.0)
+    }
+}
+
+//Returned in place of a response whose status the retry policy may want to retry
+#[derive(Debug)]
+pub struct StatusError(pub u16);
+
+impl fmt::Display for StatusError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "The server responded with status {}", self.0)
+    }
+}
+
+impl Error for StatusError {}
+
+#[cfg(test)]
+mod tests {
+    use super::{RetryError, RetryPolicy, StatusError};
+    use std::time::Duration;
+
+    #[test]
+    fn test_backoff() {
+        let policy = RetryPolicy {
+            backoff_base: Duration::from_secs(1),
+            backoff_max: Duration::from_secs(5),
+            ..RetryPolicy::default()
+        };
+        for (retry, full_delay) in [(1, 1), (2, 2), (3, 4), (4, 5), (40, 5)] {
+            let delay = policy.backoff(retry);
+            let full_delay = Duration::from_secs(full_delay);
+            assert!(delay >= full_delay / 2 && delay <= full_delay);
+        }
+    }
+
+    #[test]
+    fn test_should_retry() {
+        let policy = RetryPolicy {
+            statuses: vec![503],
+            errors: vec![RetryError::Connect],
+            ..RetryPolicy::default()
+        };
+        assert!(policy.should_retry(&StatusError(503)));
+        assert!(!policy.should_retry(&StatusError(404)));
+        assert!(!policy.should_retry(&std::fmt::Error));
+    }
+}
@@ -0,0 +1,533 @@
+use std::fmt;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+
+use crate::config::{client_default_lines, take_client_defaults};
+use crate::options::CrawlOptions;
+use crate::report::SiteCrawl;
+use crate::tree::{LinkKind, SiteTree};
+
+const MINUTE: u64 = 60;
+const DAY: u64 = 24 * 60 * MINUTE;
+//Every cron expression which can match does so within this long, the 29th of February can be 8 years apart
+const CRON_SEARCH_DAYS: u64 = 366 * 9;
+
+//Runs kept for a site when its schedule doesn't say otherwise
+pub const DEFAULT_KEEP_RUNS: usize = 10;
+
+//When a scheduled site is crawled, either a fixed time apart or whenever a cron expression matches
+#[derive(Debug, PartialEq, Clone)]
+pub enum Timing {
+    Every(Duration),
+    Cron(Cron),
+}
+
+impl Timing {
+    pub fn next_after(&self, after: SystemTime) -> SystemTime {
+        match self {
+            Timing::Every(interval) => after + *interval,
+            //parse_cron refuses expressions which never match
+            Timing::Cron(cron) => cron
+                .next_after(after)
+                .unwrap_or(after + Duration::from_secs(CRON_SEARCH_DAYS * DAY)),
+        }
+    }
+}
+
+impl fmt::Display for Timing {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Timing::Every(interval) => write!(f, "every {}s", interval.as_secs()),
+            Timing::Cron(cron) => write!(f, "cron {}", cron.expression),
+        }
+    }
+}
+
+//A standard five field cron expression of minute, hour, day of month, month and day of week, read in UTC
+//Fields take numbers, *, ranges like 1-5, lists like 1,15 and steps like */10 but not names
+#[derive(Debug, PartialEq, Clone)]
+pub struct Cron {
+    expression: String,
+    minutes: u64,
+    hours: u64,
+    days: u64,
+    months: u64,
+    weekdays: u64,
+    //When both day fields are restricted a day matching either is enough, as in cron
+    any_day: bool,
+    any_weekday: bool,
+}
+
+pub fn parse_cron(expression: &str) -> Result<Cron, String> {
+    let fields: Vec<&str> = expression.split_whitespace().collect();
+    if fields.len() != 5 {
+        return Err(format!(
+            "The cron expression {} should have 5 fields but has {}",
+            expression,
+            fields.len()
+        ));
+    }
+    let mut weekdays = parse_cron_field(fields[4], 0, 7)?;
+    //Both 0 and 7 are Sunday
+    if weekdays & (1 << 7) != 0 {
+        weekdays = (weekdays | 1) & !(1 << 7);
+    }
+    let cron = Cron {
+        expression: fields.join(" "),
+        minutes: parse_cron_field(fields[0], 0, 59)?,
+        hours: parse_cron_field(fields[1], 0, 23)?,
+        days: parse_cron_field(fields[2], 1, 31)?,
+        months: parse_cron_field(fields[3], 1, 12)?,
+        weekdays,
+        any_day: fields[2] == "*",
+        any_weekday: fields[4] == "*",
+    };
+    match cron.next_after(UNIX_EPOCH) {
+        Some(_) => Ok(cron),
+        None => Err(format!("The cron expression {} never matches", expression)),
+    }
+}
+
+//The values a field allows as bits of a mask
+fn parse_cron_field(field: &str, min: u32, max: u32) -> Result<u64, String> {
+    let invalid = || format!("Invalid cron field {}", field);
+    let number = |value: &str| {
+        value
+            .parse::<u32>()
+            .ok()
+            .filter(|value| (min..=max).contains(value))
+            .ok_or_else(invalid)
+    };
+    let mut mask = 0;
+    for part in field.split(',') {
+        let (range, step) = match part.split_once('/') {
+            Some((range, step)) => (
+                range,
+                Some(
+                    step.parse::<u32>()
+                        .ok()
+                        .filter(|step| *step > 0)
+                        .ok_or_else(invalid)?,
+                ),
+            ),
+            None => (part, None),
+        };
+        let (start, end) = match range.split_once('-') {
+            _ if range == "*" => (min, max),
+            Some((start, end)) => (number(start)?, number(end)?),
+            //A single value with a step runs to the end of the field
+            None if step.is_some() => (number(range)?, max),
+            None => (number(range)?, number(range)?),
+        };
+        if start > end {
+            return Err(invalid());
+        }
+        for value in (start..=end).step_by(step.unwrap_or(1) as usize) {
+            mask |= 1 << value;
+        }
+    }
+    Ok(mask)
+}
+
+impl Cron {
+    //The first matching minute after the given time
+    fn next_after(&self, after: SystemTime) -> Option<SystemTime> {
+        let seconds = after
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        let mut minute = seconds / MINUTE + 1;
+        let last_day = minute * MINUTE / DAY + CRON_SEARCH_DAYS;
+        while minute * MINUTE / DAY <= last_day {
+            let days = minute * MINUTE / DAY;
+            let (_, month, day) = civil_from_days(days);
+            //1970-01-01 was a Thursday
+            let weekday = (days + 4) % 7;
+            let day_matches = match (self.any_day, self.any_weekday) {
+                (false, false) => has(self.days, day) || has(self.weekdays, weekday),
+                _ => has(self.days, day) && has(self.weekdays, weekday),
+            };
+            if !has(self.months, month) || !day_matches {
+                minute = (days + 1) * DAY / MINUTE;
+                continue;
+            }
+            let minute_of_day = minute % (DAY / MINUTE);
+            if has(self.hours, minute_of_day / 60) && has(self.minutes, minute_of_day % 60) {
+                return Some(UNIX_EPOCH + Duration::from_secs(minute * MINUTE));
+            }
+            minute += 1;
+        }
+        None
+    }
+}
+
+fn has(mask: u64, value: u64) -> bool {
+    mask & (1 << value) != 0
+}
+
+//Year, month and day of a count of days since 1970-01-01
+fn civil_from_days(days: u64) -> (u64, u64, u64) {
+    let days = days + 719468;
+    let era = days / 146097;
+    let day_of_era = days % 146097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 {
+        month_index + 3
+    } else {
+        month_index - 9
+    };
+    let year = year_of_era + era * 400 + u64::from(month <= 2);
+    (year, month, day)
+}
+
+pub fn format_time(time: SystemTime) -> String {
+    let seconds = time
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    let (year, month, day) = civil_from_days(seconds / DAY);
+    let time_of_day = seconds % DAY;
+    format!(
+        "{:04}-{:02}-{:02} {:02}:{:02}:{:02} UTC",
+        year,
+        month,
+        day,
+        time_of_day / 3600,
+        time_of_day / 60 % 60,
+        time_of_day % 60
+    )
+}
+
+//Lengths like 90s, 15m, 6h or 7d, a bare number is seconds
+pub fn parse_duration(value: &str) -> Result<Duration, String> {
+    let (number, unit) = match value.trim().char_indices().last() {
+        Some((index, unit)) if unit.is_ascii_alphabetic() => (&value.trim()[..index], unit),
+        _ => (value.trim(), 's'),
+    };
+    let scale = match unit {
+        's' => 1,
+        'm' => MINUTE,
+        'h' => 60 * MINUTE,
+        'd' => DAY,
+        _ => return Err(format!("Unknown unit in the length {}", value)),
+    };
+    number
+        .parse::<u64>()
+        .ok()
+        .filter(|number| *number > 0)
+        .and_then(|number| number.checked_mul(scale))
+        .map(Duration::from_secs)
+        .ok_or_else(|| format!("Invalid length {}", value))
+}
+
+//A site the daemon crawls on its own, sent by the client as `every=`, `cron=`, `keep-runs=` and
+//`keep-for=` lines alongside the crawl options
+#[derive(Debug, PartialEq, Clone)]
+pub struct Schedule {
+    pub timing: Timing,
+    pub options: CrawlOptions,
+    //The site's own option lines, kept so its options can be rebuilt when the daemon's defaults change
+    pub option_lines: Vec<String>,
+    //The crawl defaults of the config the client was given, used in place of the daemon's
+    pub defaults: Option<Vec<String>>,
+    //At most this many runs are kept in the site's history, and none older than keep_for. Without its own
+    //count the daemon's keep-runs setting is used, so a reload changes it
+    pub keep_runs: Option<usize>,
+    pub keep_for: Option<Duration>,
+    pub next: SystemTime,
+}
+
+impl Schedule {
+    pub fn from_lines<'a>(
+        lines: impl Iterator<Item = &'a str>,
+        now: SystemTime,
+    ) -> Result<Schedule, String> {
+        let mut timing = None;
+        let mut keep_runs = None;
+        let mut keep_for = None;
+        let mut option_lines = Vec::new();
+        let (defaults, lines) = take_client_defaults(lines);
+        for line in lines {
+            match line.split_once('=') {
+                Some(("every", value)) => timing = Some(Timing::Every(parse_duration(value)?)),
+                Some(("cron", value)) => timing = Some(Timing::Cron(parse_cron(value)?)),
+                Some(("keep-runs", value)) => {
+                    keep_runs = Some(
+                        value
+                            .parse::<usize>()
+                            .ok()
+                            .filter(|runs| *runs > 0)
+                            .ok_or_else(|| {
+                                format!("--keep-runs expects at least 1 but was given {}", value)
+                            })?,
+                    )
+                }
+                Some(("keep-for", value)) => keep_for = Some(parse_duration(value)?),
+                _ => option_lines.push(line),
+            }
+        }
+        let timing = timing.ok_or("A schedule needs either --every or --cron")?;
+        Ok(Schedule {
+            next: timing.next_after(now),
+            timing,
+            options: CrawlOptions::from_lines(option_lines.iter().copied())?,
+            option_lines: option_lines.iter().map(|line| line.to_string()).collect(),
+            defaults,
+            keep_runs,
+            keep_for,
+        })
+    }
+
+    //The lines from_lines would make this schedule from again, without the daemon's defaults or when it's next due
+    pub fn lines(&self) -> Vec<String> {
+        let mut lines = vec![match &self.timing {
+            Timing::Every(interval) => format!("every={}", interval.as_secs()),
+            Timing::Cron(cron) => format!("cron={}", cron.expression),
+        }];
+        lines.extend(
+            self.keep_runs
+                .map(|keep_runs| format!("keep-runs={}", keep_runs)),
+        );
+        lines.extend(
+            self.keep_for
+                .map(|keep_for| format!("keep-for={}", keep_for.as_secs())),
+        );
+        lines.extend(
+            self.defaults
+                .as_deref()
+                .map(client_default_lines)
+                .unwrap_or_default(),
+        );
+        lines.extend(self.option_lines.iter().cloned());
+        lines
+    }
+
+    //Rebuilds the options with the default lines applied before the site's own, a schedule made with the
+    //client's defaults keeps them
+    pub fn apply_defaults(&mut self, defaults: &[String]) -> Result<(), String> {
+        let defaults = self.defaults.as_deref().unwrap_or(defaults);
+        self.options = CrawlOptions::from_lines(
+            defaults
+                .iter()
+                .chain(self.option_lines.iter())
+                .map(|line| line.as_str()),
+        )?;
+        Ok(())
+    }
+}
+
+//One finished crawl of a site, kept in its history
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+pub struct Run {
+    pub started: SystemTime,
+    pub finished: SystemTime,
+    pub scheduled: bool,
+    //Pages on the site which responded, and how many pages responded with an error status or couldn't be
+    //fetched at all
+    pub pages: usize,
+    pub errors: usize,
+}
+
+impl Run {
+    pub fn new(crawl: &SiteCrawl, started: SystemTime, scheduled: bool) -> Run {
+        let (pages, errors) = fetched_pages(&crawl.tree);
+        Run {
+            started,
+            finished: SystemTime::now(),
+            scheduled,
+            pages,
+            errors,
+        }
+    }
+}
+
+//Pages on the site which responded, and how many pages responded with an error status or couldn't be fetched
+//at all, so a page which stops answering is both one page fewer and one more error
+pub fn fetched_pages(tree: &SiteTree) -> (usize, usize) {
+    let (mut pages, mut errors) = (0, 0);
+    tree.visit(&mut |node| {
+        if node.page.kind != LinkKind::Internal {
+            return;
+        }
+        if node.page.status.is_some() {
+            pages += 1;
+        }
+        if node.page.status.is_some_and(|status| status >= 400) || node.page.error.is_some() {
+            errors += 1;
+        }
+    });
+    (pages, errors)
+}
+
+impl fmt::Display for Run {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} {} run, {} pages, {} errors, took {}s",
+            format_time(self.started),
+            if self.scheduled {
+                "scheduled"
+            } else {
+                "manual"
+            },
+            self.pages,
+            self.errors,
+            self.finished
+                .duration_since(self.started)
+                .unwrap_or_default()
+                .as_secs()
+        )
+    }
+}
+
+//A finished crawl kept alongside the site's other results, numbered in the order they finished
+#[derive(Debug, PartialEq, Clone)]
+pub struct StoredCrawl {
+    pub id: usize,
+    pub finished: SystemTime,
+    pub crawl: SiteCrawl,
+}
+
+//Runs and results are both kept within a schedule's limits
+pub trait Finished {
+    fn finished(&self) -> SystemTime;
+}
+
+impl Finished for Run {
+    fn finished(&self) -> SystemTime {
+        self.finished
+    }
+}
+
+impl Finished for StoredCrawl {
+    fn finished(&self) -> SystemTime {
+        self.finished
+    }
+}
+
+//Drops the oldest runs past the count or age limits, runs are kept oldest first
+pub fn prune_runs<T: Finished>(
+    runs: &mut Vec<T>,
+    keep_runs: usize,
+    keep_for: Option<Duration>,
+    now: SystemTime,
+) {
+    if let Some(keep_for) = keep_for {
+        runs.retain(|run| now.duration_since(run.finished()).unwrap_or_default() <= keep_for);
+    }
+    if runs.len() > keep_runs {
+        runs.drain(..runs.len() - keep_runs);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{format_time, parse_cron, parse_duration, prune_runs, Run, Schedule, Timing};
+    use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+    fn at(seconds: u64) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(seconds)
+    }
+
+    #[test]
+    fn test_cron() {
+        //2024-02-28 23:59:30 UTC, a Wednesday
+        let now = at(1709164770);
+        assert_eq!(format_time(now), "2024-02-28 23:59:30 UTC");
+        let next = |expression: &str| {
+            let timing = Timing::Cron(parse_cron(expression).unwrap());
+            format_time(timing.next_after(now))
+        };
+
+        assert_eq!(next("* * * * *"), "2024-02-29 00:00:00 UTC");
+        assert_eq!(next("30 3 * * *"), "2024-02-29 03:30:00 UTC");
+        assert_eq!(next("*/20 9-17 * * 1-5"), "2024-02-29 09:00:00 UTC");
+        assert_eq!(next("0 0 1 * *"), "2024-03-01 00:00:00 UTC");
+        assert_eq!(next("0 12 * * 0"), "2024-03-03 12:00:00 UTC");
+        assert_eq!(next("0 12 * * 7"), "2024-03-03 12:00:00 UTC");
+        //With both day fields set either one matching is enough
+        assert_eq!(next("0 6 15 * 5"), "2024-03-01 06:00:00 UTC");
+        assert_eq!(next("0 0 29 2 *"), "2024-02-29 00:00:00 UTC");
+        assert_eq!(next("15,45 4 1 1 *"), "2025-01-01 04:15:00 UTC");
+
+        assert!(parse_cron("* * * *").is_err());
+        assert!(parse_cron("60 * * * *").is_err());
+        assert!(parse_cron("5-1 * * * *").is_err());
+        assert!(parse_cron("*/0 * * * *").is_err());
+        assert!(parse_cron("0 0 * jan *").is_err());
+        assert!(parse_cron("0 0 31 2 *").is_err());
+    }
+
+    #[test]
+    fn test_schedule() {
+        let now = at(1709164770);
+        assert_eq!(parse_duration("90").unwrap(), Duration::from_secs(90));
+        assert_eq!(parse_duration("6h").unwrap(), Duration::from_secs(6 * 3600));
+        assert!(parse_duration("0m").is_err());
+        assert!(parse_duration("2w").is_err());
+
+        let schedule =
+            Schedule::from_lines(["every=15m", "keep-runs=3", "max-depth=2"].into_iter(), now)
+                .unwrap();
+        assert_eq!(schedule.timing, Timing::Every(Duration::from_secs(900)));
+        assert_eq!(schedule.next, now + Duration::from_secs(900));
+        assert_eq!(schedule.keep_runs, Some(3));
+        assert_eq!(schedule.options.max_depth, Some(2));
+        assert_eq!(
+            schedule.lines(),
+            vec!["every=900", "keep-runs=3", "max-depth=2"]
+        );
+
+        //The site's own options win over the defaults, which can be swapped out later
+        let mut schedule = schedule;
+        schedule
+            .apply_defaults(&["max-depth=5".to_string(), "max-links=7".to_string()])
+            .unwrap();
+        assert_eq!(schedule.options.max_depth, Some(2));
+        assert_eq!(schedule.options.max_links, 7);
+        schedule.apply_defaults(&[]).unwrap();
+        assert_eq!(schedule.options.max_links, 5000);
+
+        //Defaults sent by a client given --config are kept in place of the daemon's
+        let lines = ["cron=0 3 * * *", "defaults=client", "default=max-links=9"];
+        let mut schedule = Schedule::from_lines(lines.into_iter(), now).unwrap();
+        schedule
+            .apply_defaults(&["max-links=7".to_string()])
+            .unwrap();
+        assert_eq!(schedule.options.max_links, 9);
+        assert_eq!(schedule.lines(), lines);
+
+        let schedule = Schedule::from_lines(["cron=0 3 * * *"].into_iter(), now).unwrap();
+        assert_eq!(schedule.keep_runs, None);
+
+        assert!(Schedule::from_lines(["max-depth=2"].into_iter(), now).is_err());
+        assert!(Schedule::from_lines(["cron=0 0 31 2 *"].into_iter(), now).is_err());
+        assert!(Schedule::from_lines(["every=1h", "keep-runs=0"].into_iter(), now).is_err());
+    }
+
+    #[test]
+    fn test_prune_runs() {
+        let run = |finished: u64| Run {
+            started: at(finished - 10),
+            finished: at(finished),
+            scheduled: true,
+            pages: 1,
+            errors: 0,
+        };
+        let mut runs: Vec<Run> = (1..=5).map(|day| run(day * 86400)).collect();
+        prune_runs(&mut runs, 3, None, at(6 * 86400));
+        assert_eq!(runs, vec![run(3 * 86400), run(4 * 86400), run(5 * 86400)]);
+        prune_runs(
+            &mut runs,
+            3,
+            Some(Duration::from_secs(2 * 86400)),
+            at(6 * 86400),
+        );
+        assert_eq!(runs, vec![run(4 * 86400), run(5 * 86400)]);
+    }
+}
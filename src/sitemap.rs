@@ -0,0 +1,461 @@
+use std::collections::{HashSet, VecDeque};
+use std::error::Error;
+use std::io::Read;
+
+use flate2::read::GzDecoder;
+
+use quick_xml::escape::escape;
+use quick_xml::events::Event;
+use quick_xml::Reader;
+
+use reqwest::Client;
+
+use url::Url;
+
+use crate::node::fetch_body;
+use crate::options::CrawlOptions;
+use crate::report::SitemapReport;
+use crate::tree::SiteTree;
+
+//The sitemap protocol limits files to 50MiB uncompressed
+const SITEMAP_MAX_SIZE: usize = 50 * 1024 * 1024;
+//Sitemap indexes are followed until this many files have been read
+const MAX_SITEMAPS: usize = 1000;
+//The sitemap protocol also limits each file to 50,000 urls
+const SITEMAP_MAX_URLS: usize = 50_000;
+const SITEMAP_NAMESPACE: &str = "http://www.sitemaps.org/schemas/sitemap/0.9";
+const XML_DECLARATION: &str = "<?xml version=\"1.0\" encoding=\"UTF-8\"?>";
+//Starts the daemon's reply to a sitemap command so the client can tell files from an error message
+pub const SITEMAP_FILES_HEADER: &str = "sitemap files\n";
+
+//The sitemap files a site advertises through `Sitemap:` lines in its robots.txt, along with /sitemap.xml
+pub async fn discover_sitemaps(client: &Client, root: &Url, options: &CrawlOptions) -> Vec<Url> {
+    let mut sitemaps = Vec::new();
+    if let Ok(robots_url) = root.join("/robots.txt") {
+        match fetch_body(client, &robots_url, options, options.max_body_size).await {
+            Ok(Some(robots)) => sitemaps = parse_robots(&String::from_utf8_lossy(&robots), root),
+            Ok(None) => {}
+            Err(e) => eprintln!("Couldn't read {}: {}", robots_url, e),
+        }
+    }
+    if let Ok(default_sitemap) = root.join("/sitemap.xml") {
+        if !sitemaps.contains(&default_sitemap) {
+            sitemaps.push(default_sitemap);
+        }
+    }
+    sitemaps
+}
+
+pub fn parse_robots(robots: &str, root: &Url) -> Vec<Url> {
+    robots
+        .lines()
+        .filter_map(|line| line.split_once(':'))
+        .filter(|(key, _)| key.trim().eq_ignore_ascii_case("sitemap"))
+        .filter_map(|(_, value)| root.join(value.trim()).ok())
+        .collect()
+}
+
+//Reads every page listed in the given sitemaps, following sitemap indexes
+//Returns the sitemap files which could be read along with the pages they list
+pub async fn read_sitemaps(
+    client: &Client,
+    sitemaps: Vec<Url>,
+    options: &CrawlOptions,
+) -> (Vec<Url>, Vec<Url>) {
+    let mut queue: VecDeque<Url> = sitemaps.into_iter().collect();
+    let mut seen: HashSet<Url> = queue.iter().cloned().collect();
+    let mut read = Vec::new();
+    let mut pages = Vec::new();
+
+    while let Some(sitemap) = queue.pop_front() {
+        if read.len() >= MAX_SITEMAPS {
+            eprintln!("Stopped reading sitemaps after {} files", MAX_SITEMAPS);
+            break;
+        }
+        let body = match fetch_body(client, &sitemap, options, SITEMAP_MAX_SIZE).await {
+            Ok(Some(body)) => body,
+            Ok(None) => continue,
+            Err(e) => {
+                eprintln!("Couldn't read sitemap {}: {}", sitemap, e);
+                continue;
+            }
+        };
+        match decompress(body).and_then(|xml| parse_sitemap(&xml)) {
+            Ok((listed_pages, listed_sitemaps)) => {
+                read.push(sitemap);
+                pages.extend(listed_pages);
+                for listed_sitemap in listed_sitemaps {
+                    if seen.insert(listed_sitemap.clone()) {
+                        queue.push_back(listed_sitemap);
+                    }
+                }
+            }
+            Err(e) => eprintln!("Couldn't parse sitemap {}: {}", sitemap, e),
+        }
+    }
+    (read, pages)
+}
+
+//Gzipped sitemaps are recognised by their magic number since servers label them inconsistently
+fn decompress(body: Vec<u8>) -> Result<Vec<u8>, Box<dyn Error>> {
+    if body.starts_with(&[0x1f, 0x8b]) {
+        let mut xml = Vec::new();
+        GzDecoder::new(body.as_slice())
+            .take(SITEMAP_MAX_SIZE as u64)
+            .read_to_end(&mut xml)?;
+        Ok(xml)
+    } else {
+        Ok(body)
+    }
+}
+
+//Splits the `<loc>` entries of a sitemap into pages from a urlset and sitemaps from a sitemap index
+pub fn parse_sitemap(xml: &[u8]) -> Result<(Vec<Url>, Vec<Url>), Box<dyn Error>> {
+    let mut reader = Reader::from_reader(xml);
+    let mut buffer = Vec::new();
+    let mut pages = Vec::new();
+    let mut sitemaps = Vec::new();
+    let mut in_sitemap = false;
+    let mut location: Option<String> = None;
+
+    loop {
+        match reader.read_event_into(&mut buffer)? {
+            Event::Start(element) => match element.local_name().as_ref() {
+                b"sitemap" => in_sitemap = true,
+                b"url" => in_sitemap = false,
+                b"loc" => location = Some(String::new()),
+                _ => {}
+            },
+            Event::Text(text) => {
+                if let Some(location) = location.as_mut() {
+                    location.push_str(&text.unescape()?);
+                }
+            }
+            Event::CData(text) => {
+                if let Some(location) = location.as_mut() {
+                    location.push_str(&String::from_utf8_lossy(&text));
+                }
+            }
+            Event::End(element) if element.local_name().as_ref() == b"loc" => {
+                if let Some(url) = location
+                    .take()
+                    .and_then(|location| Url::parse(location.trim()).ok())
+                {
+                    if in_sitemap {
+                        sitemaps.push(url);
+                    } else {
+                        pages.push(url);
+                    }
+                }
+            }
+            Event::Eof => break,
+            _ => {}
+        }
+        buffer.clear();
+    }
+    Ok((pages, sitemaps))
+}
+
+//Urls are compared without their fragment since those never reach the server
+pub fn page_key(url: &Url) -> String {
+    let mut url = url.clone();
+    url.set_fragment(None);
+    url.to_string()
+}
+
+//Compares the listed pages against the finished tree
+pub fn sitemap_report(tree: &SiteTree, sitemaps: Vec<Url>, listed: &[Url]) -> SitemapReport {
+    let host = tree.current_site.host_str();
+    let listed_keys: HashSet<String> = listed.iter().map(page_key).collect();
+
+    let mut linked: HashSet<String> = HashSet::new();
+    let mut unlisted: Vec<Url> = Vec::new();
+    let mut unlisted_keys: HashSet<String> = HashSet::new();
+    tree.visit(&mut |node| {
+        let key = page_key(&node.current_site);
+        if !node.page.from_sitemap {
+            linked.insert(key.clone());
+        }
+        let crawled_page = node.page.html
+            && node
+                .page
+                .status
+                .is_some_and(|status| (200..300).contains(&status))
+            && node.current_site.host_str() == host;
+        if crawled_page && !listed_keys.contains(&key) && unlisted_keys.insert(key) {
+            unlisted.push(node.current_site.clone());
+        }
+    });
+
+    let mut orphan_keys: HashSet<String> = HashSet::new();
+    let orphans = listed
+        .iter()
+        .filter(|url| url.host_str() == host)
+        .filter(|url| {
+            let key = page_key(url);
+            !linked.contains(&key) && orphan_keys.insert(key)
+        })
+        .cloned()
+        .collect();
+
+    SitemapReport {
+        sitemaps,
+        listed: listed_keys.len(),
+        orphans,
+        unlisted,
+    }
+}
+
+//Builds sitemap files from the 200 status html pages of a finished crawl, as file names paired with their contents
+//Crawls too large for one file are split into numbered files listed by a sitemap.xml index,
+//which points at them under base since that's where they'll be served from
+pub fn generate_sitemaps(tree: &SiteTree, base: &Url, lastmod: bool) -> Vec<(String, String)> {
+    let host = tree.current_site.host_str();
+    let mut seen: HashSet<String> = HashSet::new();
+    let mut entries: Vec<String> = Vec::new();
+    tree.visit(&mut |node| {
+        //Redirected pages are listed where they ended up
+        let location = node
+            .page
+            .redirects
+            .last()
+            .map_or(&node.current_site, |redirect| &redirect.location);
+        let key = page_key(location);
+        if node.page.status == Some(200)
+            && node.page.html
+            && location.host_str() == host
+            && seen.insert(key.clone())
+        {
+            let mut entry = format!("  <url><loc>{}</loc>", escape(key.as_str()));
+            if let Some(modified) = node
+                .page
+                .last_modified
+                .as_deref()
+                .filter(|_| lastmod)
+                .and_then(w3c_datetime)
+            {
+                entry = entry + "<lastmod>" + &modified + "</lastmod>";
+            }
+            entries.push(entry + "</url>\n");
+        }
+    });
+
+    //Room for the declaration and the urlset element around the entries
+    let overhead = XML_DECLARATION.len() + SITEMAP_NAMESPACE.len() + 64;
+    let mut urlsets: Vec<String> = Vec::new();
+    let mut urlset = String::new();
+    let mut urls = 0;
+    for entry in entries {
+        if urls == SITEMAP_MAX_URLS || urlset.len() + entry.len() + overhead > SITEMAP_MAX_SIZE {
+            urlsets.push(urlset);
+            urlset = String::new();
+            urls = 0;
+        }
+        urlset += &entry;
+        urls += 1;
+    }
+    urlsets.push(urlset);
+
+    let wrap = |element: &str, entries: &str| {
+        format!(
+            "{}\n<{} xmlns=\"{}\">\n{}</{}>\n",
+            XML_DECLARATION, element, SITEMAP_NAMESPACE, entries, element
+        )
+    };
+    if urlsets.len() == 1 {
+        return vec![("sitemap.xml".to_string(), wrap("urlset", &urlsets[0]))];
+    }
+
+    let mut base = base.clone();
+    if !base.path().ends_with('/') {
+        base.set_path(&(base.path().to_string() + "/"));
+    }
+    let mut files = Vec::new();
+    let mut index = String::new();
+    for (number, urlset) in urlsets.iter().enumerate() {
+        let name = format!("sitemap-{}.xml", number + 1);
+        if let Ok(location) = base.join(&name) {
+            index = index + "  <sitemap><loc>" + &escape(location.as_str()) + "</loc></sitemap>\n";
+        }
+        files.push((name, wrap("urlset", urlset)));
+    }
+    files.insert(0, ("sitemap.xml".to_string(), wrap("sitemapindex", &index)));
+    files
+}
+
+//Converts an HTTP date like `Sun, 06 Nov 1994 08:49:37 GMT` into the W3C datetime sitemaps use
+//The obsolete date formats servers no longer send are ignored
+fn w3c_datetime(http_date: &str) -> Option<String> {
+    const MONTHS: [&str; 12] = [
+        "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+    ];
+    let parts: Vec<&str> = http_date.split_whitespace().collect();
+    if parts.len() != 6 || parts[5] != "GMT" {
+        return None;
+    }
+    let day: u32 = parts[1].parse().ok().filter(|day| (1..=31).contains(day))?;
+    let month = MONTHS.iter().position(|month| *month == parts[2])? + 1;
+    let year: u32 = parts[3].parse().ok().filter(|_| parts[3].len() == 4)?;
+    let time: Vec<u32> = parts[4]
+        .split(':')
+        .map(|part| part.parse::<u32>().ok().filter(|_| part.len() == 2))
+        .collect::<Option<_>>()?;
+    if time.len() != 3 || time[0] > 23 || time[1] > 59 || time[2] > 60 {
+        return None;
+    }
+    Some(format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}+00:00",
+        year, month, day, time[0], time[1], time[2]
+    ))
+}
+
+//Each file is sent as its name and length on one line followed by its contents
+pub fn encode_files(files: &[(String, String)]) -> String {
+    let mut encoded = SITEMAP_FILES_HEADER.to_string();
+    for (name, contents) in files.iter() {
+        encoded = encoded + name + " " + &contents.len().to_string() + "\n" + contents;
+    }
+    encoded
+}
+
+pub fn decode_files(encoded: &str) -> Option<Vec<(String, String)>> {
+    let mut remaining = encoded.strip_prefix(SITEMAP_FILES_HEADER)?;
+    let mut files = Vec::new();
+    while !remaining.is_empty() {
+        let (line, rest) = remaining.split_once('\n')?;
+        let (name, length) = line.rsplit_once(' ')?;
+        let length: usize = length.parse().ok()?;
+        if rest.len() < length || !rest.is_char_boundary(length) {
+            return None;
+        }
+        files.push((name.to_string(), rest[..length].to_string()));
+        remaining = &rest[length..];
+    }
+    Some(files)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        decode_files, decompress, encode_files, generate_sitemaps, parse_robots, parse_sitemap,
+        w3c_datetime,
+    };
+    use crate::tree::{SiteTree, SubSites};
+    use flate2::write::GzEncoder;
+    use flate2::Compression;
+    use std::io::Write;
+    use url::Url;
+
+    #[test]
+    fn test_parse_robots() {
+        let root = Url::parse("https://example.com/docs/").unwrap();
+        let robots = "User-agent: *\nDisallow: /private\nSitemap: https://example.com/a.xml\nsitemap:/b.xml.gz\n";
+        assert_eq!(
+            parse_robots(robots, &root),
+            vec![
+                Url::parse("https://example.com/a.xml").unwrap(),
+                Url::parse("https://example.com/b.xml.gz").unwrap()
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_sitemap() {
+        let index = br#"<?xml version="1.0" encoding="UTF-8"?>
+            <sitemapindex xmlns="http://www.sitemaps.org/schemas/sitemap/0.9">
+                <sitemap><loc>https://example.com/pages.xml.gz</loc><lastmod>2024-01-01</lastmod></sitemap>
+            </sitemapindex>"#;
+        let (pages, sitemaps) = parse_sitemap(index).expect("Couldn't parse the index");
+        assert!(pages.is_empty());
+        assert_eq!(
+            sitemaps,
+            vec![Url::parse("https://example.com/pages.xml.gz").unwrap()]
+        );
+
+        let urlset = br#"<urlset xmlns="http://www.sitemaps.org/schemas/sitemap/0.9">
+                <url><loc> https://example.com/?a=1&amp;b=2 </loc></url>
+                <url><loc><![CDATA[https://example.com/guide]]></loc></url>
+            </urlset>"#;
+        let mut gzipped = GzEncoder::new(Vec::new(), Compression::default());
+        gzipped.write_all(urlset).unwrap();
+        let xml = decompress(gzipped.finish().unwrap()).expect("Couldn't decompress");
+        let (pages, sitemaps) = parse_sitemap(&xml).expect("Couldn't parse the urlset");
+        assert!(sitemaps.is_empty());
+        assert_eq!(
+            pages,
+            vec![
+                Url::parse("https://example.com/?a=1&b=2").unwrap(),
+                Url::parse("https://example.com/guide").unwrap()
+            ]
+        );
+    }
+
+    #[test]
+    fn test_generate_sitemaps() {
+        let root_url = Url::parse("https://example.com/").unwrap();
+        let mut root = SiteTree::new(root_url.clone());
+        root.page.status = Some(200);
+        root.page.html = true;
+        root.page.last_modified = Some("Sun, 06 Nov 1994 08:49:37 GMT".to_string());
+        let mut children = Vec::new();
+        for (path, status, html) in [
+            ("/a?x=1&y=2", 200, true),
+            ("/a?x=1&y=2#top", 200, true),
+            ("/missing", 404, true),
+            ("/report.pdf", 200, false),
+            ("https://other.com/", 200, true),
+        ] {
+            let mut child = SiteTree::child(root_url.join(path).unwrap(), 1);
+            child.page.status = Some(status);
+            child.page.html = html;
+            children.push(child);
+        }
+        root.sub_sites = SubSites::List(children);
+
+        let files = generate_sitemaps(&root, &root_url, true);
+        assert_eq!(
+            files,
+            vec![(
+                "sitemap.xml".to_string(),
+                "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n\
+                 <urlset xmlns=\"http://www.sitemaps.org/schemas/sitemap/0.9\">\n  \
+                 <url><loc>https://example.com/</loc><lastmod>1994-11-06T08:49:37+00:00</lastmod></url>\n  \
+                 <url><loc>https://example.com/a?x=1&amp;y=2</loc></url>\n\
+                 </urlset>\n"
+                    .to_string()
+            )]
+        );
+        let (pages, _) = parse_sitemap(files[0].1.as_bytes()).unwrap();
+        assert_eq!(pages.len(), 2);
+        assert_eq!(decode_files(&encode_files(&files)), Some(files));
+
+        //Larger crawls are split and listed by an index
+        let mut children = Vec::new();
+        for page in 0..50_001 {
+            let mut child = SiteTree::child(root_url.join(&format!("/page/{}", page)).unwrap(), 1);
+            child.page.status = Some(200);
+            child.page.html = true;
+            children.push(child);
+        }
+        root.sub_sites = SubSites::List(children);
+        let files = generate_sitemaps(
+            &root,
+            &Url::parse("https://cdn.example.com/maps").unwrap(),
+            false,
+        );
+        let names: Vec<&str> = files.iter().map(|(name, _)| name.as_str()).collect();
+        assert_eq!(names, vec!["sitemap.xml", "sitemap-1.xml", "sitemap-2.xml"]);
+        let (pages, sitemaps) = parse_sitemap(files[0].1.as_bytes()).unwrap();
+        assert!(pages.is_empty());
+        assert_eq!(
+            sitemaps,
+            vec![
+                Url::parse("https://cdn.example.com/maps/sitemap-1.xml").unwrap(),
+                Url::parse("https://cdn.example.com/maps/sitemap-2.xml").unwrap()
+            ]
+        );
+        assert_eq!(parse_sitemap(files[2].1.as_bytes()).unwrap().0.len(), 2);
+
+        assert_eq!(w3c_datetime("Sunday, 06-Nov-94 08:49:37 GMT"), None);
+    }
+}
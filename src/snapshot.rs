@@ -0,0 +1,126 @@
+use std::error::Error;
+
+use serde::{Deserialize, Serialize};
+
+use url::Url;
+
+use crate::tree::{PageInfo, SiteTree, SubSites};
+
+//Trees are saved as a flat list of pages in json, each naming its links by their position in the list,
+//so a long chain of pages can't run into the nesting limit of the json parser
+//Pages come before their links so a tree can be rebuilt from the end of the list
+#[derive(Debug, Serialize, Deserialize)]
+struct SnapshotPage {
+    url: Url,
+    page: PageInfo,
+    //None for pages whose links were never read
+    links: Option<Vec<usize>>,
+}
+
+pub fn save_snapshot(tree: &SiteTree) -> Result<String, serde_json::Error> {
+    let mut pages = Vec::new();
+    flatten(tree, &mut pages);
+    serde_json::to_string(&pages)
+}
+
+fn flatten(tree: &SiteTree, pages: &mut Vec<SnapshotPage>) -> usize {
+    let index = pages.len();
+    pages.push(SnapshotPage {
+        url: tree.current_site.clone(),
+        page: tree.page.clone(),
+        links: None,
+    });
+    if let SubSites::List(sub_sites) = &tree.sub_sites {
+        let links = sub_sites
+            .iter()
+            .map(|sub_site| flatten(sub_site, pages))
+            .collect();
+        pages[index].links = Some(links);
+    }
+    index
+}
+
+pub fn load_snapshot(snapshot: &str) -> Result<SiteTree, Box<dyn Error>> {
+    let pages: Vec<SnapshotPage> = serde_json::from_str(snapshot)?;
+    let mut trees: Vec<Option<SiteTree>> = Vec::new();
+    trees.resize_with(pages.len(), || None);
+    for (index, page) in pages.into_iter().enumerate().rev() {
+        let sub_sites = match page.links {
+            Some(links) => {
+                let mut sub_sites = Vec::new();
+                for link in links {
+                    //Each page is only taken once and only by a page before it, so the tree can't loop
+                    match trees.get_mut(link).and_then(|tree| tree.take()) {
+                        Some(sub_site) if link > index => sub_sites.push(sub_site),
+                        _ => {
+                            return Err(
+                                format!("Page {} has an invalid link {}", index, link).into()
+                            )
+                        }
+                    }
+                }
+                SubSites::List(sub_sites)
+            }
+            None => SubSites::Nil,
+        };
+        trees[index] = Some(SiteTree {
+            current_site: page.url,
+            sub_sites,
+            page: page.page,
+        });
+    }
+    let root = trees
+        .first_mut()
+        .and_then(|tree| tree.take())
+        .ok_or("The snapshot has no pages")?;
+    //Every page has to be part of the tree
+    if trees.iter().any(|tree| tree.is_some()) {
+        return Err("The snapshot has pages no other page links to".into());
+    }
+    Ok(root)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{load_snapshot, save_snapshot};
+    use crate::redirect::Redirect;
+    use crate::tree::{LinkKind, SiteTree, SubSites};
+    use url::Url;
+
+    #[test]
+    fn test_snapshot_round_trip() {
+        let url = Url::parse("https://example.com/").unwrap();
+        let mut tree = SiteTree::new(url.clone());
+        tree.page.status = Some(200);
+        let mut moved = SiteTree::child(url.join("/old").unwrap(), 1);
+        moved.page.redirects = vec![Redirect {
+            status: 301,
+            location: url.join("/new").unwrap(),
+        }];
+        moved.sub_sites = SubSites::List(Vec::new());
+        let mut mail = SiteTree::child(Url::parse("mailto:team@example.com").unwrap(), 1);
+        mail.page.kind = LinkKind::Email;
+        tree.sub_sites = SubSites::List(vec![moved, mail]);
+
+        let snapshot = save_snapshot(&tree).unwrap();
+        assert_eq!(load_snapshot(&snapshot).unwrap(), tree);
+
+        //Chains deeper than the json nesting limit still load
+        let mut deep = SiteTree::new(url.clone());
+        for depth in (1..300).rev() {
+            let mut parent = SiteTree::child(url.join(&format!("/page/{}", depth)).unwrap(), depth);
+            parent.sub_sites = SubSites::List(vec![deep]);
+            deep = parent;
+        }
+        let snapshot = save_snapshot(&deep).unwrap();
+        assert_eq!(load_snapshot(&snapshot).unwrap(), deep);
+
+        //Fields added after a snapshot was saved are left at their defaults
+        let old = "[{\"url\":\"https://example.com/\",\"page\":{\"status\":200},\"links\":null}]";
+        assert_eq!(load_snapshot(old).unwrap().page.status, Some(200));
+
+        let looping = "[{\"url\":\"https://example.com/\",\"page\":{},\"links\":[0]}]";
+        assert!(load_snapshot(looping).is_err());
+        assert!(load_snapshot("not json").is_err());
+    }
+}
@@ -0,0 +1,159 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{read_to_string, rename, OpenOptions};
+use std::io::{ErrorKind, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use serde::{Deserialize, Serialize};
+
+use crate::schedule::{Run, Schedule};
+
+//What the daemon keeps across restarts, its schedules and the history of every site. Finished crawls can
+//be large so they're left to snapshots
+#[derive(Debug, PartialEq, Default, Serialize, Deserialize)]
+pub struct SavedState {
+    pub schedules: BTreeMap<String, SavedSchedule>,
+    pub history: BTreeMap<String, Vec<Run>>,
+}
+
+//A schedule as the lines it was made from, the daemon's crawl defaults are applied again when it's loaded
+//unless the client sent its own
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct SavedSchedule {
+    pub lines: Vec<String>,
+    pub next: SystemTime,
+}
+
+impl SavedState {
+    pub fn new(
+        schedules: &HashMap<String, Schedule>,
+        history: &HashMap<String, Vec<Run>>,
+    ) -> SavedState {
+        SavedState {
+            schedules: schedules
+                .iter()
+                .map(|(site, schedule)| {
+                    let saved = SavedSchedule {
+                        lines: schedule.lines(),
+                        next: schedule.next,
+                    };
+                    (site.clone(), saved)
+                })
+                .collect(),
+            history: history
+                .iter()
+                .map(|(site, runs)| (site.clone(), runs.clone()))
+                .collect(),
+        }
+    }
+
+    //Rebuilds the schedules with the given defaults, a schedule they no longer work with is left out and
+    //described in the errors
+    pub fn schedules(&self, defaults: &[String]) -> (HashMap<String, Schedule>, Vec<String>) {
+        let mut schedules = HashMap::new();
+        let mut errors = Vec::new();
+        for (site, saved) in self.schedules.iter() {
+            let schedule =
+                Schedule::from_lines(saved.lines.iter().map(|line| line.as_str()), saved.next)
+                    .and_then(|mut schedule| {
+                        schedule.apply_defaults(defaults)?;
+                        Ok(schedule)
+                    });
+            match schedule {
+                Ok(mut schedule) => {
+                    //A run missed while the daemon was down is started once it's back
+                    schedule.next = saved.next;
+                    schedules.insert(site.clone(), schedule);
+                }
+                Err(e) => errors.push(format!("Dropped the schedule for {}: {}", site, e)),
+            }
+        }
+        (schedules, errors)
+    }
+}
+
+//Replaces the state file in one step, so a daemon stopped part way through leaves the old one whole
+pub fn save_state(path: &Path, state: &SavedState) -> Result<(), String> {
+    let text = serde_json::to_string(state).map_err(|e| e.to_string())?;
+    let mut temporary = path.as_os_str().to_owned();
+    temporary.push(".tmp");
+    let temporary = PathBuf::from(temporary);
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(&temporary)
+        .map_err(|e| format!("Couldn't write {}: {}", temporary.display(), e))?;
+    file.write_all(text.as_bytes())
+        .and_then(|_| file.sync_all())
+        .map_err(|e| format!("Couldn't write {}: {}", temporary.display(), e))?;
+    rename(&temporary, path).map_err(|e| format!("Couldn't replace {}: {}", path.display(), e))
+}
+
+//Nothing has been saved yet when the file doesn't exist
+pub fn load_state(path: &Path) -> Result<SavedState, String> {
+    match read_to_string(path) {
+        Ok(text) => serde_json::from_str(&text)
+            .map_err(|e| format!("Invalid state file {}: {}", path.display(), e)),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(SavedState::default()),
+        Err(e) => Err(format!("Couldn't read {}: {}", path.display(), e)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{load_state, save_state, SavedState};
+    use crate::schedule::{Run, Schedule};
+    use std::collections::HashMap;
+    use std::fs::{metadata, remove_file, write};
+    use std::os::unix::fs::PermissionsExt;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    #[test]
+    fn test_state_file() {
+        let path = std::env::temp_dir().join(format!("crawl-state-{}", std::process::id()));
+        assert_eq!(load_state(&path), Ok(SavedState::default()));
+
+        let now = UNIX_EPOCH + Duration::from_secs(1709164770);
+        let defaults = vec!["max-links=7".to_string()];
+        let mut schedule = Schedule::from_lines(
+            ["cron=0 3 * * *", "keep-for=7d", "max-depth=2"].into_iter(),
+            now,
+        )
+        .unwrap();
+        schedule.apply_defaults(&defaults).unwrap();
+        let schedules = HashMap::from([("https://example.com/".to_string(), schedule)]);
+        let run = Run {
+            started: now,
+            finished: now + Duration::from_secs(30),
+            scheduled: true,
+            pages: 12,
+            errors: 1,
+        };
+        let history = HashMap::from([("https://example.com/".to_string(), vec![run])]);
+
+        save_state(&path, &SavedState::new(&schedules, &history)).unwrap();
+        assert_eq!(metadata(&path).unwrap().permissions().mode() & 0o777, 0o600);
+        let saved = load_state(&path).unwrap();
+        let (restored, errors) = saved.schedules(&defaults);
+        assert_eq!(restored, schedules);
+        assert!(errors.is_empty());
+        assert_eq!(
+            saved.history["https://example.com/"],
+            history["https://example.com/"]
+        );
+
+        //Defaults which no longer suit a schedule drop it rather than the whole state
+        let (restored, errors) = saved.schedules(&["max-depth=deep".to_string()]);
+        assert!(restored.is_empty());
+        assert_eq!(errors.len(), 1);
+
+        write(&path, "{").unwrap();
+        assert!(load_state(&path)
+            .unwrap_err()
+            .starts_with("Invalid state file"));
+        remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,127 @@
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::TcpListener;
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use url::Url;
+
+//Every request the test server received as its request line followed by its header lines,
+//then an empty line and the body when one was sent
+pub type Requests = Arc<Mutex<Vec<Vec<String>>>>;
+
+//Serves canned responses from a local port and records every request it receives
+//Any {origin} in a response is replaced with the server's scheme, host and port
+pub fn serve(routes: Vec<(&'static str, String)>) -> (Url, Requests) {
+    let listener = TcpListener::bind("127.0.0.1:0").expect("Couldn't bind a local port");
+    let url = Url::parse(&format!(
+        "http://{}/",
+        listener
+            .local_addr()
+            .expect("Couldn't get the local address")
+    ))
+    .expect("Couldn't parse the given URL");
+    let origin = url.origin().ascii_serialization();
+    let requests: Requests = Arc::new(Mutex::new(Vec::new()));
+    let recorded = requests.clone();
+
+    thread::spawn(move || {
+        for mut stream in listener.incoming().flatten() {
+            let mut reader = BufReader::new(stream.try_clone().expect("Couldn't clone stream"));
+            let mut request = Vec::new();
+            let mut line = String::new();
+            while reader.read_line(&mut line).is_ok() && line.trim() != "" {
+                request.push(line.trim().to_string());
+                line.clear();
+            }
+            if request.is_empty() {
+                continue;
+            }
+            //Any body follows an empty line so it can't be mistaken for a header
+            if let Some(length) =
+                header(&request, "content-length").and_then(|length| length.parse::<usize>().ok())
+            {
+                let mut body = vec![0; length];
+                if reader.read_exact(&mut body).is_ok() {
+                    request.push(String::new());
+                    request.push(String::from_utf8_lossy(&body).into_owned());
+                }
+            }
+
+            let path = request[0].split(' ').nth(1).unwrap_or("/").to_string();
+            let served = {
+                let mut recorded = recorded.lock().expect("Request log was poisoned");
+                recorded.push(request);
+                recorded
+                    .iter()
+                    .filter(|request| request[0].split(' ').nth(1) == Some(path.as_str()))
+                    .count()
+            };
+            //Repeated routes are answered in order with the last one repeating
+            let responses: Vec<&String> = routes
+                .iter()
+                .filter(|(route, _)| *route == path)
+                .map(|(_, response)| response)
+                .collect();
+            let response = responses
+                .get(served.min(responses.len().max(1)) - 1)
+                .map(|response| with_origin(response, &origin))
+                .unwrap_or_else(|| http_response("404 Not Found", "", ""));
+            let _ = stream.write_all(response.as_bytes());
+        }
+    });
+    (url, requests)
+}
+
+//Fills in the origin after the response was built so its Content-Length has to be redone
+fn with_origin(response: &str, origin: &str) -> String {
+    match response.split_once("\r\n\r\n") {
+        Some((head, body)) if response.contains("{origin}") => {
+            let body = body.replace("{origin}", origin);
+            let head: Vec<String> = head
+                .replace("{origin}", origin)
+                .split("\r\n")
+                .map(|line| {
+                    if line.starts_with("Content-Length:") {
+                        format!("Content-Length: {}", body.len())
+                    } else {
+                        line.to_string()
+                    }
+                })
+                .collect();
+            format!("{}\r\n\r\n{}", head.join("\r\n"), body)
+        }
+        _ => response.to_string(),
+    }
+}
+
+pub fn http_response(status: &str, headers: &str, body: &str) -> String {
+    format!(
+        "HTTP/1.1 {}\r\n{}Content-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        headers,
+        body.len(),
+        body
+    )
+}
+
+pub fn request_lines(requests: &Requests) -> Vec<String> {
+    requests
+        .lock()
+        .expect("Request log was poisoned")
+        .iter()
+        .map(|request| request[0].clone())
+        .collect()
+}
+
+//Finds a header in a recorded request, names are compared without case
+pub fn header<'a>(request: &'a [String], name: &str) -> Option<&'a str> {
+    request
+        .iter()
+        .skip(1)
+        .take_while(|line| !line.is_empty())
+        .find_map(|line| {
+            line.split_once(':')
+                .filter(|(header, _)| header.trim().eq_ignore_ascii_case(name))
+                .map(|(_, value)| value.trim())
+        })
+}
@@ -0,0 +1,207 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+
+use url::Url;
+
+use crate::options::CrawlOptions;
+
+//Heuristics for urls which keep generating new pages, like calendars, session ids in paths and
+//relative links which grow the path every time they're followed
+#[derive(Debug, PartialEq, Clone, Copy, Serialize, Deserialize)]
+pub enum TrapKind {
+    LongUrl,
+    RepeatedPath,
+    Template,
+    Directory,
+}
+
+impl fmt::Display for TrapKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TrapKind::LongUrl => write!(f, "url too long"),
+            TrapKind::RepeatedPath => write!(f, "repeated path segment"),
+            TrapKind::Template => write!(f, "too many urls for one pattern"),
+            TrapKind::Directory => write!(f, "too many pages in one directory"),
+        }
+    }
+}
+
+//A suspected trap along with how many urls were stopped because of it
+#[derive(Debug, PartialEq, Clone)]
+pub struct Trap {
+    pub kind: TrapKind,
+    pub pattern: String,
+    pub example: Url,
+    pub stopped: usize,
+}
+
+//Keeps the counts the heuristics need across the whole job
+#[derive(Debug, Default)]
+pub struct TrapDetector {
+    template_urls: HashMap<String, HashSet<String>>,
+    directory_pages: HashMap<String, usize>,
+    pub traps: Vec<Trap>,
+}
+
+impl TrapDetector {
+    //Gives the kind of trap a url looks like so it can be left unfetched, each url is only checked once
+    pub fn check(&mut self, url: &Url, options: &CrawlOptions) -> Option<TrapKind> {
+        if url.as_str().len() > options.max_url_length {
+            return self.flag(TrapKind::LongUrl, url.path().to_string(), url);
+        }
+
+        let segments: Vec<&str> = url
+            .path_segments()
+            .map(|segments| segments.filter(|segment| !segment.is_empty()).collect())
+            .unwrap_or_default();
+        let mut repeats: HashMap<&str, usize> = HashMap::new();
+        for segment in segments.iter() {
+            let count = repeats.entry(segment).or_default();
+            *count += 1;
+            if *count > options.max_path_repeats {
+                return self.flag(TrapKind::RepeatedPath, format!("/{}/", segment), url);
+            }
+        }
+
+        let template = url_template(url);
+        let urls = self.template_urls.entry(template.clone()).or_default();
+        if urls.len() >= options.max_template_urls {
+            return self.flag(TrapKind::Template, template, url);
+        }
+        urls.insert(url.to_string());
+
+        let path = url.path();
+        let directory = path[..path.rfind('/').map_or(0, |index| index + 1)].to_string();
+        let pages = self.directory_pages.entry(directory.clone()).or_default();
+        if *pages >= options.max_directory_pages {
+            return self.flag(TrapKind::Directory, directory, url);
+        }
+        *pages += 1;
+        None
+    }
+
+    fn flag(&mut self, kind: TrapKind, pattern: String, url: &Url) -> Option<TrapKind> {
+        match self
+            .traps
+            .iter_mut()
+            .find(|trap| trap.kind == kind && trap.pattern == pattern)
+        {
+            Some(trap) => trap.stopped += 1,
+            None => self.traps.push(Trap {
+                kind,
+                pattern,
+                example: url.clone(),
+                stopped: 1,
+            }),
+        }
+        Some(kind)
+    }
+}
+
+//The shape of a url with the parts that usually vary between generated pages taken out
+//Segments holding numbers or long ids become * and only the names of query parameters are kept
+fn url_template(url: &Url) -> String {
+    let mut template = String::new();
+    for segment in url.path().split('/').skip(1) {
+        template.push('/');
+        if is_variable(segment) {
+            template.push('*');
+        } else {
+            template.push_str(segment);
+        }
+    }
+    let mut names: Vec<String> = url
+        .query_pairs()
+        .map(|(name, _)| name.into_owned())
+        .collect();
+    names.sort();
+    names.dedup();
+    if !names.is_empty() {
+        template = template + "?" + &names.join("&");
+    }
+    template
+}
+
+fn is_variable(segment: &str) -> bool {
+    let digits = segment.chars().filter(|c| c.is_ascii_digit()).count();
+    let hex_id = segment.len() >= 16 && segment.chars().all(|c| c.is_ascii_hexdigit() || c == '-');
+    (digits > 0 && digits * 2 >= segment.len()) || hex_id
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{url_template, Trap, TrapDetector, TrapKind};
+    use crate::options::CrawlOptions;
+    use url::Url;
+
+    #[test]
+    fn test_url_template() {
+        let url =
+            Url::parse("https://example.com/calendar/2024/05/events.html?view=day&day=3&view=list")
+                .unwrap();
+        assert_eq!(url_template(&url), "/calendar/*/*/events.html?day&view");
+        let url = Url::parse("https://example.com/s/3f2a9c1e-77b4-4e0a-9d1b/cart").unwrap();
+        assert_eq!(url_template(&url), "/s/*/cart");
+    }
+
+    #[test]
+    fn test_trap_detector() {
+        let options = CrawlOptions {
+            max_url_length: 60,
+            max_path_repeats: 2,
+            max_template_urls: 3,
+            max_directory_pages: 4,
+            ..CrawlOptions::default()
+        };
+        let mut detector = TrapDetector::default();
+        let check = |detector: &mut TrapDetector, url: &str| {
+            detector.check(&Url::parse(url).unwrap(), &options)
+        };
+
+        assert_eq!(check(&mut detector, "https://example.com/a/a/"), None);
+        assert_eq!(
+            check(&mut detector, "https://example.com/a/b/a/b/a/"),
+            Some(TrapKind::RepeatedPath)
+        );
+        assert_eq!(
+            check(
+                &mut detector,
+                &format!("https://example.com/{}", "x".repeat(60))
+            ),
+            Some(TrapKind::LongUrl)
+        );
+        for month in 1..=3 {
+            let url = format!("https://example.com/calendar/2024/{}", month);
+            assert_eq!(check(&mut detector, &url), None);
+        }
+        assert_eq!(
+            check(&mut detector, "https://example.com/calendar/2024/4"),
+            Some(TrapKind::Template)
+        );
+        assert_eq!(
+            check(&mut detector, "https://example.com/calendar/2025/1"),
+            Some(TrapKind::Template)
+        );
+        for page in ["a", "b", "c", "d"] {
+            let url = format!("https://example.com/docs/{}", page);
+            assert_eq!(check(&mut detector, &url), None);
+        }
+        assert_eq!(
+            check(&mut detector, "https://example.com/docs/e"),
+            Some(TrapKind::Directory)
+        );
+
+        assert_eq!(detector.traps.len(), 4);
+        assert_eq!(
+            detector.traps[2],
+            Trap {
+                kind: TrapKind::Template,
+                pattern: "/calendar/*/*".to_string(),
+                example: Url::parse("https://example.com/calendar/2024/4").unwrap(),
+                stopped: 2,
+            }
+        );
+    }
+}
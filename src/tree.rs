@@ -1,11 +1,82 @@
+use std::collections::HashSet;
 use std::fmt;
 
+use serde::{Deserialize, Serialize};
+
 use url::Url;
 
+use crate::fingerprint::Fingerprint;
+use crate::redirect::Redirect;
+use crate::trap::TrapKind;
+
 #[derive(Debug, PartialEq, Clone)]
 pub struct SiteTree {
     pub current_site: Url,
     pub sub_sites: SubSites,
+    pub page: PageInfo,
+}
+
+//Links are internal when they're http links sharing the host of the crawl's root, only internal links are crawled
+//Links with other schemes are told apart so they can be reported instead of fetched
+#[derive(Debug, PartialEq, Clone, Copy, Default, Serialize, Deserialize)]
+pub enum LinkKind {
+    #[default]
+    Internal,
+    External,
+    Email,
+    Phone,
+    Script,
+    Data,
+    //ftp and any other scheme
+    Other,
+}
+
+impl LinkKind {
+    pub fn of(url: &Url, domain: &str) -> LinkKind {
+        match url.scheme() {
+            "http" | "https" if url.host_str() == Some(domain) => LinkKind::Internal,
+            "http" | "https" => LinkKind::External,
+            "mailto" => LinkKind::Email,
+            "tel" => LinkKind::Phone,
+            "javascript" => LinkKind::Script,
+            "data" => LinkKind::Data,
+            _ => LinkKind::Other,
+        }
+    }
+
+    pub fn is_http(&self) -> bool {
+        matches!(self, LinkKind::Internal | LinkKind::External)
+    }
+}
+
+//Details recorded about a page while it's crawled
+//Missing fields are left at their defaults so snapshots saved by older versions still load
+#[derive(Debug, PartialEq, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct PageInfo {
+    pub kind: LinkKind,
+    pub depth: usize,
+    pub retries: u32,
+    //Status of the last response, None if the page wasn't fetched or the request failed
+    pub status: Option<u16>,
+    pub html: bool,
+    //Last-Modified and ETag headers of the last response, sent back when the page is recrawled
+    pub last_modified: Option<String>,
+    pub etag: Option<String>,
+    //Set when a recrawl found the page unchanged, its links are the ones found before
+    pub not_modified: bool,
+    //Every redirect followed to reach the page, the last location is where its content came from
+    pub redirects: Vec<Redirect>,
+    //Ids and anchor names on the page, None unless it was html and read in full
+    pub anchors: Option<HashSet<String>>,
+    //Set for html pages read in full
+    pub fingerprint: Option<Fingerprint>,
+    //Why fetching the page or checking the external link failed, None when it responded or wasn't tried
+    pub error: Option<String>,
+    //Pages only found through the sitemap are attached to the root with this set
+    pub from_sitemap: bool,
+    //Set when the url looked like a crawl trap and was left unfetched
+    pub trap: Option<TrapKind>,
 }
 
 #[derive(Debug, PartialEq, Clone)]
@@ -26,6 +97,43 @@ impl fmt::Display for SiteTree {
 }
 
 impl SiteTree {
+    pub fn new(current_site: Url) -> SiteTree {
+        SiteTree {
+            current_site,
+            sub_sites: SubSites::Nil,
+            page: PageInfo::default(),
+        }
+    }
+
+    pub fn child(current_site: Url, depth: usize) -> SiteTree {
+        SiteTree {
+            page: PageInfo {
+                depth,
+                ..PageInfo::default()
+            },
+            ..SiteTree::new(current_site)
+        }
+    }
+
+    //Calls visit on this node and every node below it, parents before their children
+    pub fn visit<'a>(&'a self, visit: &mut impl FnMut(&'a SiteTree)) {
+        visit(self);
+        if let SubSites::List(sub_sites) = &self.sub_sites {
+            for sub_site in sub_sites.iter() {
+                sub_site.visit(visit);
+            }
+        }
+    }
+
+    pub fn visit_mut(&mut self, visit: &mut impl FnMut(&mut SiteTree)) {
+        visit(self);
+        if let SubSites::List(sub_sites) = &mut self.sub_sites {
+            for sub_site in sub_sites.iter_mut() {
+                sub_site.visit_mut(visit);
+            }
+        }
+    }
+
     #[allow(dead_code)]
     pub fn print(&self, spacing: usize) {
         let mut last: Vec<bool> = Vec::new();
@@ -88,6 +196,10 @@ impl SiteTree {
 
         output_string.push_str(buffer_string.as_str());
         output_string.push_str(self.current_site.as_str());
+        if let Some(redirect) = self.page.redirects.last() {
+            output_string.push_str(" -> ");
+            output_string.push_str(redirect.location.as_str());
+        }
         output_string.push('\n');
 
         match &self.sub_sites {
@@ -123,38 +235,46 @@ impl SiteTree {
 
 #[cfg(test)]
 mod tests {
-    use super::{SiteTree, SubSites};
+    use super::{PageInfo, SiteTree, SubSites};
     use url::Url;
 
     #[test]
     fn test_tree_print() {
         let site_tree = SiteTree {
+            page: PageInfo::default(),
             current_site: Url::parse("https://example.com").unwrap(),
             sub_sites: SubSites::List(vec![
                 SiteTree {
+                    page: PageInfo::default(),
                     current_site: Url::parse("https://example.com/subsite1").unwrap(),
                     sub_sites: SubSites::Nil,
                 },
                 SiteTree {
+                    page: PageInfo::default(),
                     current_site: Url::parse("https://example.com/subsite2").unwrap(),
                     sub_sites: SubSites::List(vec![
                         SiteTree {
+                            page: PageInfo::default(),
                             current_site: Url::parse("https://example.com/subsite2/1").unwrap(),
                             sub_sites: SubSites::List(vec![SiteTree {
+                                page: PageInfo::default(),
                                 current_site: Url::parse("https://example.com/subsite2/1/1")
                                     .unwrap(),
                                 sub_sites: SubSites::Nil,
                             }]),
                         },
                         SiteTree {
+                            page: PageInfo::default(),
                             current_site: Url::parse("https://example.com/subsite2/2").unwrap(),
                             sub_sites: SubSites::List(vec![
                                 SiteTree {
+                                    page: PageInfo::default(),
                                     current_site: Url::parse("https://example.com/subsite2/2/1")
                                         .unwrap(),
                                     sub_sites: SubSites::Nil,
                                 },
                                 SiteTree {
+                                    page: PageInfo::default(),
                                     current_site: Url::parse("https://example.com/subsite2/2/2")
                                         .unwrap(),
                                     sub_sites: SubSites::Nil,
@@ -164,6 +284,7 @@ mod tests {
                     ]),
                 },
                 SiteTree {
+                    page: PageInfo::default(),
                     current_site: Url::parse("https://example.com/subsite3").unwrap(),
                     sub_sites: SubSites::Nil,
                 },